@@ -0,0 +1,251 @@
+//! Physical page frame database.
+//!
+//! Every 4K page frame in [`PHYS_MEMORY_BASE`, `PHYS_MEMORY_END`) has a
+//! [`PageFrame`] descriptor which records how many owners and how many user
+//! mappings the page has. Pages that can be shared (copy-on-write pages after
+//! fork, shared file mappings) must be released by [`put_page`] instead of
+//! [`dealloc_pages`], so that they are freed exactly once.
+//!
+//! All addresses here are kernel virtual addresses, the same as those returned
+//! by [`alloc_pages`].
+//!
+//! [`PHYS_MEMORY_BASE`]: axconfig::PHYS_MEMORY_BASE
+//! [`PHYS_MEMORY_END`]: axconfig::PHYS_MEMORY_END
+//! [`alloc_pages`]: crate::GlobalAllocator::alloc_pages
+//! [`dealloc_pages`]: crate::GlobalAllocator::dealloc_pages
+
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use axhal::mem::virt_to_phys;
+use axconfig::{PHYS_MEMORY_BASE, PHYS_MEMORY_SIZE};
+use crate::{global_allocator, PAGE_SIZE};
+
+/// Page has been modified and must be written back.
+pub const PG_DIRTY: usize = 1 << 0;
+/// Page is locked in memory and must not be reclaimed.
+pub const PG_LOCKED: usize = 1 << 1;
+/// Page belongs to a shared mapping, so it is never copied-on-write.
+pub const PG_SHARED: usize = 1 << 2;
+/// Page caches the content of a file.
+pub const PG_CACHE: usize = 1 << 3;
+
+/// Descriptor of a physical page frame.
+pub struct PageFrame {
+    refcount: AtomicUsize,
+    mapcount: AtomicUsize,
+    flags: AtomicUsize,
+}
+
+impl PageFrame {
+    /// Number of owners of this page.
+    pub fn count(&self) -> usize {
+        self.refcount.load(Ordering::Acquire)
+    }
+
+    /// Takes an extra reference.
+    pub fn get(&self) {
+        let old = self.refcount.fetch_add(1, Ordering::AcqRel);
+        assert!(old > 0, "get a free page");
+    }
+
+    /// Drops a reference. Returns true if it was the last one.
+    pub fn put(&self) -> bool {
+        let old = self.refcount.fetch_sub(1, Ordering::AcqRel);
+        assert!(old > 0, "put a free page");
+        old == 1
+    }
+
+    /// Number of user page tables which map this page.
+    pub fn mapcount(&self) -> usize {
+        self.mapcount.load(Ordering::Acquire)
+    }
+
+    /// Records that the page has been mapped into a user page table.
+    pub fn inc_mapcount(&self) {
+        self.mapcount.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Records that the page has been unmapped from a user page table.
+    pub fn dec_mapcount(&self) {
+        let old = self.mapcount.fetch_sub(1, Ordering::AcqRel);
+        assert!(old > 0, "unmap a page which isn't mapped");
+    }
+
+    /// Returns the `PG_*` flags of this page.
+    pub fn flags(&self) -> usize {
+        self.flags.load(Ordering::Acquire)
+    }
+
+    /// Whether any of `flags` is set on this page.
+    pub fn test_flags(&self, flags: usize) -> bool {
+        (self.flags() & flags) != 0
+    }
+
+    /// Sets `flags` on this page.
+    pub fn set_flags(&self, flags: usize) {
+        self.flags.fetch_or(flags, Ordering::AcqRel);
+    }
+
+    /// Clears `flags` on this page.
+    pub fn clear_flags(&self, flags: usize) {
+        self.flags.fetch_and(!flags, Ordering::AcqRel);
+    }
+
+    fn reset(&self, refcount: usize) {
+        self.flags.store(0, Ordering::Release);
+        self.mapcount.store(0, Ordering::Release);
+        self.refcount.store(refcount, Ordering::Release);
+    }
+}
+
+const NR_FRAMES: usize = PHYS_MEMORY_SIZE / PAGE_SIZE;
+
+static FRAMES: AtomicPtr<PageFrame> = AtomicPtr::new(core::ptr::null_mut());
+
+fn frame_index(dva: usize) -> Option<usize> {
+    let pa = virt_to_phys(dva.into()).as_usize();
+    if pa < PHYS_MEMORY_BASE {
+        return None;
+    }
+    let index = (pa - PHYS_MEMORY_BASE) / PAGE_SIZE;
+    (index < NR_FRAMES).then_some(index)
+}
+
+fn try_virt_to_page(dva: usize) -> Option<&'static PageFrame> {
+    let frames = FRAMES.load(Ordering::Acquire);
+    if frames.is_null() {
+        return None;
+    }
+    let index = frame_index(dva)?;
+    Some(unsafe { &*frames.add(index) })
+}
+
+/// Returns the descriptor of the page at kernel virtual address `dva`.
+pub fn virt_to_page(dva: usize) -> &'static PageFrame {
+    try_virt_to_page(dva).unwrap_or_else(|| panic!("bad page {:#X}", dva))
+}
+
+/// Takes an extra reference to the page at kernel virtual address `dva`.
+pub fn get_page(dva: usize) {
+    virt_to_page(dva).get();
+}
+
+/// Drops a reference to the page at kernel virtual address `dva`,
+/// and frees the page when the last reference goes away.
+pub fn put_page(dva: usize) {
+    if virt_to_page(dva).put() {
+        global_allocator().dealloc_pages(dva, 1);
+    }
+}
+
+/// Returns the number of owners of the page at kernel virtual address `dva`.
+pub fn page_count(dva: usize) -> usize {
+    virt_to_page(dva).count()
+}
+
+/// Called by the page allocator for each allocated page.
+pub(crate) fn frames_alloc(dva: usize, num_pages: usize) {
+    for i in 0..num_pages {
+        if let Some(page) = try_virt_to_page(dva + i * PAGE_SIZE) {
+            page.reset(1);
+        }
+    }
+}
+
+/// Called by the page allocator for each freed page.
+pub(crate) fn frames_free(dva: usize, num_pages: usize) {
+    for i in 0..num_pages {
+        if let Some(page) = try_virt_to_page(dva + i * PAGE_SIZE) {
+            page.reset(0);
+        }
+    }
+}
+
+/// Allocates the descriptor array from the page allocator.
+///
+/// Pages allocated before this are kernel pages which are never shared,
+/// so their descriptors are left as zero.
+pub(crate) fn init() {
+    let size = NR_FRAMES * core::mem::size_of::<PageFrame>();
+    let num_pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+    let base = global_allocator()
+        .alloc_pages(num_pages, PAGE_SIZE)
+        .expect("no memory for page frame database");
+    unsafe {
+        core::ptr::write_bytes(base as *mut u8, 0, num_pages * PAGE_SIZE);
+    }
+    FRAMES.store(base as *mut PageFrame, Ordering::Release);
+    info!("  page frame database: {} frames at {:#x}", NR_FRAMES, base);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_frame() -> PageFrame {
+        PageFrame {
+            refcount: AtomicUsize::new(0),
+            mapcount: AtomicUsize::new(0),
+            flags: AtomicUsize::new(0),
+        }
+    }
+
+    #[test]
+    fn test_refcount() {
+        let page = new_frame();
+        page.reset(1);
+        assert_eq!(page.count(), 1);
+        page.get();
+        page.get();
+        assert_eq!(page.count(), 3);
+        assert!(!page.put());
+        assert!(!page.put());
+        assert!(page.put());
+        assert_eq!(page.count(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_free_page() {
+        new_frame().get();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_put_free_page() {
+        new_frame().put();
+    }
+
+    #[test]
+    fn test_mapcount() {
+        let page = new_frame();
+        page.reset(1);
+        page.inc_mapcount();
+        page.inc_mapcount();
+        assert_eq!(page.mapcount(), 2);
+        page.dec_mapcount();
+        assert_eq!(page.mapcount(), 1);
+    }
+
+    #[test]
+    fn test_flags() {
+        let page = new_frame();
+        page.set_flags(PG_SHARED | PG_DIRTY);
+        assert!(page.test_flags(PG_SHARED));
+        assert!(!page.test_flags(PG_LOCKED));
+        page.clear_flags(PG_DIRTY);
+        assert!(!page.test_flags(PG_DIRTY));
+        assert_eq!(page.flags(), PG_SHARED);
+    }
+
+    #[test]
+    fn test_reset() {
+        let page = new_frame();
+        page.reset(1);
+        page.inc_mapcount();
+        page.set_flags(PG_LOCKED);
+        page.reset(0);
+        assert_eq!(page.count(), 0);
+        assert_eq!(page.mapcount(), 0);
+        assert_eq!(page.flags(), 0);
+    }
+}
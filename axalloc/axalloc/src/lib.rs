@@ -5,13 +5,14 @@
 //! [`GlobalAllocator`] is defined with the `#[global_allocator]` attribute, to
 //! be registered as the standard library’s default allocator.
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 #[macro_use]
 extern crate log;
 extern crate alloc;
 
 mod page;
+mod frame;
 
 use allocator::{AllocResult, BaseAllocator, BitmapPageAllocator, ByteAllocator, PageAllocator};
 use core::alloc::{GlobalAlloc, Layout};
@@ -23,6 +24,8 @@ const PAGE_SIZE: usize = 0x1000;
 const MIN_HEAP_SIZE: usize = 0x8000; // 32 K
 
 pub use page::GlobalPage;
+pub use frame::{PageFrame, virt_to_page, get_page, put_page, page_count};
+pub use frame::{PG_DIRTY, PG_LOCKED, PG_SHARED, PG_CACHE};
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "slab")] {
@@ -144,17 +147,23 @@ impl GlobalAllocator {
     /// `align_pow2` must be a power of 2, and the returned region bound will be
     /// aligned to it.
     pub fn alloc_pages(&self, num_pages: usize, align_pow2: usize) -> AllocResult<usize> {
-        self.palloc.lock().alloc_pages(num_pages, align_pow2)
+        let pos = self.palloc.lock().alloc_pages(num_pages, align_pow2)?;
+        frame::frames_alloc(pos, num_pages);
+        Ok(pos)
     }
 
     /// Gives back the allocated pages starts from `pos` to the page allocator.
     ///
+    /// Pages which may be shared with others should be released by
+    /// [`put_page`] instead.
+    ///
     /// The pages should be allocated by [`alloc_pages`], and `align_pow2`
     /// should be the same as the one used in [`alloc_pages`]. Otherwise, the
     /// behavior is undefined.
     ///
     /// [`alloc_pages`]: GlobalAllocator::alloc_pages
     pub fn dealloc_pages(&self, pos: usize, num_pages: usize) {
+        frame::frames_free(pos, num_pages);
         self.palloc.lock().dealloc_pages(pos, num_pages)
     }
 
@@ -269,4 +278,5 @@ pub fn init() {
                 .expect("add heap memory region failed");
         }
     }
+    frame::init();
 }
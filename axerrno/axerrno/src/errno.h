@@ -146,5 +146,10 @@
 #define ERFKILL         132 /* Operation not possible due to RF-kill */
 #define EHWPOISON       133 /* Memory page has hardware error */
 
+/* Copy from include/linux/errno.h, these should never be seen by user programs. */
+#define ERESTARTSYS     512 /* Restart the syscall if SA_RESTART is set */
+#define ERESTARTNOINTR  513 /* Always restart the syscall */
+#define ERESTARTNOHAND  514 /* Restart the syscall if no handler is run */
+
 
 #endif
@@ -56,6 +56,8 @@ pub enum AxError {
     InvalidData,
     /// Invalid parameter/argument.
     InvalidInput,
+    /// The operation was interrupted by a signal, and it may be restarted.
+    Interrupted,
     /// Input/output error.
     Io,
     /// The filesystem object is, unexpectedly, a directory.
@@ -211,6 +213,7 @@ impl AxError {
             DirectoryNotEmpty => "Directory not empty",
             InvalidData => "Invalid data",
             InvalidInput => "Invalid input parameter",
+            Interrupted => "Interrupted by a signal",
             Io => "I/O error",
             IsADirectory => "Is a directory",
             NoMemory => "Out of memory",
@@ -263,6 +266,7 @@ impl From<AxError> for LinuxError {
             ConnectionReset => LinuxError::ECONNRESET,
             DirectoryNotEmpty => LinuxError::ENOTEMPTY,
             InvalidInput | InvalidData => LinuxError::EINVAL,
+            Interrupted => LinuxError::ERESTARTSYS,
             Io => LinuxError::EIO,
             IsADirectory => LinuxError::EISDIR,
             NoMemory => LinuxError::ENOMEM,
@@ -297,7 +301,7 @@ mod tests {
     #[test]
     fn test_try_from() {
         let max_code = core::mem::variant_count::<AxError>() as i32;
-        assert_eq!(max_code, 22);
+        assert_eq!(max_code, 23);
         assert_eq!(max_code, AxError::WriteZero.code());
 
         assert_eq!(AxError::AddrInUse.code(), 1);
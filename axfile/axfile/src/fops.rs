@@ -26,7 +26,10 @@ pub type FilePerm = axfs_vfs::VfsNodePerm;
 pub struct File {
     node: WithCap<VfsNodeRef>,
     is_append: bool,
+    is_nonblock: bool,
     offset: u64,
+    /// Pages of shared mappings (file offset -> kernel va of the page).
+    /// Each entry holds a reference to the page.
     pub shared_map: BTreeMap<usize, usize>,
 }
 
@@ -116,6 +119,9 @@ impl OpenOptions {
 
 impl Drop for File {
     fn drop(&mut self) {
+        while let Some((_, dva)) = self.shared_map.pop_first() {
+            axalloc::put_page(dva);
+        }
         unsafe { self.node.access_unchecked().release().ok() };
     }
 }
@@ -183,6 +189,7 @@ impl File {
         Self {
             node: WithCap::new(node, cap),
             is_append: false,
+            is_nonblock: false,
             offset: 0,
             shared_map: BTreeMap::new(),
         }
@@ -231,6 +238,7 @@ impl File {
         Ok(Self {
             node: WithCap::new(node, access_cap),
             is_append: opts.append,
+            is_nonblock: false,
             offset: 0,
             shared_map: BTreeMap::new(),
         })
@@ -317,6 +325,34 @@ impl File {
     pub fn get_attr(&self) -> AxResult<FileAttr> {
         self.node.access(Cap::empty())?.get_attr()
     }
+
+    /// Whether the file is opened for reading.
+    pub fn readable(&self) -> bool {
+        self.node.can_access(Cap::READ)
+    }
+
+    /// Whether the file is opened for writing.
+    pub fn writable(&self) -> bool {
+        self.node.can_access(Cap::WRITE)
+    }
+
+    /// Whether each write goes to the end of the file.
+    pub fn is_append(&self) -> bool {
+        self.is_append
+    }
+
+    pub fn set_append(&mut self, append: bool) {
+        self.is_append = append;
+    }
+
+    /// Whether the file is in non-blocking mode.
+    pub fn is_nonblock(&self) -> bool {
+        self.is_nonblock
+    }
+
+    pub fn set_nonblock(&mut self, nonblock: bool) {
+        self.is_nonblock = nonblock;
+    }
 }
 
 impl Directory {
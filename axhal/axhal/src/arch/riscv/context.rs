@@ -1,4 +1,4 @@
-use crate::arch::{SR_FS_INITIAL, SR_SPIE, SR_SUM, SR_UXL_64};
+use crate::arch::{SR_FS_INITIAL, SR_SPIE, SR_UXL_64};
 use core::arch::asm;
 use memory_addr::VirtAddr;
 
@@ -54,6 +54,30 @@ pub struct TrapFrame {
     pub sstatus: usize,
 }
 
+/// Floating-point registers of the F and D extensions,
+/// the same as `struct __riscv_d_ext_state` of Linux.
+#[allow(missing_docs)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FpState {
+    pub f: [u64; 32],
+    pub fcsr: u32,
+}
+
+impl FpState {
+    /// Saves the FP registers of the CPU to this place.
+    #[inline]
+    pub fn save(&mut self) {
+        unsafe { fpstate_save(self) }
+    }
+
+    /// Loads the FP registers of the CPU from this place.
+    #[inline]
+    pub fn restore(&self) {
+        unsafe { fpstate_restore(self) }
+    }
+}
+
 /// Saved hardware states of a task.
 ///
 /// The context usually includes:
@@ -87,7 +111,9 @@ pub struct TaskContext {
     pub s11: usize,
 
     pub tp: usize,
-    // TODO: FP states
+    /// FP states
+    #[cfg(feature = "fp_simd")]
+    pub fp_state: FpState,
 }
 
 impl TaskContext {
@@ -102,6 +128,9 @@ impl TaskContext {
         self.sp = kstack_top.as_usize();
         self.ra = entry;
         self.tp = tls_area.as_usize();
+        // The new task inherits the FP state of the creator, like fork.
+        #[cfg(feature = "fp_simd")]
+        self.fp_state.save();
     }
 
     /// Switches to another task.
@@ -114,8 +143,12 @@ impl TaskContext {
             self.tp = super::read_thread_pointer();
             unsafe { super::write_thread_pointer(next_ctx.tp) };
         }
+        #[cfg(feature = "fp_simd")]
+        {
+            self.fp_state.save();
+            next_ctx.fp_state.restore();
+        }
         unsafe {
-            // TODO: switch FP states
             context_switch(self, next_ctx)
         }
     }
@@ -162,10 +195,105 @@ unsafe extern "C" fn context_switch(_current_task: &mut TaskContext, _next_task:
     )
 }
 
+// FP instructions are illegal if sstatus.FS is Off, so turn it on at first.
+// It is restored from the trap frame when we return to user.
+#[naked]
+unsafe extern "C" fn fpstate_save(_fpstate: &mut FpState) {
+    asm!(
+        "
+        li      t0, {sr_fs}
+        csrs    sstatus, t0
+        fsd     f0, 0*8(a0)
+        fsd     f1, 1*8(a0)
+        fsd     f2, 2*8(a0)
+        fsd     f3, 3*8(a0)
+        fsd     f4, 4*8(a0)
+        fsd     f5, 5*8(a0)
+        fsd     f6, 6*8(a0)
+        fsd     f7, 7*8(a0)
+        fsd     f8, 8*8(a0)
+        fsd     f9, 9*8(a0)
+        fsd     f10, 10*8(a0)
+        fsd     f11, 11*8(a0)
+        fsd     f12, 12*8(a0)
+        fsd     f13, 13*8(a0)
+        fsd     f14, 14*8(a0)
+        fsd     f15, 15*8(a0)
+        fsd     f16, 16*8(a0)
+        fsd     f17, 17*8(a0)
+        fsd     f18, 18*8(a0)
+        fsd     f19, 19*8(a0)
+        fsd     f20, 20*8(a0)
+        fsd     f21, 21*8(a0)
+        fsd     f22, 22*8(a0)
+        fsd     f23, 23*8(a0)
+        fsd     f24, 24*8(a0)
+        fsd     f25, 25*8(a0)
+        fsd     f26, 26*8(a0)
+        fsd     f27, 27*8(a0)
+        fsd     f28, 28*8(a0)
+        fsd     f29, 29*8(a0)
+        fsd     f30, 30*8(a0)
+        fsd     f31, 31*8(a0)
+        frcsr   t0
+        sw      t0, 32*8(a0)
+        ret",
+        sr_fs = const SR_FS_INITIAL,
+        options(noreturn),
+    )
+}
+
+#[naked]
+unsafe extern "C" fn fpstate_restore(_fpstate: &FpState) {
+    asm!(
+        "
+        li      t0, {sr_fs}
+        csrs    sstatus, t0
+        fld     f0, 0*8(a0)
+        fld     f1, 1*8(a0)
+        fld     f2, 2*8(a0)
+        fld     f3, 3*8(a0)
+        fld     f4, 4*8(a0)
+        fld     f5, 5*8(a0)
+        fld     f6, 6*8(a0)
+        fld     f7, 7*8(a0)
+        fld     f8, 8*8(a0)
+        fld     f9, 9*8(a0)
+        fld     f10, 10*8(a0)
+        fld     f11, 11*8(a0)
+        fld     f12, 12*8(a0)
+        fld     f13, 13*8(a0)
+        fld     f14, 14*8(a0)
+        fld     f15, 15*8(a0)
+        fld     f16, 16*8(a0)
+        fld     f17, 17*8(a0)
+        fld     f18, 18*8(a0)
+        fld     f19, 19*8(a0)
+        fld     f20, 20*8(a0)
+        fld     f21, 21*8(a0)
+        fld     f22, 22*8(a0)
+        fld     f23, 23*8(a0)
+        fld     f24, 24*8(a0)
+        fld     f25, 25*8(a0)
+        fld     f26, 26*8(a0)
+        fld     f27, 27*8(a0)
+        fld     f28, 28*8(a0)
+        fld     f29, 29*8(a0)
+        fld     f30, 30*8(a0)
+        fld     f31, 31*8(a0)
+        lw      t0, 32*8(a0)
+        fscsr   t0
+        ret",
+        sr_fs = const SR_FS_INITIAL,
+        options(noreturn),
+    )
+}
+
 pub fn start_thread(regs: usize, pc: usize, sp: usize) {
     let regs = unsafe { core::slice::from_raw_parts_mut(regs as *mut TrapFrame, 1) };
     regs[0].sepc = pc;
-    // default to open the sum bit
-    regs[0].sstatus = SR_SPIE | SR_FS_INITIAL | SR_UXL_64 | SR_SUM;
+    regs[0].sstatus = SR_SPIE | SR_FS_INITIAL | SR_UXL_64;
     regs[0].regs.sp = sp;
+    // The new program starts with the initial FP state.
+    FpState::default().restore();
 }
@@ -90,6 +90,11 @@ macro_rules! include_asm_marcos {
             STR     t1, sp, 32                  // tf.sstatus
             STR     t2, sp, 1                   // tf.regs.sp
 
+            // Disable user memory access, it's only enabled in
+            // user-access routines. It is restored by sret.
+            li      t0, 0x40000                 // SR_SUM
+            csrc    sstatus, t0
+
         .if \from_user == 1
             LDR     t1, sp, 2                   // load supervisor gp
             LDR     t0, sp, 3                   // load supervisor tp
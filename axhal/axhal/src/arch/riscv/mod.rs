@@ -11,7 +11,7 @@ use memory_addr::{PhysAddr, VirtAddr};
 use riscv::asm;
 use riscv::register::{satp, sstatus, stvec};
 
-pub use self::context::{start_thread, GeneralRegisters, TaskContext, TrapFrame};
+pub use self::context::{start_thread, FpState, GeneralRegisters, TaskContext, TrapFrame};
 
 pub const TASK_SIZE: usize = 0x40_0000_0000;
 pub const STACK_SIZE: usize = 32 * PAGE_SIZE_4K;
@@ -43,6 +43,19 @@ pub fn disable_sum() {
     unsafe { sstatus::clear_sum() }
 }
 
+/// Permits kernel to access user memory.
+/// Only user-access routines call it, and call `user_access_end` after.
+#[inline]
+pub fn user_access_begin() {
+    unsafe { sstatus::set_sum() }
+}
+
+/// Forbids kernel to access user memory.
+#[inline]
+pub fn user_access_end() {
+    disable_sum()
+}
+
 /// Allows the current CPU to respond to interrupts.
 #[inline]
 pub fn enable_irqs() {
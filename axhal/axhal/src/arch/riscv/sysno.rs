@@ -3,6 +3,9 @@
 ///
 
 pub const LINUX_SYSCALL_GETCWD: usize = 0x11;
+pub const LINUX_SYSCALL_DUP: usize = 0x17;
+pub const LINUX_SYSCALL_DUP3: usize = 0x18;
+pub const LINUX_SYSCALL_FCNTL: usize = 0x19;
 pub const LINUX_SYSCALL_IOCTL: usize = 0x1d;
 pub const LINUX_SYSCALL_MKDIRAT: usize = 0x22;
 pub const LINUX_SYSCALL_UNLINKAT: usize = 0x23;
@@ -25,11 +28,14 @@ pub const LINUX_SYSCALL_FSTATAT: usize = 0x4f;
 pub const LINUX_SYSCALL_CAPGET: usize = 0x5a;
 pub const LINUX_SYSCALL_EXIT: usize = 0x5d;
 pub const LINUX_SYSCALL_EXIT_GROUP: usize = 0x5e;
+pub const LINUX_SYSCALL_WAITID: usize = 0x5f;
 pub const LINUX_SYSCALL_SETITIMER: usize = 0x67;
+pub const LINUX_SYSCALL_TKILL: usize = 0x82;
 pub const LINUX_SYSCALL_TGKILL: usize = 0x83;
 pub const LINUX_SYSCALL_RT_SIGRETURN: usize = 0x8b;
 pub const LINUX_SYSCALL_SETPGID: usize = 0x9a;
 pub const LINUX_SYSCALL_UNAME: usize = 0xa0;
+pub const LINUX_SYSCALL_PRCTL: usize = 0xa7;
 pub const LINUX_SYSCALL_GETPID: usize = 0xac;
 pub const LINUX_SYSCALL_GETPPID: usize = 0xad;
 pub const LINUX_SYSCALL_GETEUID: usize = 0xaf;
@@ -37,6 +43,7 @@ pub const LINUX_SYSCALL_GETGID: usize = 0xb0;
 pub const LINUX_SYSCALL_GETTID: usize = 0xb2;
 pub const LINUX_SYSCALL_BRK: usize = 0xd6;
 pub const LINUX_SYSCALL_MUNMAP: usize = 0xd7;
+pub const LINUX_SYSCALL_MREMAP: usize = 0xd8;
 pub const LINUX_SYSCALL_CLONE: usize = 0xdc;
 pub const LINUX_SYSCALL_EXECVE: usize = 0xdd;
 pub const LINUX_SYSCALL_MMAP: usize = 0xde;
@@ -49,10 +56,16 @@ pub const LINUX_SYSCALL_GETRANDOM: usize = 0x116;
 pub const LINUX_SYSCALL_RSEQ: usize = 0x125;
 
 pub const LINUX_SYSCALL_SET_TID_ADDRESS: usize = 0x60;
+pub const LINUX_SYSCALL_FUTEX: usize = 0x62;
 pub const LINUX_SYSCALL_SET_ROBUST_LIST: usize = 0x63;
+pub const LINUX_SYSCALL_GET_ROBUST_LIST: usize = 0x64;
 pub const LINUX_SYSCALL_CLOCK_GETTIME: usize = 0x71;
 pub const LINUX_SYSCALL_CLOCK_NANOSLEEP: usize = 0x73;
 pub const LINUX_SYSCALL_SCHED_GETAFFINITY: usize = 0x7b;
 pub const LINUX_SYSCALL_KILL: usize = 0x81;
 pub const LINUX_SYSCALL_RT_SIGACTION: usize = 0x86;
 pub const LINUX_SYSCALL_RT_SIGPROCMASK: usize = 0x87;
+pub const LINUX_SYSCALL_SIGALTSTACK: usize = 0x84;
+pub const LINUX_SYSCALL_RT_SIGSUSPEND: usize = 0x85;
+pub const LINUX_SYSCALL_RT_SIGPENDING: usize = 0x88;
+pub const LINUX_SYSCALL_RT_SIGTIMEDWAIT: usize = 0x89;
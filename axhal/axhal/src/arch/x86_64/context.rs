@@ -61,7 +61,7 @@ struct ContextSwitchFrame {
 /// See <https://www.felixcloutier.com/x86/fxsave> for more details.
 #[allow(missing_docs)]
 #[repr(C, align(16))]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct FxsaveArea {
     pub fcw: u16,
     pub fsw: u16,
@@ -78,7 +78,36 @@ pub struct FxsaveArea {
 
 static_assertions::const_assert_eq!(core::mem::size_of::<FxsaveArea>(), 512);
 
+impl FxsaveArea {
+    /// Creates the initial state, just as after FNINIT.
+    pub const fn new() -> Self {
+        let mut area: FxsaveArea = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
+        area.fcw = 0x37f;
+        area.ftw = 0xffff;
+        area.mxcsr = 0x1f80;
+        area
+    }
+
+    /// Saves the FP/SIMD registers of the CPU to this area.
+    #[inline]
+    pub fn save(&mut self) {
+        unsafe { core::arch::x86_64::_fxsave64(self as *mut _ as *mut u8) }
+    }
+
+    /// Loads the FP/SIMD registers of the CPU from this area.
+    ///
+    /// The reserved bits of `mxcsr` must be zero, or it raises #GP.
+    #[inline]
+    pub fn restore(&self) {
+        unsafe { core::arch::x86_64::_fxrstor64(self as *const _ as *const u8) }
+    }
+}
+
 /// Extended state of a task, such as FP/SIMD states.
+///
+/// FXSAVE is used rather than XSAVE on purpose: CR4.OSXSAVE is never set,
+/// so x87 and SSE are the only user visible states and there's nothing
+/// for XSAVE to save on top of the fxsave area.
 pub struct ExtendedState {
     /// Memory region for the FXSAVE/FXRSTOR instruction.
     pub fxsave_area: FxsaveArea,
@@ -88,20 +117,16 @@ pub struct ExtendedState {
 impl ExtendedState {
     #[inline]
     fn save(&mut self) {
-        unsafe { core::arch::x86_64::_fxsave64(&mut self.fxsave_area as *mut _ as *mut u8) }
+        self.fxsave_area.save()
     }
 
     #[inline]
     fn restore(&self) {
-        unsafe { core::arch::x86_64::_fxrstor64(&self.fxsave_area as *const _ as *const u8) }
+        self.fxsave_area.restore()
     }
 
     const fn default() -> Self {
-        let mut area: FxsaveArea = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
-        area.fcw = 0x37f;
-        area.ftw = 0xffff;
-        area.mxcsr = 0x1f80;
-        Self { fxsave_area: area }
+        Self { fxsave_area: FxsaveArea::new() }
     }
 }
 
@@ -177,6 +202,9 @@ impl TaskContext {
         }
         self.kstack_top = kstack_top;
         self.fs_base = tls_area.as_usize();
+        // The new task inherits the FP/SIMD state of the creator, like fork.
+        #[cfg(feature = "fp_simd")]
+        self.ext_state.save();
     }
 
     /// Switches to another task.
@@ -236,4 +264,6 @@ pub fn start_thread(regs: usize, pc: usize, sp: usize) {
     regs[0].ss = GdtStruct::UDATA_SELECTOR.0 as _;
     regs[0].rip = pc as u64;
     regs[0].rsp = sp as u64;
+    // The new program starts with the initial FP/SIMD state.
+    FxsaveArea::new().restore();
 }
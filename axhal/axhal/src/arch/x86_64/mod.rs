@@ -6,6 +6,7 @@ pub mod sysno;
 mod trap;
 use crate::mem::PAGE_SIZE_4K;
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
 use memory_addr::{PhysAddr, VirtAddr};
 pub use trap::ret_from_fork;
 use x86::{controlregs, msr, tlb};
@@ -27,6 +28,46 @@ pub const TASK_UNMAPPED_BASE: usize = (TASK_SIZE / 3) & !(PAGE_SIZE_4K - 1);
  */
 pub const ELF_ET_DYN_BASE: usize = (TASK_SIZE / 3) * 2;
 
+/// Causes of page fault, decoded from the error code of #PF.
+/// They have the same values as riscv scause, so mm can share the code.
+pub const EXC_INST_PAGE_FAULT: usize = 12;
+pub const EXC_LOAD_PAGE_FAULT: usize = 13;
+pub const EXC_STORE_PAGE_FAULT: usize = 15;
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables SMAP if CPU supports it, so that kernel can access
+/// user memory only between `user_access_begin` and `user_access_end`.
+pub fn init_smap() {
+    let has_smap = raw_cpuid::CpuId::new()
+        .get_extended_feature_info()
+        .map_or(false, |info| info.has_smap());
+    if has_smap {
+        unsafe {
+            controlregs::cr4_write(controlregs::cr4() | controlregs::Cr4::CR4_ENABLE_SMAP);
+        }
+        SMAP_ENABLED.store(true, Ordering::Release);
+    }
+    info!("SMAP enabled: {}", has_smap);
+}
+
+/// Permits kernel to access user memory.
+/// Only user-access routines call it, and call `user_access_end` after.
+#[inline]
+pub fn user_access_begin() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe { asm!("stac") }
+    }
+}
+
+/// Forbids kernel to access user memory.
+#[inline]
+pub fn user_access_end() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe { asm!("clac") }
+    }
+}
+
 /// Allows the current CPU to respond to interrupts.
 #[inline]
 pub fn enable_irqs() {
@@ -21,15 +21,22 @@ pub const LINUX_SYSCALL_EXIT_GROUP: usize = 0xe7;
 pub const LINUX_SYSCALL_OPENAT: usize = 0x101;
 pub const LINUX_SYSCALL_FSTATAT: usize = 0x106;
 pub const LINUX_SYSCALL_SET_ROBUST_LIST: usize = 0x111;
+pub const LINUX_SYSCALL_GET_ROBUST_LIST: usize = 0x112;
+pub const LINUX_SYSCALL_FUTEX: usize = 0xca;
 pub const LINUX_SYSCALL_PRLIMIT64: usize = 0x12e;
 pub const LINUX_SYSCALL_GETRANDOM: usize = 0x13e;
 pub const LINUX_SYSCALL_RSEQ: usize = 0x14e;
 
 pub const LINUX_SYSCALL_IOCTL: usize = 16;
+pub const LINUX_SYSCALL_DUP: usize = 32;
+pub const LINUX_SYSCALL_DUP2: usize = 33;
+pub const LINUX_SYSCALL_DUP3: usize = 292;
+pub const LINUX_SYSCALL_FCNTL: usize = 72;
 pub const LINUX_SYSCALL_FTRUNCATE: usize = 77;
 pub const LINUX_SYSCALL_GETCWD: usize = 79;
 pub const LINUX_SYSCALL_CHDIR: usize = 80;
 pub const LINUX_SYSCALL_FACCESSAT: usize = 269;
+pub const LINUX_SYSCALL_TKILL: usize = 200;
 pub const LINUX_SYSCALL_TGKILL: usize = 234;
 pub const LINUX_SYSCALL_GETPID: usize = 39;
 pub const LINUX_SYSCALL_GETPPID: usize = 110;
@@ -49,18 +56,25 @@ pub const LINUX_SYSCALL_UNLINKAT: usize = 263;
 pub const LINUX_SYSCALL_WRITEV: usize = 20;
 pub const LINUX_SYSCALL_READLINKAT: usize = 267;
 pub const LINUX_SYSCALL_MUNMAP: usize = 11;
+pub const LINUX_SYSCALL_MREMAP: usize = 25;
 pub const LINUX_SYSCALL_MSYNC: usize = 26;
 pub const LINUX_SYSCALL_MADVISE: usize = 28;
 
 pub const LINUX_SYSCALL_RT_SIGACTION: usize = 13;
 pub const LINUX_SYSCALL_RT_SIGPROCMASK: usize = 14;
 pub const LINUX_SYSCALL_RT_SIGRETURN: usize = 15;
+pub const LINUX_SYSCALL_RT_SIGPENDING: usize = 127;
+pub const LINUX_SYSCALL_RT_SIGTIMEDWAIT: usize = 128;
+pub const LINUX_SYSCALL_RT_SIGSUSPEND: usize = 130;
+pub const LINUX_SYSCALL_SIGALTSTACK: usize = 131;
 pub const LINUX_SYSCALL_CLONE: usize = 56;
 pub const LINUX_SYSCALL_EXECVE: usize = 59;
 pub const LINUX_SYSCALL_SCHED_GETAFFINITY: usize = 204;
 pub const LINUX_SYSCALL_SETITIMER: usize = 38;
 pub const LINUX_SYSCALL_WAIT4: usize = 61;
+pub const LINUX_SYSCALL_WAITID: usize = 247;
 pub const LINUX_SYSCALL_KILL: usize = 62;
 pub const LINUX_SYSCALL_SETPGID: usize = 109;
+pub const LINUX_SYSCALL_PRCTL: usize = 157;
 pub const LINUX_SYSCALL_VFORK: usize = 58;
 pub const LINUX_SYSCALL_CLOCK_NANOSLEEP: usize = 230;
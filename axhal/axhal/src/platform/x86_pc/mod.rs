@@ -26,6 +26,7 @@ pub fn platform_init() {
 
     self::apic::init_primary();
     self::time::init_primary();
+    crate::arch::init_smap();
 }
 
 /// Initializes the platform devices for secondary CPUs.
@@ -33,4 +34,5 @@ pub fn platform_init() {
 pub fn platform_init_secondary() {
     self::apic::init_secondary();
     self::time::init_secondary();
+    crate::arch::init_smap();
 }
@@ -2,19 +2,34 @@
 
 extern crate alloc;
 
-use axtype::get_user_str;
-use fileops::iovec;
-use axtype::{align_up_4k, is_aligned_4k};
+use uaccess::get_user_str;
+use axtype::align_up_4k;
 use axhal::arch::sysno::*;
-use axerrno::{linux_err_from, LinuxError};
+use axerrno::{linux_err, linux_err_from, LinuxError};
 
 #[macro_use]
 extern crate log;
 
+mod trace;
+pub use trace::{set_syscall_trace, syscall_trace, unknown_syscalls};
+
 const MAX_SYSCALL_ARGS: usize = 6;
 pub type SyscallArgs = [usize; MAX_SYSCALL_ARGS];
 
 pub fn do_syscall(args: SyscallArgs, sysno: usize) -> usize {
+    let tid = taskctx::current_ctx().tid();
+    if !trace::traced(tid) {
+        return dispatch_syscall(args, sysno);
+    }
+    if trace::noreturn(sysno) {
+        trace::trace_syscall(tid, sysno, &args, None);
+    }
+    let ret = dispatch_syscall(args, sysno);
+    trace::trace_syscall(tid, sysno, &args, Some(ret));
+    ret
+}
+
+fn dispatch_syscall(args: SyscallArgs, sysno: usize) -> usize {
     match sysno {
         LINUX_SYSCALL_IOCTL => linux_syscall_ioctl(args),
         LINUX_SYSCALL_GETCWD => linux_syscall_getcwd(args),
@@ -24,6 +39,9 @@ pub fn do_syscall(args: SyscallArgs, sysno: usize) -> usize {
         LINUX_SYSCALL_UNLINKAT => linux_syscall_unlinkat(args),
         LINUX_SYSCALL_OPENAT => linux_syscall_openat(args),
         LINUX_SYSCALL_CLOSE => linux_syscall_close(args),
+        LINUX_SYSCALL_DUP => linux_syscall_dup(args),
+        LINUX_SYSCALL_DUP3 => linux_syscall_dup3(args),
+        LINUX_SYSCALL_FCNTL => linux_syscall_fcntl(args),
         LINUX_SYSCALL_LSEEK => linux_syscall_lseek(args),
         LINUX_SYSCALL_READ => linux_syscall_read(args),
         LINUX_SYSCALL_PREAD64 => linux_syscall_pread64(args),
@@ -38,20 +56,29 @@ pub fn do_syscall(args: SyscallArgs, sysno: usize) -> usize {
         LINUX_SYSCALL_CLONE => linux_syscall_clone(args),
         LINUX_SYSCALL_EXECVE => linux_syscall_execve(args),
         LINUX_SYSCALL_MUNMAP => linux_syscall_munmap(args),
+        LINUX_SYSCALL_MREMAP => linux_syscall_mremap(args),
         LINUX_SYSCALL_MMAP => linux_syscall_mmap(args),
         LINUX_SYSCALL_MSYNC => linux_syscall_msync(args),
         LINUX_SYSCALL_MADVISE => linux_syscall_madvise(args),
         LINUX_SYSCALL_MPROTECT => linux_syscall_mprotect(args),
         LINUX_SYSCALL_SET_TID_ADDRESS => linux_syscall_set_tid_address(args),
         LINUX_SYSCALL_SET_ROBUST_LIST => linux_syscall_set_robust_list(args),
+        LINUX_SYSCALL_GET_ROBUST_LIST => linux_syscall_get_robust_list(args),
+        LINUX_SYSCALL_FUTEX => linux_syscall_futex(args),
         LINUX_SYSCALL_WAIT4 => linux_syscall_wait4(args),
+        LINUX_SYSCALL_WAITID => linux_syscall_waitid(args),
+        LINUX_SYSCALL_PRCTL => linux_syscall_prctl(args),
         LINUX_SYSCALL_PRLIMIT64 => linux_syscall_prlimit64(args),
         LINUX_SYSCALL_GETRANDOM => linux_syscall_getrandom(args),
         LINUX_SYSCALL_CLOCK_GETTIME => linux_syscall_clock_gettime(args),
         LINUX_SYSCALL_CLOCK_NANOSLEEP => linux_syscall_clock_nanosleep(args),
         LINUX_SYSCALL_RT_SIGPROCMASK => linux_syscall_rt_sigprocmask(args),
+        LINUX_SYSCALL_RT_SIGPENDING => linux_syscall_rt_sigpending(args),
+        LINUX_SYSCALL_RT_SIGSUSPEND => linux_syscall_rt_sigsuspend(args),
+        LINUX_SYSCALL_RT_SIGTIMEDWAIT => linux_syscall_rt_sigtimedwait(args),
         LINUX_SYSCALL_RT_SIGACTION => linux_syscall_rt_sigaction(args),
         LINUX_SYSCALL_RT_SIGRETURN => linux_syscall_rt_sigreturn(args),
+        LINUX_SYSCALL_SIGALTSTACK => linux_syscall_sigaltstack(args),
         LINUX_SYSCALL_GETTID => linux_syscall_gettid(args),
         LINUX_SYSCALL_GETPID => linux_syscall_getpid(args),
         LINUX_SYSCALL_GETPPID => linux_syscall_getppid(args),
@@ -59,6 +86,7 @@ pub fn do_syscall(args: SyscallArgs, sysno: usize) -> usize {
         LINUX_SYSCALL_SETPGID => linux_syscall_setpgid(args),
         LINUX_SYSCALL_GETEUID => linux_syscall_geteuid(args),
         LINUX_SYSCALL_KILL => linux_syscall_kill(args),
+        LINUX_SYSCALL_TKILL => linux_syscall_tkill(args),
         LINUX_SYSCALL_TGKILL => linux_syscall_tgkill(args),
         LINUX_SYSCALL_EXIT => linux_syscall_exit(args),
         LINUX_SYSCALL_EXIT_GROUP => linux_syscall_exit_group(args),
@@ -76,7 +104,13 @@ pub fn do_syscall(args: SyscallArgs, sysno: usize) -> usize {
         LINUX_SYSCALL_ARCH_PRCTL => linux_syscall_arch_prctl(args),
         #[cfg(target_arch = "x86_64")]
         LINUX_SYSCALL_VFORK => linux_syscall_vfork(args),
-        _ => panic!("Unsupported syscall: {}, {:#x}", sysno, sysno),
+        #[cfg(target_arch = "x86_64")]
+        LINUX_SYSCALL_DUP2 => linux_syscall_dup2(args),
+        _ => {
+            let count = trace::count_unknown(sysno);
+            warn!("Unsupported syscall: {}, {:#x} (count {})", sysno, sysno, count);
+            linux_err!(ENOSYS)
+        }
     }
 }
 
@@ -86,7 +120,10 @@ fn linux_syscall_faccessat(args: SyscallArgs) -> usize {
         "linux_syscall_faccessat dfd {:#X} filename {:#X} mode {}",
         dfd, filename, mode
     );
-    let filename = get_user_str(filename);
+    let filename = match get_user_str(filename) {
+        Ok(filename) => filename,
+        Err(e) => return linux_err_from!(e),
+    };
     warn!("filename: {}", filename);
     0
 }
@@ -112,7 +149,10 @@ fn linux_syscall_setitimer(args: SyscallArgs) -> usize {
 
 fn linux_syscall_fchownat(args: SyscallArgs) -> usize {
     let [dfd, pathname, owner, group, flags, ..] = args;
-    let pathname = get_user_str(pathname);
+    let pathname = match get_user_str(pathname) {
+        Ok(pathname) => pathname,
+        Err(e) => return linux_err_from!(e),
+    };
     warn!(
         "impl fchownat dfd {:#X} path {} owner:group {}:{} flags {:#X}",
         dfd, pathname, owner, group, flags
@@ -128,7 +168,10 @@ fn linux_syscall_fchmod(args: SyscallArgs) -> usize {
 
 fn linux_syscall_fchmodat(args: SyscallArgs) -> usize {
     let [dfd, pathname, mode, flags, ..] = args;
-    let pathname = get_user_str(pathname);
+    let pathname = match get_user_str(pathname) {
+        Ok(pathname) => pathname,
+        Err(e) => return linux_err_from!(e),
+    };
     warn!(
         "impl fchmodat dfd {:#X} path {} mode {:#o} flags {:#X}",
         dfd, pathname, mode, flags
@@ -138,13 +181,19 @@ fn linux_syscall_fchmodat(args: SyscallArgs) -> usize {
 
 fn linux_syscall_mkdirat(args: SyscallArgs) -> usize {
     let [dfd, pathname, mode, ..] = args;
-    let pathname = get_user_str(pathname);
+    let pathname = match get_user_str(pathname) {
+        Ok(pathname) => pathname,
+        Err(e) => return linux_err_from!(e),
+    };
     fileops::mkdirat(dfd, &pathname, mode)
 }
 
 fn linux_syscall_unlinkat(args: SyscallArgs) -> usize {
     let [dfd, path, flags, ..] = args;
-    let path = get_user_str(path);
+    let path = match get_user_str(path) {
+        Ok(path) => path,
+        Err(e) => return linux_err_from!(e),
+    };
     warn!(
         "impl unlinkat dfd {}, path {} flags {:#X}",
         dfd, path, flags
@@ -155,16 +204,39 @@ fn linux_syscall_unlinkat(args: SyscallArgs) -> usize {
 fn linux_syscall_openat(args: SyscallArgs) -> usize {
     let [dfd, filename, flags, mode, ..] = args;
 
-    let filename = get_user_str(filename);
+    let filename = match get_user_str(filename) {
+        Ok(filename) => filename,
+        Err(e) => return linux_err_from!(e),
+    };
     info!("filename: {}\n", filename);
-    fileops::register_file(fileops::openat(dfd, &filename, flags, mode))
+    fileops::register_file(fileops::openat(dfd, &filename, flags, mode), flags)
 }
 
 fn linux_syscall_close(args: SyscallArgs) -> usize {
     let [fd, ..] = args;
     info!("linux_syscall_close [{}] ...", fd);
-    fileops::unregister_file(fd);
-    0
+    fileops::unregister_file(fd)
+}
+
+fn linux_syscall_dup(args: SyscallArgs) -> usize {
+    let [oldfd, ..] = args;
+    fileops::dup(oldfd)
+}
+
+fn linux_syscall_dup3(args: SyscallArgs) -> usize {
+    let [oldfd, newfd, flags, ..] = args;
+    fileops::dup3(oldfd, newfd, flags)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn linux_syscall_dup2(args: SyscallArgs) -> usize {
+    let [oldfd, newfd, ..] = args;
+    fileops::dup2(oldfd, newfd)
+}
+
+fn linux_syscall_fcntl(args: SyscallArgs) -> usize {
+    let [fd, cmd, arg, ..] = args;
+    fileops::fcntl(fd, cmd, arg)
 }
 
 fn linux_syscall_lseek(args: SyscallArgs) -> usize {
@@ -174,16 +246,12 @@ fn linux_syscall_lseek(args: SyscallArgs) -> usize {
 
 fn linux_syscall_read(args: SyscallArgs) -> usize {
     let [fd, buf, count, ..] = args;
-
-    let ubuf = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, count) };
-    fileops::read(fd, ubuf)
+    fileops::read(fd, buf, count)
 }
 
 fn linux_syscall_pread64(args: SyscallArgs) -> usize {
     let [fd, buf, count, offset, ..] = args;
-
-    let ubuf = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, count) };
-    fileops::pread64(fd, ubuf, offset)
+    fileops::pread64(fd, buf, count, offset)
 }
 
 #[cfg(target_arch = "riscv64")]
@@ -196,17 +264,13 @@ fn linux_syscall_getdents64(args: SyscallArgs) -> usize {
 fn linux_syscall_write(args: SyscallArgs) -> usize {
     let [fd, buf, size, ..] = args;
     info!("write: {:#x}, {:#x}, {:#x}", fd, buf, size);
-
-    let ubuf = unsafe { core::slice::from_raw_parts(buf as *const u8, size) };
-    fileops::write(fd, ubuf)
+    fileops::write(fd, buf, size)
 }
 
 fn linux_syscall_writev(args: SyscallArgs) -> usize {
     let [fd, array, size, ..] = args;
     info!("writev: {:#x}, {:#x}, {:#x}", fd, array, size);
-
-    let iov_array = unsafe { core::slice::from_raw_parts(array as *const iovec, size) };
-    fileops::writev(fd, iov_array)
+    fileops::writev(fd, array, size)
 }
 
 fn linux_syscall_fstatat(args: SyscallArgs) -> usize {
@@ -227,7 +291,6 @@ fn linux_syscall_access(_args: SyscallArgs) -> usize {
 
 fn linux_syscall_mmap(args: SyscallArgs) -> usize {
     let [va, len, prot, flags, fd, offset] = args;
-    assert!(is_aligned_4k(va));
     info!(
         "###### mmap!!! {:#x} {:#x} prot {:#x} flags {:#x} {:#x} {:#x}",
         va, len, prot, flags, fd, offset
@@ -243,6 +306,17 @@ fn linux_syscall_munmap(args: SyscallArgs) -> usize {
     let [va, len, ..] = args;
     warn!("munmap!!! {:#x} {:#x}", va, len);
     mmap::munmap(va, len)
+        .unwrap_or_else(|e| {
+            linux_err_from!(e)
+        })
+}
+
+fn linux_syscall_mremap(args: SyscallArgs) -> usize {
+    let [old_addr, old_len, new_len, flags, new_addr, ..] = args;
+    mmap::mremap(old_addr, old_len, new_len, flags, new_addr)
+        .unwrap_or_else(|e| {
+            linux_err_from!(e)
+        })
 }
 
 fn linux_syscall_msync(args: SyscallArgs) -> usize {
@@ -262,20 +336,24 @@ fn linux_syscall_ioctl(args: SyscallArgs) -> usize {
 
 fn linux_syscall_getcwd(args: SyscallArgs) -> usize {
     let [buf, size, ..] = args;
-
-    let ubuf = unsafe { core::slice::from_raw_parts_mut(buf as *mut u8, size) };
-    fileops::getcwd(ubuf)
+    fileops::getcwd(buf, size)
 }
 
 fn linux_syscall_chdir(args: SyscallArgs) -> usize {
     let [pathname, ..] = args;
-    let pathname = get_user_str(pathname);
+    let pathname = match get_user_str(pathname) {
+        Ok(pathname) => pathname,
+        Err(e) => return linux_err_from!(e),
+    };
     fileops::chdir(&pathname)
 }
 
 fn linux_syscall_mprotect(args: SyscallArgs) -> usize {
     let [va, len, prot, ..] = args;
     mmap::mprotect(va, len, prot)
+        .unwrap_or_else(|e| {
+            linux_err_from!(e)
+        })
 }
 
 fn linux_syscall_set_tid_address(args: SyscallArgs) -> usize {
@@ -283,9 +361,19 @@ fn linux_syscall_set_tid_address(args: SyscallArgs) -> usize {
     fork::set_tid_address(tidptr)
 }
 
-fn linux_syscall_set_robust_list(_args: SyscallArgs) -> usize {
-    warn!("impl linux_syscall_set_robust_list");
-    0
+fn linux_syscall_set_robust_list(args: SyscallArgs) -> usize {
+    let [head, len, ..] = args;
+    futex::set_robust_list(head, len)
+}
+
+fn linux_syscall_get_robust_list(args: SyscallArgs) -> usize {
+    let [tid, head_ptr, len_ptr, ..] = args;
+    futex::get_robust_list(tid, head_ptr, len_ptr)
+}
+
+fn linux_syscall_futex(args: SyscallArgs) -> usize {
+    let [uaddr, op, val, timeout, uaddr2, val3] = args;
+    futex::futex(uaddr, op, val, timeout, uaddr2, val3)
 }
 
 fn linux_syscall_prlimit64(args: SyscallArgs) -> usize {
@@ -298,6 +386,16 @@ fn linux_syscall_wait4(args: SyscallArgs) -> usize {
     sys::wait4(pid, wstatus, options, rusage)
 }
 
+fn linux_syscall_waitid(args: SyscallArgs) -> usize {
+    let [which, pid, infop, options, rusage, ..] = args;
+    sys::waitid(which, pid, infop, options, rusage)
+}
+
+fn linux_syscall_prctl(args: SyscallArgs) -> usize {
+    let [option, arg2, ..] = args;
+    sys::prctl(option, arg2)
+}
+
 fn linux_syscall_getrandom(args: SyscallArgs) -> usize {
     let [buf, len, flags, ..] = args;
     warn!(
@@ -319,11 +417,22 @@ fn linux_syscall_clock_nanosleep(_args: SyscallArgs) -> usize {
 
 fn linux_syscall_rt_sigprocmask(args: SyscallArgs) -> usize {
     let [how, set, oldset, sigsetsize, ..] = args;
-    warn!(
-        "impl linux_syscall_rt_sigprocmask how {} set {:#X} oldset {:#X} size {} tid {}",
-        how, set, oldset, sigsetsize, task::current().tid(),
-    );
-    0
+    signal::rt_sigprocmask(how, set, oldset, sigsetsize)
+}
+
+fn linux_syscall_rt_sigpending(args: SyscallArgs) -> usize {
+    let [set, sigsetsize, ..] = args;
+    signal::rt_sigpending(set, sigsetsize)
+}
+
+fn linux_syscall_rt_sigsuspend(args: SyscallArgs) -> usize {
+    let [newset, sigsetsize, ..] = args;
+    signal::rt_sigsuspend(newset, sigsetsize)
+}
+
+fn linux_syscall_rt_sigtimedwait(args: SyscallArgs) -> usize {
+    let [these, info, ts, sigsetsize, ..] = args;
+    signal::rt_sigtimedwait(these, info, ts, sigsetsize)
 }
 
 fn linux_syscall_rt_sigaction(args: SyscallArgs) -> usize {
@@ -335,6 +444,11 @@ fn linux_syscall_rt_sigreturn(_args: SyscallArgs) -> usize {
     signal::rt_sigreturn()
 }
 
+fn linux_syscall_sigaltstack(args: SyscallArgs) -> usize {
+    let [uss, uoss, ..] = args;
+    signal::sigaltstack(uss, uoss)
+}
+
 fn linux_syscall_gettid(_args: SyscallArgs) -> usize {
     sys::gettid()
 }
@@ -360,9 +474,14 @@ fn linux_syscall_setpgid(_args: SyscallArgs) -> usize {
     sys::setpgid()
 }
 
-fn linux_syscall_tgkill(_args: SyscallArgs) -> usize {
-    warn!("impl linux_syscall_tgkill");
-    0
+fn linux_syscall_tkill(args: SyscallArgs) -> usize {
+    let [tid, sig, ..] = args;
+    signal::tkill(tid, sig)
+}
+
+fn linux_syscall_tgkill(args: SyscallArgs) -> usize {
+    let [tgid, tid, sig, ..] = args;
+    signal::tgkill(tgid, tid, sig)
 }
 
 fn linux_syscall_kill(args: SyscallArgs) -> usize {
@@ -378,6 +497,7 @@ fn linux_syscall_arch_prctl(args: SyscallArgs) -> usize {
 
 const UTS_LEN: usize = 64;
 
+#[derive(Clone, Copy)]
 #[repr(C)]
 struct utsname {
     sysname: [u8; UTS_LEN + 1],
@@ -388,11 +508,20 @@ struct utsname {
     domainname: [u8; UTS_LEN + 1],
 }
 
+unsafe impl uaccess::Pod for utsname {}
+
 fn linux_syscall_uname(args: SyscallArgs) -> usize {
     let ptr = args[0];
     info!("uname: {:#x}", ptr);
 
-    let uname = unsafe { (ptr as *mut utsname).as_mut().unwrap() };
+    let mut uname = utsname {
+        sysname: [0; UTS_LEN + 1],
+        nodename: [0; UTS_LEN + 1],
+        release: [0; UTS_LEN + 1],
+        version: [0; UTS_LEN + 1],
+        machine: [0; UTS_LEN + 1],
+        domainname: [0; UTS_LEN + 1],
+    };
 
     init_bytes_from_str(&mut uname.sysname[..], "Linux");
     init_bytes_from_str(&mut uname.nodename[..], "host");
@@ -404,7 +533,10 @@ fn linux_syscall_uname(args: SyscallArgs) -> usize {
     );
     init_bytes_from_str(&mut uname.machine[..], "riscv64");
 
-    return 0;
+    match uaccess::put_user(uname, ptr) {
+        Ok(()) => 0,
+        Err(e) => linux_err_from!(e),
+    }
 }
 
 fn init_bytes_from_str(dst: &mut [u8], src: &str) {
@@ -431,7 +563,10 @@ fn linux_syscall_clone(args: SyscallArgs) -> usize {
 
 fn linux_syscall_execve(args: SyscallArgs) -> usize {
     let [path, argv, envp, ..] = args;
-    let path = get_user_str(path);
+    let path = match get_user_str(path) {
+        Ok(path) => path,
+        Err(e) => return linux_err_from!(e),
+    };
     exec::execve(&path, argv, envp)
 }
 
@@ -452,4 +587,5 @@ fn linux_syscall_vfork(_args: SyscallArgs) -> usize {
 
 pub fn init() {
     info!("Initialize systemcalls ...");
+    trace::init();
 }
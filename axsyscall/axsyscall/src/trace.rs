@@ -0,0 +1,294 @@
+//! Syscall tracing, just like strace.
+//!
+//! The tracer is switched by boot param 'strace=<spec>', or by writing
+//! the spec to /proc/sys/kernel/strace. Spec is one of:
+//!   "all"         trace all tasks
+//!   "1,3"         trace the tasks with these tids
+//!   "off" or ""   stop tracing
+//!
+//! Unknown syscalls are counted per number, and the counts are listed
+//! in /proc/sys/kernel/unknown_syscalls.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use axerrno::LinuxError;
+use axfs_vfs::{VfsError, VfsNodeAttr, VfsNodeOps, VfsNodePerm, VfsNodeType, VfsResult};
+use axhal::arch::sysno::*;
+use spinpreempt::SpinLock;
+use taskctx::Tid;
+use crate::SyscallArgs;
+
+/// Whether any task is traced. Fast path for the common case.
+static TRACE_ON: AtomicBool = AtomicBool::new(false);
+static TRACE_ALL: AtomicBool = AtomicBool::new(false);
+static TRACE_TIDS: SpinLock<BTreeSet<Tid>> = SpinLock::new(BTreeSet::new());
+
+/// Counts of unknown syscalls (sysno -> count).
+static UNKNOWN_SYSCALLS: SpinLock<BTreeMap<usize, usize>> = SpinLock::new(BTreeMap::new());
+
+/// Sets tasks to be traced by `spec`.
+pub fn set_syscall_trace(spec: &str) -> Result<(), LinuxError> {
+    let spec = spec.trim();
+    let mut tids = BTreeSet::new();
+    let all = match spec {
+        "" | "off" => false,
+        "all" => true,
+        _ => {
+            for tid in spec.split(',') {
+                let tid = tid.trim().parse::<Tid>().map_err(|_| LinuxError::EINVAL)?;
+                tids.insert(tid);
+            }
+            false
+        }
+    };
+
+    let on = all || !tids.is_empty();
+    *TRACE_TIDS.lock() = tids;
+    TRACE_ALL.store(all, Ordering::Release);
+    TRACE_ON.store(on, Ordering::Release);
+    info!("syscall trace: '{}'", spec);
+    Ok(())
+}
+
+/// Returns the spec of the tracer, in the same format as it's set.
+pub fn syscall_trace() -> String {
+    if !TRACE_ON.load(Ordering::Acquire) {
+        return String::from("off");
+    }
+    if TRACE_ALL.load(Ordering::Acquire) {
+        return String::from("all");
+    }
+    let tids: Vec<String> = TRACE_TIDS.lock().iter().map(|tid| tid.to_string()).collect();
+    tids.join(",")
+}
+
+pub(crate) fn traced(tid: Tid) -> bool {
+    if !TRACE_ON.load(Ordering::Relaxed) {
+        return false;
+    }
+    TRACE_ALL.load(Ordering::Relaxed) || TRACE_TIDS.lock().contains(&tid)
+}
+
+pub(crate) fn count_unknown(sysno: usize) -> usize {
+    let mut unknown = UNKNOWN_SYSCALLS.lock();
+    let count = unknown.entry(sysno).or_insert(0);
+    *count += 1;
+    *count
+}
+
+/// Returns counts of unknown syscalls, which are ordered by sysno.
+pub fn unknown_syscalls() -> Vec<(usize, usize)> {
+    UNKNOWN_SYSCALLS.lock().iter().map(|(&nr, &count)| (nr, count)).collect()
+}
+
+/// Syscalls which never return to the caller.
+pub(crate) fn noreturn(sysno: usize) -> bool {
+    sysno == LINUX_SYSCALL_EXIT || sysno == LINUX_SYSCALL_EXIT_GROUP
+}
+
+pub(crate) fn trace_syscall(tid: Tid, sysno: usize, args: &SyscallArgs, ret: Option<usize>) {
+    let mut line = String::new();
+    let _ = match syscall_name(sysno) {
+        Some((name, nargs)) => write!(line, "[{}] {}(", tid, name).and_then(|_| {
+            for (i, arg) in args[..nargs].iter().enumerate() {
+                let sep = if i == 0 { "" } else { ", " };
+                write!(line, "{}{:#x}", sep, arg)?;
+            }
+            Ok(())
+        }),
+        None => write!(line, "[{}] syscall_{}({:#x?})", tid, sysno, args),
+    };
+    let _ = match ret {
+        Some(ret) if (ret as isize) < 0 && (ret as isize) >= -4095 => {
+            let code = -(ret as isize) as i32;
+            match LinuxError::try_from(code) {
+                Ok(e) => write!(line, ") = -1 {:?} ({})", e, e.as_str()),
+                Err(_) => write!(line, ") = {}", ret as isize),
+            }
+        },
+        Some(ret) => write!(line, ") = {:#x}", ret),
+        None => write!(line, ") = ?"),
+    };
+    axlog2::ax_println!("{}", line);
+}
+
+/// Returns name and number of args of the syscall.
+fn syscall_name(sysno: usize) -> Option<(&'static str, usize)> {
+    let name = match sysno {
+        LINUX_SYSCALL_IOCTL => ("ioctl", 3),
+        LINUX_SYSCALL_GETCWD => ("getcwd", 2),
+        LINUX_SYSCALL_CHDIR => ("chdir", 1),
+        LINUX_SYSCALL_FACCESSAT => ("faccessat", 3),
+        LINUX_SYSCALL_MKDIRAT => ("mkdirat", 3),
+        LINUX_SYSCALL_UNLINKAT => ("unlinkat", 3),
+        LINUX_SYSCALL_OPENAT => ("openat", 4),
+        LINUX_SYSCALL_CLOSE => ("close", 1),
+        LINUX_SYSCALL_DUP => ("dup", 1),
+        LINUX_SYSCALL_DUP3 => ("dup3", 3),
+        LINUX_SYSCALL_FCNTL => ("fcntl", 3),
+        LINUX_SYSCALL_LSEEK => ("lseek", 3),
+        LINUX_SYSCALL_READ => ("read", 3),
+        LINUX_SYSCALL_PREAD64 => ("pread64", 4),
+        LINUX_SYSCALL_WRITE => ("write", 3),
+        LINUX_SYSCALL_WRITEV => ("writev", 3),
+        LINUX_SYSCALL_READLINKAT => ("readlinkat", 4),
+        LINUX_SYSCALL_FTRUNCATE => ("ftruncate", 2),
+        LINUX_SYSCALL_FSTATAT => ("newfstatat", 4),
+        LINUX_SYSCALL_UNAME => ("uname", 1),
+        LINUX_SYSCALL_BRK => ("brk", 1),
+        LINUX_SYSCALL_RSEQ => ("rseq", 4),
+        LINUX_SYSCALL_CLONE => ("clone", 5),
+        LINUX_SYSCALL_EXECVE => ("execve", 3),
+        LINUX_SYSCALL_MUNMAP => ("munmap", 2),
+        LINUX_SYSCALL_MREMAP => ("mremap", 5),
+        LINUX_SYSCALL_MMAP => ("mmap", 6),
+        LINUX_SYSCALL_MSYNC => ("msync", 3),
+        LINUX_SYSCALL_MADVISE => ("madvise", 3),
+        LINUX_SYSCALL_MPROTECT => ("mprotect", 3),
+        LINUX_SYSCALL_SET_TID_ADDRESS => ("set_tid_address", 1),
+        LINUX_SYSCALL_SET_ROBUST_LIST => ("set_robust_list", 2),
+        LINUX_SYSCALL_GET_ROBUST_LIST => ("get_robust_list", 3),
+        LINUX_SYSCALL_FUTEX => ("futex", 6),
+        LINUX_SYSCALL_WAIT4 => ("wait4", 4),
+        LINUX_SYSCALL_WAITID => ("waitid", 5),
+        LINUX_SYSCALL_PRCTL => ("prctl", 5),
+        LINUX_SYSCALL_PRLIMIT64 => ("prlimit64", 4),
+        LINUX_SYSCALL_GETRANDOM => ("getrandom", 3),
+        LINUX_SYSCALL_CLOCK_GETTIME => ("clock_gettime", 2),
+        LINUX_SYSCALL_CLOCK_NANOSLEEP => ("clock_nanosleep", 4),
+        LINUX_SYSCALL_RT_SIGPROCMASK => ("rt_sigprocmask", 4),
+        LINUX_SYSCALL_RT_SIGPENDING => ("rt_sigpending", 2),
+        LINUX_SYSCALL_RT_SIGSUSPEND => ("rt_sigsuspend", 2),
+        LINUX_SYSCALL_RT_SIGTIMEDWAIT => ("rt_sigtimedwait", 4),
+        LINUX_SYSCALL_RT_SIGACTION => ("rt_sigaction", 4),
+        LINUX_SYSCALL_RT_SIGRETURN => ("rt_sigreturn", 0),
+        LINUX_SYSCALL_SIGALTSTACK => ("sigaltstack", 2),
+        LINUX_SYSCALL_GETTID => ("gettid", 0),
+        LINUX_SYSCALL_GETPID => ("getpid", 0),
+        LINUX_SYSCALL_GETPPID => ("getppid", 0),
+        LINUX_SYSCALL_GETGID => ("getgid", 0),
+        LINUX_SYSCALL_SETPGID => ("setpgid", 2),
+        LINUX_SYSCALL_GETEUID => ("geteuid", 0),
+        LINUX_SYSCALL_KILL => ("kill", 2),
+        LINUX_SYSCALL_TKILL => ("tkill", 2),
+        LINUX_SYSCALL_TGKILL => ("tgkill", 3),
+        LINUX_SYSCALL_EXIT => ("exit", 1),
+        LINUX_SYSCALL_EXIT_GROUP => ("exit_group", 1),
+        LINUX_SYSCALL_FCHMOD => ("fchmod", 2),
+        LINUX_SYSCALL_FCHMODAT => ("fchmodat", 3),
+        LINUX_SYSCALL_FCHOWNAT => ("fchownat", 5),
+        LINUX_SYSCALL_SCHED_GETAFFINITY => ("sched_getaffinity", 3),
+        LINUX_SYSCALL_CAPGET => ("capget", 2),
+        LINUX_SYSCALL_SETITIMER => ("setitimer", 3),
+        #[cfg(target_arch = "riscv64")]
+        LINUX_SYSCALL_GETDENTS64 => ("getdents64", 3),
+        #[cfg(target_arch = "x86_64")]
+        LINUX_SYSCALL_ACCESS => ("access", 2),
+        #[cfg(target_arch = "x86_64")]
+        LINUX_SYSCALL_ARCH_PRCTL => ("arch_prctl", 2),
+        #[cfg(target_arch = "x86_64")]
+        LINUX_SYSCALL_VFORK => ("vfork", 0),
+        #[cfg(target_arch = "x86_64")]
+        LINUX_SYSCALL_DUP2 => ("dup2", 2),
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Copies the content of a proc entry from `offset`.
+fn read_proc_str(src: &str, offset: u64, buf: &mut [u8]) -> usize {
+    let src = src.as_bytes();
+    let start = core::cmp::min(offset as usize, src.len());
+    let len = core::cmp::min(src.len() - start, buf.len());
+    buf[..len].copy_from_slice(&src[start..start + len]);
+    len
+}
+
+/// /proc/sys/kernel/strace
+struct TraceKnob;
+
+impl VfsNodeOps for TraceKnob {
+    fn get_attr(&self) -> VfsResult<VfsNodeAttr> {
+        let perm = VfsNodePerm::from_bits_truncate(0o644);
+        Ok(VfsNodeAttr::new(perm, VfsNodeType::File, 0, 0))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        let mut spec = syscall_trace();
+        spec.push('\n');
+        Ok(read_proc_str(&spec, offset, buf))
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> VfsResult<usize> {
+        let spec = core::str::from_utf8(buf).map_err(|_| VfsError::InvalidInput)?;
+        set_syscall_trace(spec).map_err(|_| VfsError::InvalidInput)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, _size: u64) -> VfsResult {
+        Ok(())
+    }
+}
+
+/// /proc/sys/kernel/unknown_syscalls
+struct UnknownSyscalls;
+
+impl VfsNodeOps for UnknownSyscalls {
+    fn get_attr(&self) -> VfsResult<VfsNodeAttr> {
+        let perm = VfsNodePerm::from_bits_truncate(0o444);
+        Ok(VfsNodeAttr::new(perm, VfsNodeType::File, 0, 0))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        let mut content = String::new();
+        for (nr, count) in unknown_syscalls() {
+            let _ = writeln!(content, "{} {}", nr, count);
+        }
+        Ok(read_proc_str(&content, offset, buf))
+    }
+}
+
+pub(crate) fn init() {
+    fileops::proc_create("/proc/sys/kernel/strace", Arc::new(TraceKnob));
+    fileops::proc_create("/proc/sys/kernel/unknown_syscalls", Arc::new(UnknownSyscalls));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The tracer is global, so the cases run in one test in order.
+    #[test]
+    fn test_set_syscall_trace() {
+        set_syscall_trace("all").unwrap();
+        assert_eq!(syscall_trace(), "all");
+        assert!(traced(1) && traced(100));
+
+        set_syscall_trace("1,3").unwrap();
+        assert_eq!(syscall_trace(), "1,3");
+        assert!(traced(1) && traced(3));
+        assert!(!traced(2));
+
+        set_syscall_trace(" 2 ").unwrap();
+        assert_eq!(syscall_trace(), "2");
+        assert!(traced(2) && !traced(1));
+
+        // A bad tid keeps the old spec.
+        assert_eq!(set_syscall_trace("1,x"), Err(LinuxError::EINVAL));
+        assert_eq!(set_syscall_trace("-1"), Err(LinuxError::EINVAL));
+        assert_eq!(syscall_trace(), "2");
+
+        set_syscall_trace("off").unwrap();
+        assert_eq!(syscall_trace(), "off");
+        assert!(!traced(2));
+
+        set_syscall_trace("all").unwrap();
+        set_syscall_trace("").unwrap();
+        assert_eq!(syscall_trace(), "off");
+        assert!(!traced(1));
+    }
+}
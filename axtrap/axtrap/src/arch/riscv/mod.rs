@@ -5,6 +5,8 @@ use riscv::register::scause::{self, Exception as E, Trap};
 use riscv::register::stval;
 use riscv::register::stvec;
 use preempt_guard::NoPreempt;
+use signal::{SEGV_ACCERR, BUS_ADRALN, ILL_ILLOPC};
+use task::{SIGSEGV, SIGBUS, SIGILL};
 
 axhal::include_asm_marcos!();
 
@@ -23,21 +25,41 @@ pub fn init_trap() {
 }
 
 #[no_mangle]
-pub fn riscv_trap_handler(tf: &mut TrapFrame, _from_user: bool) {
+pub fn riscv_trap_handler(tf: &mut TrapFrame, from_user: bool) {
     let scause = scause::read();
     match scause.cause() {
         Trap::Exception(E::Breakpoint) => handle_breakpoint(&mut tf.sepc),
         Trap::Exception(E::UserEnvCall) => handle_linux_syscall(tf),
         Trap::Exception(E::InstructionPageFault) => {
-            handle_page_fault(stval::read(), scause.code(), tf);
+            handle_page_fault(stval::read(), scause.code(), tf, from_user);
         }
         Trap::Exception(E::LoadPageFault) => {
-            handle_page_fault(stval::read(), scause.code(), tf);
+            handle_page_fault(stval::read(), scause.code(), tf, from_user);
         }
         Trap::Exception(E::StorePageFault) => {
-            handle_page_fault(stval::read(), scause.code(), tf);
+            handle_page_fault(stval::read(), scause.code(), tf, from_user);
+        }
+        Trap::Exception(E::LoadFault) | Trap::Exception(E::StoreFault) if from_user => {
+            signal::force_sig_fault(SIGSEGV, SEGV_ACCERR, stval::read());
+            signal::do_signal(tf);
+        }
+        Trap::Exception(E::LoadMisaligned) | Trap::Exception(E::StoreMisaligned) if from_user => {
+            signal::force_sig_fault(SIGBUS, BUS_ADRALN, stval::read());
+            signal::do_signal(tf);
+        }
+        Trap::Exception(E::IllegalInstruction) if from_user => {
+            warn!("User illegal instruction @ {:#x}", tf.sepc);
+            signal::force_sig_fault(SIGILL, ILL_ILLOPC, tf.sepc);
+            signal::do_signal(tf);
+        }
+        Trap::Interrupt(_) => {
+            handle_irq_extern(scause.bits(), tf);
+            // A task which never traps otherwise, e.g., a busy loop,
+            // gets its signals on the return to user mode.
+            if from_user {
+                signal::do_signal(tf);
+            }
         }
-        Trap::Interrupt(_) => handle_irq_extern(scause.bits(), tf),
         _ => {
             panic!(
                 "Unhandled trap {:?} @ {:#x}:\n{:#x?}",
@@ -50,17 +72,26 @@ pub fn riscv_trap_handler(tf: &mut TrapFrame, _from_user: bool) {
 }
 
 /// Call page fault handler.
-fn handle_page_fault(badaddr: usize, cause: usize, tf: &mut TrapFrame) {
+fn handle_page_fault(badaddr: usize, cause: usize, tf: &mut TrapFrame, from_user: bool) {
     debug!("handle_page_fault... cause {}", cause);
-    mmap::faultin_page(badaddr, cause);
-    signal::do_signal(tf);
+    if let Err(fault) = mmap::faultin_page(badaddr, cause) {
+        if !from_user {
+            panic!(
+                "Kernel page fault @ {:#x}, badaddr={:#x}, cause={}:\n{:#x?}",
+                tf.sepc, badaddr, cause, tf
+            );
+        }
+        crate::force_sig_vm_fault(fault, badaddr);
+    }
+    if from_user {
+        signal::do_signal(tf);
+    }
 }
 
 /// Call the external IRQ handler.
 fn handle_irq_extern(irq_num: usize, _tf: &mut TrapFrame) {
     let _ = NoPreempt::new();
     crate::platform::irq::dispatch_irq(irq_num);
-    // Todo: why we cannot do_signal here (irq context -> userland).
     //drop(guard); // rescheduling may occur when preemption is re-enabled.
 }
 
@@ -71,8 +102,9 @@ fn handle_breakpoint(sepc: &mut usize) {
 
 fn handle_linux_syscall(tf: &mut TrapFrame) {
     debug!("handle_linux_syscall");
+    let (sysno, orig_a0) = (tf.regs.a7, tf.regs.a0);
     syscall(tf, axsyscall::do_syscall);
-    signal::do_signal(tf);
+    signal::do_signal_syscall(tf, sysno, orig_a0);
 }
 
 fn syscall_args(tf: &TrapFrame) -> SyscallArgs {
@@ -4,13 +4,21 @@ mod syscall;
 use x86::{controlregs::cr2, irq::*};
 use preempt_guard::NoPreempt;
 
-use axhal::arch::TrapFrame;
+use axhal::arch::{TrapFrame, user_access_end};
+use axhal::arch::{EXC_INST_PAGE_FAULT, EXC_LOAD_PAGE_FAULT, EXC_STORE_PAGE_FAULT};
+use signal::{SI_KERNEL, ILL_ILLOPN, FPE_INTDIV};
+use signal::{FPE_FLTINV, FPE_FLTDIV, FPE_FLTOVF, FPE_FLTUND, FPE_FLTRES};
+use task::{SIGSEGV, SIGILL, SIGFPE};
 
 core::arch::global_asm!(include_str!("trap.S"));
 
 const IRQ_VECTOR_START: u8 = 0x20;
 const IRQ_VECTOR_END: u8 = 0xff;
 
+// Bits in error code of page fault
+const PF_WRITE: u64 = 1 << 1;
+const PF_INSTR: u64 = 1 << 4;
+
 pub fn init_trap() {
     // To init the IDT
     crate::platform::init_percpu_interrupt();
@@ -19,11 +27,13 @@ pub fn init_trap() {
 
 #[no_mangle]
 fn x86_trap_handler(tf: &mut TrapFrame) {
+    // User may set AC (by popf or sigreturn), which would lift SMAP for
+    // the kernel. Unlike syscall (see SFMask), traps don't clear it.
+    user_access_end();
     match tf.vector as u8 {
         PAGE_FAULT_VECTOR => {
-            // if tf.is_user() {
             debug!(
-                "User #PF @ {:#x}, fault_vaddr={:#x}, error_code={:#x}",
+                "#PF @ {:#x}, fault_vaddr={:#x}, error_code={:#x}",
                 tf.rip,
                 unsafe { cr2() },
                 tf.error_code,
@@ -33,40 +43,40 @@ fn x86_trap_handler(tf: &mut TrapFrame) {
             // +---+--  --+---+-----+---+--  --+---+----+----+---+---+---+---+---+
             // |   Reserved   | SGX |   Reserved   | SS | PK | I | R | U | W | P |
             // +---+--  --+---+-----+---+--  --+---+----+----+---+---+---+---+---+
-            /*
-            log::debug!("error_code: {:?}", tf.error_code);
-            if tf.error_code & (1 << 1) != 0 {
-                handle_page_fault(badaddr, 2);
-            }
-            if tf.error_code & (1 << 2) != 0 {
-                handle_page_fault(badaddr, 3);
-            }
-            if tf.error_code & (1 << 3) != 0 {
-                handle_page_fault(badaddr, 1);
-            }
-            if tf.error_code & (1 << 4) != 0 {
-                handle_page_fault(badaddr, 0);
-            }
-            */
-            // Todo: set proper cause for handle_page_fault.
-            handle_page_fault(badaddr, 0 /* cause */);
-            // } else {
-            //     panic!(
-            //         "Kernel #PF @ {:#x}, fault_vaddr={:#x}, error_code={:#x}:\n{:#x?}",
-            //         tf.rip,
-            //         unsafe { cr2() },
-            //         tf.error_code,
-            //         tf,
-            //     );
-            // }
+            let cause = if tf.error_code & PF_INSTR != 0 {
+                EXC_INST_PAGE_FAULT
+            } else if tf.error_code & PF_WRITE != 0 {
+                EXC_STORE_PAGE_FAULT
+            } else {
+                EXC_LOAD_PAGE_FAULT
+            };
+            handle_page_fault(badaddr, cause, tf);
         }
         BREAKPOINT_VECTOR => debug!("#BP @ {:#x} ", tf.rip),
+        GENERAL_PROTECTION_FAULT_VECTOR if tf.is_user() => {
+            warn!("User #GP @ {:#x}, error_code={:#x}", tf.rip, tf.error_code);
+            signal::force_sig_fault(SIGSEGV, SI_KERNEL, 0);
+        }
         GENERAL_PROTECTION_FAULT_VECTOR => {
             panic!(
                 "#GP @ {:#x}, error_code={:#x}:\n{:#x?}",
                 tf.rip, tf.error_code, tf
             );
         }
+        INVALID_OPCODE_VECTOR if tf.is_user() => {
+            warn!("User #UD @ {:#x}", tf.rip);
+            signal::force_sig_fault(SIGILL, ILL_ILLOPN, tf.rip as usize);
+        }
+        DIVIDE_ERROR_VECTOR if tf.is_user() => {
+            warn!("User #DE @ {:#x}", tf.rip);
+            signal::force_sig_fault(SIGFPE, FPE_INTDIV, tf.rip as usize);
+        }
+        X87_FPU_VECTOR | SIMD_FLOATING_POINT_VECTOR if tf.is_user() => {
+            warn!("User #MF/#XM @ {:#x}", tf.rip);
+            if let Some(code) = fpu_exception_code(tf.vector as u8) {
+                signal::force_sig_fault(SIGFPE, code, tf.rip as usize);
+            }
+        }
         IRQ_VECTOR_START..=IRQ_VECTOR_END => handle_irq_extern(tf.vector as _),
         _ => {
             panic!(
@@ -75,15 +85,56 @@ fn x86_trap_handler(tf: &mut TrapFrame) {
             );
         }
     }
-    #[cfg(feature = "signal")]
     if tf.is_user() {
-        crate::trap::handle_signal();
+        signal::do_signal(tf);
     }
 }
+/// Works out the si_code of a #MF or #XM from the FPU state, which
+/// still belongs to the faulting task since the kernel never uses it.
+/// None means a spurious trap, which is ignored.
+fn fpu_exception_code(vector: u8) -> Option<usize> {
+    // Unmasked exceptions which are pending.
+    let err = if vector == SIMD_FLOATING_POINT_VECTOR {
+        let mut mxcsr: u32 = 0;
+        unsafe { core::arch::asm!("stmxcsr [{}]", in(reg) &mut mxcsr) };
+        !(mxcsr >> 7) & mxcsr
+    } else {
+        let mut cwd: u16 = 0;
+        let swd: u16;
+        unsafe {
+            core::arch::asm!("fnstcw [{}]", in(reg) &mut cwd);
+            core::arch::asm!("fnstsw ax", out("ax") swd);
+        }
+        (!cwd & swd) as u32
+    };
+
+    if err & 0x001 != 0 {
+        Some(FPE_FLTINV)
+    } else if err & 0x004 != 0 {
+        Some(FPE_FLTDIV)
+    } else if err & 0x008 != 0 {
+        Some(FPE_FLTOVF)
+    } else if err & 0x012 != 0 {
+        Some(FPE_FLTUND)
+    } else if err & 0x020 != 0 {
+        Some(FPE_FLTRES)
+    } else {
+        None
+    }
+}
+
 /// Call page fault handler.
-fn handle_page_fault(badaddr: usize, cause: usize) {
-    debug!("handle_page_fault...");
-    mmap::faultin_page(badaddr, cause);
+fn handle_page_fault(badaddr: usize, cause: usize, tf: &mut TrapFrame) {
+    debug!("handle_page_fault... cause {}", cause);
+    if let Err(fault) = mmap::faultin_page(badaddr, cause) {
+        if !tf.is_user() {
+            panic!(
+                "Kernel #PF @ {:#x}, fault_vaddr={:#x}, error_code={:#x}:\n{:#x?}",
+                tf.rip, badaddr, tf.error_code, tf,
+            );
+        }
+        crate::force_sig_vm_fault(fault, badaddr);
+    }
 }
 
 /// Call the external IRQ handler.
@@ -13,7 +13,11 @@ use axhal::arch::GdtStruct;
 
 use super::TrapFrame;
 
-global_asm!(include_str!("syscall.S"));
+global_asm!(
+    include_str!("syscall.S"),
+    ucode_selector = const GdtStruct::UCODE64_SELECTOR.0,
+    udata_selector = const GdtStruct::UDATA_SELECTOR.0,
+);
 
 pub fn init_syscall() {
     extern "C" {
@@ -44,7 +48,9 @@ pub fn init_syscall() {
 #[no_mangle]
 fn x86_syscall_handler(tf: &mut TrapFrame) {
     debug!("handle_linux_syscall");
+    let orig_rax = tf.rax as usize;
     syscall(tf, axsyscall::do_syscall);
+    signal::do_signal_syscall(tf, orig_rax, orig_rax);
 }
 fn syscall_args(tf: &TrapFrame) -> SyscallArgs {
     [tf.rdi, tf.rsi, tf.rdx, tf.r10, tf.r8, tf.r9].map(|n| n as _)
@@ -10,6 +10,9 @@ mod platform;
 use crate::irq::IrqHandler;
 use axhal::time::TIMER_IRQ_NUM;
 use preempt_guard::NoPreempt;
+use mmap::VmFault;
+use signal::{SEGV_MAPERR, SEGV_ACCERR, BUS_ADRERR};
+use task::{SIGSEGV, SIGBUS};
 
 pub fn init(cpu_id: usize, dtb_pa: usize) {
     axconfig::init_once!();
@@ -24,6 +27,7 @@ pub fn init(cpu_id: usize, dtb_pa: usize) {
     arch::init_trap();
     // Todo: extract irq as standalone modular axirq.
     axsyscall::init();
+    signal::init();
 
     register_irq_handler(TIMER_IRQ_NUM, || {
         update_timer();
@@ -50,6 +54,18 @@ pub fn update_timer() {
     axhal::time::set_oneshot_timer(deadline);
 }
 
+/// Sends the signal for a bad page fault from user mode.
+fn force_sig_vm_fault(fault: VmFault, addr: usize) {
+    let (signo, code) = match fault {
+        VmFault::MapErr => (SIGSEGV, SEGV_MAPERR),
+        VmFault::AccErr => (SIGSEGV, SEGV_ACCERR),
+        VmFault::SigBus => (SIGBUS, BUS_ADRERR),
+        // No OOM killer here, so the faulting task gets SIGBUS.
+        VmFault::Oom => (SIGBUS, BUS_ADRERR),
+    };
+    signal::force_sig_fault(signo, code, addr);
+}
+
 pub fn register_irq_handler(irq: usize, handler: IrqHandler) {
     irq::register_handler(irq, handler);
 }
@@ -2,7 +2,6 @@
 
 extern crate alloc;
 use alloc::string::String;
-use alloc::vec::Vec;
 
 pub const PAGE_SIZE: usize  = 0x1000;
 pub const PAGE_SHIFT: usize = 12;
@@ -97,11 +96,6 @@ impl DtbInfo {
     }
 }
 
-pub fn get_user_str(ptr: usize) -> String {
-    let ptr = ptr as *const u8;
-    String::from(raw_ptr_to_ref_str(ptr))
-}
-
 /// # Safety
 ///
 /// The caller must ensure that the pointer is valid and
@@ -127,18 +121,3 @@ pub unsafe fn get_str_len(ptr: *const u8) -> usize {
     }
     cur - ptr as usize
 }
-
-pub fn get_user_str_vec(addr: usize) -> Vec<String> {
-    let mut vec = Vec::new();
-    let ptr = addr as *const usize;
-    let mut index = 0;
-    loop {
-        let ptr_str = unsafe { ptr.add(index).read() };
-        if ptr_str == 0 {
-            break;
-        }
-        vec.push(get_user_str(ptr_str));
-        index += 1;
-    }
-    vec
-}
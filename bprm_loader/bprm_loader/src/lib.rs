@@ -4,133 +4,204 @@
 extern crate log;
 extern crate alloc;
 
+use core::mem::size_of;
 use core::ptr::null;
 use core::str::from_utf8;
+use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::vec;
 use alloc::vec::Vec;
 use alloc::string::String;
 
-use axerrno::LinuxResult;
+use axerrno::{LinuxError, LinuxResult};
 use axhal::arch::STACK_SIZE;
-use elf::abi::{PT_INTERP, PT_LOAD};
+use elf::abi::{ET_DYN, PT_INTERP, PT_LOAD};
 use elf::endian::AnyEndian;
 use elf::parse::ParseAt;
 use elf::segment::ProgramHeader;
 use elf::segment::SegmentTable;
 use elf::ElfBytes;
 use axio::SeekFrom;
-use axtype::{align_down_4k, align_up_4k, PAGE_SIZE};
+use axtype::{align_up, align_down_4k, align_up_4k, PAGE_SIZE};
 use axtype::is_aligned;
 use mmap::FileRef;
-use mmap::{MAP_ANONYMOUS, MAP_FIXED};
+use mmap::{MAP_ANONYMOUS, MAP_FIXED, MAP_GROWSDOWN};
 use user_stack::UserStack;
 use axhal::arch::{ELF_ET_DYN_BASE, TASK_SIZE};
-use mmap::{PROT_READ, PROT_WRITE, PROT_EXEC};
+use mmap::{PROT_NONE, PROT_READ, PROT_WRITE, PROT_EXEC};
+use task::RLIMIT_STACK;
 use elf::abi::{PF_R, PF_W, PF_X};
 
 const ELF_HEAD_BUF_SIZE: usize = 256;
 
+/// An elf file opened for execve, with its program headers.
+struct ElfFile {
+    file: FileRef,
+    phdrs: Vec<ProgramHeader>,
+    ehdr: ElfHdr,
+}
+
+/// A program which has been opened and checked, with its interpreter.
+pub struct Binprm {
+    filename: String,
+    elf: ElfFile,
+    interp: Option<ElfFile>,
+    args: Vec<String>,
+    envs: Vec<String>,
+}
+
 /// executes a new program.
 pub fn execve(
-    filename: &str, flags: usize, load_bias: usize, args: Vec<String>
+    filename: &str, flags: usize, load_bias: usize, args: Vec<String>, envs: Vec<String>
 ) -> LinuxResult<(usize, usize)> {
     info!("bprm_execve: {}", filename);
-    let file = do_open_execat(filename, flags)?;
-    exec_binprm(file, load_bias, args)
+    let bprm = prepare_binprm(filename, flags, args, envs)?;
+    exec_binprm(bprm, load_bias)
+}
+
+/// Opens the program and its interpreter, and checks their headers and
+/// the size of the arguments. Nothing has been changed yet, so execve
+/// can still fail and return to the old program.
+pub fn prepare_binprm(
+    filename: &str, flags: usize, args: Vec<String>, envs: Vec<String>
+) -> LinuxResult<Binprm> {
+    let elf = open_elf(filename, flags)?;
+
+    let mut interp = None;
+    for phdr in elf.phdrs.iter().filter(|phdr| phdr.p_type == PT_INTERP) {
+        info!(
+            "Interp: phdr: offset: {:#X}=>{:#X} size: {:#X}=>{:#X}",
+            phdr.p_offset, phdr.p_vaddr, phdr.p_filesz, phdr.p_memsz
+        );
+        let path = read_interp_path(&elf.file, phdr)?;
+        info!("PT_INTERP {:?}!", path);
+        interp = Some(open_elf(&path, 0)?);
+    }
+
+    let (size, _) = elf_tables_size(filename, &args, &envs);
+    if size as u64 > task::current().rlimit(RLIMIT_STACK) / 4 {
+        return Err(LinuxError::E2BIG);
+    }
+
+    Ok(Binprm {
+        filename: String::from(filename),
+        elf,
+        interp,
+        args,
+        envs,
+    })
+}
+
+/// Loads the program checked by prepare_binprm into the current mm.
+/// Returns its entry and stack pointer.
+pub fn exec_binprm(bprm: Binprm, load_bias: usize) -> LinuxResult<(usize, usize)> {
+    load_elf_binary(bprm, load_bias)
 }
 
 fn do_open_execat(filename: &str, _flags: usize) -> LinuxResult<FileRef> {
     fileops::do_open(filename, _flags)
 }
 
-fn exec_binprm(file: FileRef, load_bias: usize, args: Vec<String>) -> LinuxResult<(usize, usize)> {
-    load_elf_binary(file, load_bias, args)
+fn open_elf(filename: &str, flags: usize) -> LinuxResult<ElfFile> {
+    let file = do_open_execat(filename, flags)?;
+    let (phdrs, ehdr) = load_elf_phdrs(file.clone())?;
+    Ok(ElfFile { file, phdrs, ehdr })
 }
 
-fn load_elf_interp(
-    file: FileRef,
-    load_bias: usize,
-    app_entry: usize,
-    args: Vec<String>,
-) -> LinuxResult<(usize, usize)> {
-    let (phdrs, entry) = load_elf_phdrs(file.clone())?;
+/// Reads the path of the interpreter in PT_INTERP.
+fn read_interp_path(file: &FileRef, phdr: &ProgramHeader) -> LinuxResult<String> {
+    let mut path: [u8; 256] = [0; 256];
+    let len = phdr.p_filesz as usize;
+    if len < 2 || len > path.len() {
+        return Err(LinuxError::ENOEXEC);
+    }
+    let mut file = file.lock();
+    let _ = file.seek(SeekFrom::Start(phdr.p_offset));
+    file.read(&mut path[..len])?;
+    let path = from_utf8(&path[..len]).map_err(|_| LinuxError::ENOEXEC)?;
+    Ok(String::from(path.trim_matches(char::from(0))))
+}
 
-    let mut elf_bss: usize = 0;
-    let mut elf_brk: usize = 0;
+/// Loads the interpreter into a free range of the address space.
+/// Returns its entry and load bias.
+fn load_elf_interp(interp: ElfFile) -> LinuxResult<(usize, usize)> {
+    let ElfFile { file, phdrs, ehdr } = interp;
+
+    // Reserve the whole range, and then segments are mapped into it.
+    let (start, end) = total_mapping_range(&phdrs);
+    let base = mmap::_mmap(0, end - start, PROT_NONE, MAP_ANONYMOUS, None, 0)?;
+    let load_bias = base - start;
+    info!("interp: load_bias {:#X}", load_bias);
+
+    let (elf_bss, elf_brk) = map_elf_segments(&file, &phdrs, load_bias)?;
+    padzero(elf_bss)?;
+    map_bss(elf_bss, elf_brk)?;
+    Ok((ehdr.entry + load_bias, load_bias))
+}
 
-    info!("interp: args: {:?}", args);
-    info!("There are {} PT_LOAD segments", phdrs.len());
-    for phdr in &phdrs {
-        info!(
-            "phdr: offset: {:#X}=>{:#X} size: {:#X}=>{:#X}",
-            phdr.p_offset, phdr.p_vaddr, phdr.p_filesz, phdr.p_memsz
-        );
+fn load_elf_binary(bprm: Binprm, load_bias: usize) -> LinuxResult<(usize, usize)> {
+    let Binprm { filename, elf, interp, args, envs } = bprm;
+    let ElfFile { file, phdrs, ehdr } = elf;
 
-        let va = align_down_4k(phdr.p_vaddr as usize);
-        let va_end = align_up_4k((phdr.p_vaddr + phdr.p_filesz) as usize);
-        mmap::_mmap(
-            va + load_bias,
-            va_end - va,
-            make_prot(phdr.p_flags),
-            MAP_FIXED,
-            Some(file.clone()),
-            phdr.p_offset as usize,
-        )?;
+    // Position independent executable is loaded at ELF_ET_DYN_BASE.
+    let load_bias = if ehdr.e_type == ET_DYN && load_bias == 0 {
+        align_down_4k(ELF_ET_DYN_BASE)
+    } else {
+        load_bias
+    };
 
-        let pos = (phdr.p_vaddr + phdr.p_filesz) as usize;
-        if elf_bss < pos {
-            elf_bss = pos;
-        }
-        let pos = (phdr.p_vaddr + phdr.p_memsz) as usize;
-        if elf_brk < pos {
-            elf_brk = pos;
-        }
-    }
+    let (elf_bss, elf_brk) = map_elf_segments(&file, &phdrs, load_bias)?;
 
-    let entry = entry + load_bias;
-    elf_bss += load_bias;
-    elf_brk += load_bias;
-
-    let sp = get_arg_page(app_entry, args)?;
+    // Program headers are in the first PT_LOAD segment.
+    let phdr_addr = phdrs
+        .iter()
+        .find(|phdr| phdr.p_type == PT_LOAD)
+        .map_or(0, |phdr| {
+            (phdr.p_vaddr - phdr.p_offset) as usize + ehdr.phoff + load_bias
+        });
+    let entry = ehdr.entry + load_bias;
 
     info!("set brk...");
-    set_brk(elf_bss, elf_brk);
-
-    info!("pad bss...");
-    padzero(elf_bss);
-    Ok((entry, sp))
+    set_brk(elf_bss, elf_brk)?;
+    padzero(elf_bss)?;
+
+    let (start, interp_base) = match interp {
+        Some(interp) => load_elf_interp(interp)?,
+        None => (entry, 0),
+    };
+
+    let auxv: [(usize, usize); NR_AUXV] = [
+        (AT_HWCAP, elf_hwcap()),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_CLKTCK, USER_HZ),
+        (AT_PHDR, phdr_addr),
+        (AT_PHENT, ehdr.phentsize),
+        (AT_PHNUM, ehdr.phnum),
+        (AT_BASE, interp_base),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_SECURE, 0),
+    ];
+    setup_arg_pages()?;
+    #[cfg(target_arch = "riscv64")]
+    setup_sigpage()?;
+    let sp = create_elf_tables(&filename, &args, &envs, &auxv)?;
+    Ok((start, sp))
 }
 
-fn load_elf_binary(
-    file: FileRef, load_bias: usize, mut args: Vec<String>
+/// Maps the file data of PT_LOAD segments at `load_bias`.
+/// Returns the end of the file data and the end of the memory.
+fn map_elf_segments(
+    file: &FileRef, phdrs: &[ProgramHeader], load_bias: usize
 ) -> LinuxResult<(usize, usize)> {
-    let (phdrs, entry) = load_elf_phdrs(file.clone())?;
-
-    for phdr in &phdrs {
-        if phdr.p_type == PT_INTERP {
-            info!(
-                "Interp: phdr: offset: {:#X}=>{:#X} size: {:#X}=>{:#X}",
-                phdr.p_offset, phdr.p_vaddr, phdr.p_filesz, phdr.p_memsz
-            );
-            let mut path: [u8; 256] = [0; 256];
-            let _ = file.lock().seek(SeekFrom::Start(phdr.p_offset as u64));
-            let ret = file.lock().read(&mut path).unwrap();
-            let path = &path[0..phdr.p_filesz as usize];
-            let path = from_utf8(&path).expect("Interpreter path isn't valid UTF-8");
-            let path = path.trim_matches(char::from(0));
-            info!("PT_INTERP ret {} {:?}!", ret, path);
-            // Todo: check elf_ex->e_type == ET_DYN
-            let load_bias = align_down_4k(ELF_ET_DYN_BASE);
-            let file = do_open_execat(path, 0)?;
-            args.insert(0, path.into());
-            return load_elf_interp(file, load_bias, entry, args);
-        }
-    }
-
     let mut elf_bss: usize = 0;
     let mut elf_brk: usize = 0;
 
     info!("There are {} PT_LOAD segments", phdrs.len());
-    for phdr in &phdrs {
+    for phdr in phdrs.iter().filter(|phdr| phdr.p_type == PT_LOAD) {
         info!(
             "phdr: offset: {:#X}=>{:#X} size: {:#X}=>{:#X}",
             phdr.p_offset, phdr.p_vaddr, phdr.p_filesz, phdr.p_memsz
@@ -156,31 +227,30 @@ fn load_elf_binary(
             elf_brk = pos;
         }
     }
+    Ok((elf_bss + load_bias, elf_brk + load_bias))
+}
 
-    let entry = entry + load_bias;
-    elf_bss += load_bias;
-    elf_brk += load_bias;
-
-    let sp = get_arg_page(entry, args)?;
-
-    info!("set brk...");
-    set_brk(elf_bss, elf_brk);
-
-    padzero(elf_bss);
-    Ok((entry, sp))
+/// Range of the address space covered by PT_LOAD segments.
+fn total_mapping_range(phdrs: &[ProgramHeader]) -> (usize, usize) {
+    let loads = phdrs.iter().filter(|phdr| phdr.p_type == PT_LOAD);
+    let start = loads.clone().map(|phdr| phdr.p_vaddr as usize).min().unwrap_or(0);
+    let end = loads.map(|phdr| (phdr.p_vaddr + phdr.p_memsz) as usize).max().unwrap_or(0);
+    (align_down_4k(start), align_up_4k(end))
 }
 
-fn padzero(elf_bss: usize) {
+fn padzero(elf_bss: usize) -> LinuxResult {
     let nbyte = elf_bss & (PAGE_SIZE - 1);
     info!("padzero nbyte: {:#X} ...", elf_bss);
     if nbyte != 0 {
         let nbyte = PAGE_SIZE - nbyte;
-        unsafe { core::slice::from_raw_parts_mut(elf_bss as *mut u8, nbyte) }.fill(0);
+        uaccess::clear_user(elf_bss, nbyte)?;
         info!("padzero nbyte: {:#X} {:#X}", elf_bss, nbyte);
     }
+    Ok(())
 }
 
-fn set_brk(elf_bss: usize, elf_brk: usize) {
+/// Maps anonymous pages for bss beyond the file data.
+fn map_bss(elf_bss: usize, elf_brk: usize) -> LinuxResult {
     let elf_bss = align_up_4k(elf_bss);
     let elf_brk = align_up_4k(elf_brk);
     if elf_bss < elf_brk {
@@ -192,11 +262,15 @@ fn set_brk(elf_bss: usize, elf_brk: usize) {
             MAP_FIXED | MAP_ANONYMOUS,
             None,
             0,
-        )
-        .unwrap();
+        )?;
     }
+    Ok(())
+}
 
-    task::current().mm().lock().set_brk(elf_brk as usize)
+fn set_brk(elf_bss: usize, elf_brk: usize) -> LinuxResult {
+    map_bss(elf_bss, elf_brk)?;
+    task::current().mm().lock().set_brk(align_up_4k(elf_brk));
+    Ok(())
 }
 
 #[inline]
@@ -216,106 +290,201 @@ fn make_prot(pflags: u32) -> usize {
     prot
 }
 
-fn load_elf_phdrs(file: FileRef) -> LinuxResult<(Vec<ProgramHeader>, usize)> {
+/// Fields of the elf header which are needed after loading.
+struct ElfHdr {
+    e_type: u16,
+    entry: usize,
+    phoff: usize,
+    phentsize: usize,
+    phnum: usize,
+}
+
+fn load_elf_phdrs(file: FileRef) -> LinuxResult<(Vec<ProgramHeader>, ElfHdr)> {
     let mut file = file.lock();
     let mut buf: [u8; ELF_HEAD_BUF_SIZE] = [0; ELF_HEAD_BUF_SIZE];
     file.read(&mut buf)?;
 
-    let ehdr = ElfBytes::<AnyEndian>::parse_elf_header(&buf[..]).unwrap();
+    let ehdr = ElfBytes::<AnyEndian>::parse_elf_header(&buf[..]).map_err(|_| LinuxError::ENOEXEC)?;
     info!("e_entry: {:#X}", ehdr.e_entry);
 
     let phnum = ehdr.e_phnum as usize;
     // Validate phentsize before trying to read the table so that we can error early for corrupted files
-    let entsize = ProgramHeader::validate_entsize(ehdr.class, ehdr.e_phentsize as usize).unwrap();
-    let size = entsize.checked_mul(phnum).unwrap();
-    assert!(size > 0 && size <= PAGE_SIZE);
+    let entsize = ProgramHeader::validate_entsize(ehdr.class, ehdr.e_phentsize as usize)
+        .map_err(|_| LinuxError::ENOEXEC)?;
+    let size = entsize * phnum;
+    if size == 0 || size > PAGE_SIZE {
+        return Err(LinuxError::ENOEXEC);
+    }
     let phoff = ehdr.e_phoff;
-    //let mut buf: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
-    let mut buf: [u8; 2 * 1024] = [0; 2 * 1024];
+    let mut buf = vec![0u8; size];
     info!("phoff: {:#X}", ehdr.e_phoff);
     let _ = file.seek(SeekFrom::Start(phoff));
-    file.read(&mut buf)?;
+    if file.read(&mut buf)? != size {
+        return Err(LinuxError::ENOEXEC);
+    }
     let phdrs = SegmentTable::new(ehdr.endianness, ehdr.class, &buf[..]);
 
     let phdrs: Vec<ProgramHeader> = phdrs
         .iter()
         .filter(|phdr| phdr.p_type == PT_LOAD || phdr.p_type == PT_INTERP)
         .collect();
-    Ok((phdrs, ehdr.e_entry as usize))
+    let hdr = ElfHdr {
+        e_type: ehdr.e_type,
+        entry: ehdr.e_entry as usize,
+        phoff: ehdr.e_phoff as usize,
+        phentsize: entsize,
+        phnum,
+    };
+    Ok((phdrs, hdr))
 }
 
-/*
-const AT_PHDR: u8 = 3;
-const AT_PHENT: u8 = 4;
-const AT_PHNUM: u8 = 5;
-const AT_PAGESZ: u8 = 6;
-const AT_ENTRY: u8 = 9;
-const AT_RANDOM: u8 = 25;
-
-pub fn get_auxv_vector(
-    entry: usize
-) -> BTreeMap<u8, usize> {
-    let mut map = BTreeMap::new();
-    map.insert(
-        AT_PHDR,
-        40,
-    );
-    map.insert(AT_PHENT, 38);
-    map.insert(AT_PHNUM, 2);
-    map.insert(AT_ENTRY, entry);
-    map.insert(AT_RANDOM, 0);
-    map.insert(AT_PAGESZ, PAGE_SIZE);
-    map
+// Entries of the auxiliary vector
+const AT_NULL: usize = 0;       /* end of vector */
+const AT_PHDR: usize = 3;       /* program headers for program */
+const AT_PHENT: usize = 4;      /* size of program header entry */
+const AT_PHNUM: usize = 5;      /* number of program headers */
+const AT_PAGESZ: usize = 6;     /* system page size */
+const AT_BASE: usize = 7;       /* base address of interpreter */
+const AT_FLAGS: usize = 8;      /* flags */
+const AT_ENTRY: usize = 9;      /* entry point of program */
+const AT_UID: usize = 11;       /* real uid */
+const AT_EUID: usize = 12;      /* effective uid */
+const AT_GID: usize = 13;       /* real gid */
+const AT_EGID: usize = 14;      /* effective gid */
+const AT_HWCAP: usize = 16;     /* arch dependent hints at CPU capabilities */
+const AT_CLKTCK: usize = 17;    /* frequency at which times() increments */
+const AT_SECURE: usize = 23;    /* secure mode boolean */
+const AT_RANDOM: usize = 25;    /* address of 16 random bytes */
+const AT_EXECFN: usize = 31;    /* filename of program */
+
+const USER_HZ: usize = 100;
+
+// Number of the auxv entries from load_elf_binary, which doesn't count
+// AT_RANDOM, AT_EXECFN and AT_NULL.
+const NR_AUXV: usize = 14;
+
+/// CPU capabilities for user, i.e., one bit for each single-letter
+/// extension of the ISA ('a' is bit 0).
+#[cfg(target_arch = "riscv64")]
+fn elf_hwcap() -> usize {
+    b"imafdc".iter().fold(0, |hwcap, c| hwcap | (1 << (c - b'a')))
 }
-*/
 
-fn get_arg_page(_entry: usize, args: Vec<String>) -> LinuxResult<usize> {
-    //let auxv = get_auxv_vector(entry);
+/// CPU capabilities for user, i.e., edx of cpuid leaf 1.
+#[cfg(target_arch = "x86_64")]
+fn elf_hwcap() -> usize {
+    unsafe { core::arch::x86_64::__cpuid(1).edx as usize }
+}
 
-    let va = TASK_SIZE - STACK_SIZE;
-    mmap::_mmap(va, STACK_SIZE, PROT_READ | PROT_WRITE, MAP_FIXED | MAP_ANONYMOUS, None, 0)?;
-    // Todo: set proper cause for faultin_page.
-    let direct_va = mmap::faultin_page(TASK_SIZE - PAGE_SIZE, 0);
-    let mut stack = UserStack::new(TASK_SIZE, direct_va + PAGE_SIZE);
-    stack.push(&[null::<u64>()]);
+/// Gets random bytes for AT_RANDOM, which seeds the stack protector and
+/// pointer guard of libc.
+/// There's no entropy source yet, so they come from the clock.
+fn get_random_bytes(buf: &mut [u8]) {
+    static SEED: AtomicU64 = AtomicU64::new(0);
+    let mut x = SEED.fetch_add(axhal::time::current_ticks(), Ordering::Relaxed);
+    for chunk in buf.chunks_mut(8) {
+        // splitmix64
+        x = x.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = x;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        chunk.copy_from_slice(&z.to_ne_bytes()[..chunk.len()]);
+    }
+    SEED.fetch_add(x, Ordering::Relaxed);
+}
 
-    let random_str: &[usize; 2] = &[3703830112808742751usize, 7081108068768079778usize];
-    stack.push(random_str.as_slice());
-    //let random_str_pos = stack.get_sp();
+/// Maps the stack, whose pages are populated when they're accessed.
+fn setup_arg_pages() -> LinuxResult {
+    let va = TASK_SIZE - STACK_SIZE;
+    let prot = PROT_READ | PROT_WRITE;
+    let flags = MAP_FIXED | MAP_ANONYMOUS | MAP_GROWSDOWN;
+    mmap::_mmap(va, STACK_SIZE, prot, flags, None, 0)?;
+    Ok(())
+}
 
-    let argv_slice: Vec<_> = args.iter().map(|arg| stack.push_str(arg)).collect();
+/// Returns the size of the initial stack and the number of items in
+/// its table, which are built by create_elf_tables.
+fn elf_tables_size(filename: &str, args: &[String], envs: &[String]) -> (usize, usize) {
+    let ptr_size = size_of::<usize>();
+    let strings: usize = args.iter().chain(envs.iter())
+        .map(|s| s.len() + 1)
+        .sum::<usize>() + filename.len() + 1;
+    // argc, argv and envp with their nulls, auxv with AT_RANDOM,
+    // AT_EXECFN and AT_NULL.
+    let items = 1 + (args.len() + 1) + (envs.len() + 1) + (NR_AUXV + 3) * 2;
+    // The end marker, random bytes and padding for alignment.
+    let size = align_up(ptr_size + strings + 16 + 16 + ptr_size + items * ptr_size, ptr_size);
+    (size, items)
+}
 
-    stack.push(&[null::<u8>(), null::<u8>()]);
-    /*
-    for (key, value) in auxv.iter() {
-        if (*key) == 25 {
-            // AT RANDOM
-            stack.push(&[*key as usize, random_str_pos]);
-        } else {
-            stack.push(&[*key as usize, *value]);
-        }
+/// Maps a read-only page with the sigreturn trampoline, which signal
+/// handlers return to. It takes the place of vdso in Linux.
+#[cfg(target_arch = "riscv64")]
+fn setup_sigpage() -> LinuxResult {
+    extern "C" {
+        fn __user_rt_sigreturn();
     }
-    */
-
-    // Todo: refine the code.
-    // We can study from Linux's code just like:
-    //   'items = (argc + 1) + (envc + 1) + 1;'
-    //   'sp = STACK_ROUND(sp, items);'
-    // And then, store argc, pointers of argv&envs.
-    {
-        if !is_aligned(stack.get_sp(), 16) {
-            stack.push(&[null::<u8>()]);
-        }
+    // The trampoline is two instructions: li a7, __NR_rt_sigreturn; scall.
+    let code = unsafe {
+        core::slice::from_raw_parts(__user_rt_sigreturn as usize as *const u8, 8)
+    };
+    let va = mmap::_mmap(0, PAGE_SIZE, PROT_READ | PROT_WRITE, MAP_ANONYMOUS, None, 0)?;
+    uaccess::copy_to_user(va, code)?;
+    mmap::mprotect(va, PAGE_SIZE, PROT_READ | PROT_EXEC)?;
+    // Make sure the code is seen by instruction fetch.
+    axhal::arch::local_flush_icache_all();
+    task::current().mm().lock().set_sigpage(va);
+    Ok(())
+}
+
+/// Sets up the initial stack of the program, from the top:
+/// filename, envp and argv strings, random bytes, then auxv, envp,
+/// argv and argc. Returns the stack pointer.
+fn create_elf_tables(
+    filename: &str, args: &[String], envs: &[String], auxv: &[(usize, usize)]
+) -> LinuxResult<usize> {
+    let ptr_size = size_of::<usize>();
+    let (size, items) = elf_tables_size(filename, args, envs);
+    assert_eq!(auxv.len(), NR_AUXV);
+
+    // Build the stack in kernel, and then copy it to user.
+    let mut buf = vec![0usize; size / ptr_size];
+    let mut stack = UserStack::new(TASK_SIZE, buf.as_mut_ptr() as usize + size);
+    stack.push(&[null::<u64>()]);
+
+    let execfn = stack.push_str(filename);
+    let mut envp: Vec<usize> = envs.iter().rev().map(|env| stack.push_str(env)).collect();
+    envp.reverse();
+    let mut argv: Vec<usize> = args.iter().rev().map(|arg| stack.push_str(arg)).collect();
+    argv.reverse();
+
+    let mut random = [0u8; 16];
+    get_random_bytes(&mut random);
+    stack.push(random.as_slice());
+    let random_pos = stack.get_sp();
+
+    let pad = stack.get_sp() % 16;
+    stack.push(&[0u8; 16][..pad]);
+    // Stack pointer is aligned to 16 at the end.
+    if items % 2 != 0 {
+        stack.push(&[null::<u8>()]);
     }
 
-    // pointers to envs
-    stack.push(&[null::<u8>()]);
-    stack.push(&[null::<u8>()]);
-    // pointers to argv
-    stack.push(&[null::<u8>()]);
-    stack.push(argv_slice.as_slice());
-    // argc
-    stack.push(&[args.len()]);
+    let mut table = Vec::with_capacity(items);
+    table.push(args.len());
+    table.extend_from_slice(&argv);
+    table.push(0);
+    table.extend_from_slice(&envp);
+    table.push(0);
+    for (key, value) in auxv {
+        table.extend_from_slice(&[*key, *value]);
+    }
+    table.extend_from_slice(&[AT_RANDOM, random_pos]);
+    table.extend_from_slice(&[AT_EXECFN, execfn]);
+    table.extend_from_slice(&[AT_NULL, 0]);
+    assert_eq!(table.len(), items);
+    stack.push(table.as_slice());
 
     let sp = stack.get_sp();
 
@@ -324,6 +493,10 @@ fn get_arg_page(_entry: usize, args: Vec<String>) -> LinuxResult<usize> {
     // When we call these, X86_64 requires that memory-alignment aligned to 16-bytes.
     // Or mmu causes #GP.
     assert!(is_aligned(sp, 16));
+
+    let used = TASK_SIZE - sp;
+    let bytes = unsafe { core::slice::from_raw_parts(buf.as_ptr() as *const u8, size) };
+    uaccess::copy_to_user(sp, &bytes[size - used..])?;
     Ok(sp)
 }
 
@@ -6,10 +6,12 @@ extern crate alloc;
 use alloc::vec;
 use alloc::string::String;
 
-use axerrno::LinuxResult;
+use axerrno::{LinuxResult, LinuxError, linux_err_from};
 use axhal::arch::start_thread;
 use mmap::{MAP_ANONYMOUS, MAP_FIXED, PROT_READ};
-use axtype::{PAGE_SIZE, get_user_str_vec};
+use axtype::PAGE_SIZE;
+use uaccess::get_user_str_vec;
+use task::SIGSEGV;
 
 pub fn kernel_execve(filename: &str) -> LinuxResult {
     info!("kernel_execve... {}", filename);
@@ -18,7 +20,8 @@ pub fn kernel_execve(filename: &str) -> LinuxResult {
     let _ = setup_zero_page();
 
     let args = vec![filename.into()];
-    let (entry, sp) = bprm_loader::execve(filename, 0, 0, args)?;
+    let envs = vec!["HOME=/".into(), "TERM=linux".into()];
+    let (entry, sp) = bprm_loader::execve(filename, 0, 0, args, envs)?;
 
     info!("start thread...");
     start_thread(task::current().pt_regs_addr(), entry, sp);
@@ -35,29 +38,69 @@ fn setup_zero_page() -> LinuxResult {
 pub fn execve(path: &str, argv: usize, envp: usize) -> usize {
     info!("execve: {}", path);
 
-    let mut args = get_user_str_vec(argv);
-    assert!(args.len() > 0);
-    args[0] = String::from(path);
+    let mut args = match get_user_str_vec(argv) {
+        Ok(args) => args,
+        Err(e) => return linux_err_from!(e),
+    };
+    // argv may be empty, but programs expect argv[0].
+    if args.is_empty() {
+        args.push(String::from(path));
+    } else {
+        args[0] = String::from(path);
+    }
     for arg in &args {
         info!("arg: {}", arg);
     }
-    let envp = get_user_str_vec(envp);
+    let envp = match get_user_str_vec(envp) {
+        Ok(envp) => envp,
+        Err(e) => return linux_err_from!(e),
+    };
     for env in &envp {
         info!("env: {}", env);
     }
-    assert_eq!(envp.len(), 0);
 
+    // Bad programs are found out while we can still return the error.
+    let bprm = match bprm_loader::prepare_binprm(path, 0, args, envp) {
+        Ok(bprm) => bprm,
+        Err(e) => return linux_err_from!(e),
+    };
+
+    // Point of no return.
+    if let Err(e) = sys::de_thread() {
+        return linux_err_from!(e);
+    }
+    sys::exec_mm_release();
     task::alloc_mm();
+    let task = task::current();
+    signal::flush_signal_handlers(&task);
+    do_close_on_exec();
 
     // TODO: Move it into kernel_init().
     let _ = setup_zero_page();
-    let (entry, sp) = bprm_loader::execve(path, 0, 0, args).expect("exec error!");
+    let (entry, sp) = match bprm_loader::exec_binprm(bprm, 0) {
+        Ok(ret) => ret,
+        Err(e) => {
+            // The old program is gone, so nothing can be returned to.
+            warn!("execve {} fails after the point of no return: {:?}", path, e);
+            sys::do_group_exit(SIGSEGV as u32);
+        }
+    };
 
     info!("start thread...");
     start_thread(task::current().pt_regs_addr(), entry, sp);
     0
 }
 
+/// Closes the fds with close-on-exec. The fd table is unshared first,
+/// so others sharing it are not affected.
+fn do_close_on_exec() {
+    task::unshare_files();
+    let task = task::current();
+    let files = task.filetable.lock().close_on_exec();
+    // Close the files out of the lock.
+    drop(files);
+}
+
 pub fn init(cpu_id: usize, dtb_pa: usize) {
     axconfig::init_once!();
 
@@ -0,0 +1,51 @@
+//! Console device, which backs stdin, stdout and stderr of init.
+
+use alloc::sync::Arc;
+use axfs_vfs::{VfsNodeAttr, VfsNodeOps, VfsNodePerm, VfsNodeType, VfsResult};
+use axerrno::AxError;
+use axfile::fops::{File, OpenOptions};
+use mutex::Mutex;
+use crate::FileRef;
+
+struct ConsoleNode;
+
+impl VfsNodeOps for ConsoleNode {
+    fn get_attr(&self) -> VfsResult<VfsNodeAttr> {
+        let perm = VfsNodePerm::from_bits_truncate(0o620);
+        Ok(VfsNodeAttr::new(perm, VfsNodeType::CharDevice, 0, 0))
+    }
+
+    /// Returns what has arrived, up to the end of the line. If nothing
+    /// has, returns WouldBlock and the caller waits for input.
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> VfsResult<usize> {
+        let mut len = 0;
+        while len < buf.len() {
+            match axhal::console::getchar() {
+                Some(c) => {
+                    let c = if c == b'\r' { b'\n' } else { c };
+                    buf[len] = c;
+                    len += 1;
+                    if c == b'\n' {
+                        break;
+                    }
+                }
+                None if len > 0 => break,
+                None => return Err(AxError::WouldBlock),
+            }
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> VfsResult<usize> {
+        axhal::console::write_bytes(buf);
+        Ok(buf.len())
+    }
+}
+
+/// Opens the console for both reading and writing.
+pub fn open() -> FileRef {
+    let mut opts = OpenOptions::new();
+    opts.read(true);
+    opts.write(true);
+    Arc::new(Mutex::new(File::new(Arc::new(ConsoleNode), (&opts).into())))
+}
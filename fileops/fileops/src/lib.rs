@@ -9,16 +9,21 @@ use alloc::sync::Arc;
 use alloc::vec;
 
 mod proc_ops;
+pub use proc_ops::proc_create;
+mod console;
 
 use axerrno::AxResult;
 use axerrno::{LinuxError, LinuxResult, linux_err, linux_err_from};
+use axerrno::AxError;
 use axerrno::AxError::NotFound;
 use axfile::api::create_dir;
 use axfile::fops::File;
 use axfile::fops::OpenOptions;
 use mutex::Mutex;
-use axtype::get_user_str;
+use uaccess::{get_user_str, get_user, put_user, copy_from_user, copy_to_user, access_ok, Pod};
+use axtype::PAGE_SIZE;
 use axio::SeekFrom;
+use task::RLIMIT_NOFILE;
 
 pub type FileRef = Arc<Mutex<File>>;
 
@@ -31,7 +36,28 @@ const SEEK_SET: usize = 0;
 const SEEK_CUR: usize = 1;
 const SEEK_END: usize = 2;
 
+const O_ACCMODE: usize = 0o3;
+const O_RDONLY: usize = 0o0;
+const O_WRONLY: usize = 0o1;
+const O_RDWR: usize = 0o2;
 const O_CREAT: usize = 0o100;
+const O_APPEND: usize = 0o2000;
+const O_NONBLOCK: usize = 0o4000;
+const O_CLOEXEC: usize = 0o2000000;
+
+// fcntl commands
+const F_DUPFD: usize = 0;
+const F_GETFD: usize = 1;
+const F_SETFD: usize = 2;
+const F_GETFL: usize = 3;
+const F_SETFL: usize = 4;
+const F_DUPFD_CLOEXEC: usize = 1030;
+
+const FD_CLOEXEC: usize = 1;
+
+/// Max size of the kernel buffer between user memory and a file,
+/// so a huge count from user doesn't turn into a huge allocation.
+const RW_CHUNK: usize = PAGE_SIZE;
 
 pub fn openat(dfd: usize, filename: &str, flags: usize, mode: usize) -> AxResult<File> {
     info!(
@@ -39,8 +65,20 @@ pub fn openat(dfd: usize, filename: &str, flags: usize, mode: usize) -> AxResult
         filename, dfd, flags, mode
     );
 
+    // Only the access mode decides whether the file can be read or
+    // written, e.g., read from an O_WRONLY file fails with EBADF.
+    // O_WRONLY and O_RDWR files have to be writable, as files under
+    // /proc/sys are written to.
     let mut opts = OpenOptions::new();
-    opts.read(true);
+    match flags & O_ACCMODE {
+        O_RDONLY => opts.read(true),
+        O_WRONLY => opts.write(true),
+        O_RDWR => {
+            opts.read(true);
+            opts.write(true);
+        }
+        _ => (),
+    }
     if (flags & O_CREAT) != 0 {
         opts.write(true);
         opts.create(true);
@@ -52,6 +90,9 @@ pub fn openat(dfd: usize, filename: &str, flags: usize, mode: usize) -> AxResult
 
     let path = handle_path(dfd, filename);
     info!("openat path {}", path);
+    if let Some(node) = proc_ops::lookup(&path) {
+        return Ok(File::new(node, (&opts).into()));
+    }
     File::open(&path, &opts, &fs).or_else(|e| {
         if e == NotFound {
             // Handle special filesystem, e.g., procfs, sysfs ..
@@ -69,22 +110,177 @@ pub fn special_open(path: &str, opts: &OpenOptions) -> AxResult<File> {
     Err(NotFound)
 }
 
-pub fn register_file(file: AxResult<File>) -> usize {
-    let file = match file {
+pub fn register_file(file: AxResult<File>, flags: usize) -> usize {
+    let mut file = match file {
         Ok(f) => f,
         Err(e) => {
             return linux_err_from!(e);
         }
     };
-    let current = task::current();
-    let fd = current.filetable.lock().insert(Arc::new(Mutex::new(file)));
+    file.set_append((flags & O_APPEND) != 0);
+    file.set_nonblock((flags & O_NONBLOCK) != 0);
+    let fd = match alloc_fd(0, Arc::new(Mutex::new(file)), flags) {
+        Ok(fd) => fd,
+        Err(e) => return linux_err_from!(e),
+    };
     info!("openat fd {}", fd);
     fd
 }
 
-pub fn unregister_file(fd: usize) {
+/// Installs the file at the lowest free fd not less than `start`.
+/// Only O_CLOEXEC of `flags` matters.
+fn alloc_fd(start: usize, file: FileRef, flags: usize) -> LinuxResult<usize> {
     let current = task::current();
-    current.filetable.lock().remove(fd);
+    let nofile = current.rlimit(RLIMIT_NOFILE) as usize;
+    let mut filetable = current.filetable.lock();
+    let fd = filetable.alloc_fd(start, file, nofile).ok_or(LinuxError::EMFILE)?;
+    filetable.set_cloexec(fd, (flags & O_CLOEXEC) != 0);
+    Ok(fd)
+}
+
+pub fn unregister_file(fd: usize) -> usize {
+    let current = task::current();
+    let file = current.filetable.lock().remove(fd);
+    match file {
+        Some(_) => 0,
+        None => linux_err!(EBADF),
+    }
+}
+
+pub fn dup(oldfd: usize) -> usize {
+    info!("dup: oldfd {}", oldfd);
+    let file = match fdget(oldfd) {
+        Ok(f) => f,
+        Err(e) => return linux_err_from!(e),
+    };
+    match alloc_fd(0, file, 0) {
+        Ok(fd) => fd,
+        Err(e) => linux_err_from!(e),
+    }
+}
+
+pub fn dup3(oldfd: usize, newfd: usize, flags: usize) -> usize {
+    info!("dup3: oldfd {} newfd {} flags {:#X}", oldfd, newfd, flags);
+    if (flags & !O_CLOEXEC) != 0 || oldfd == newfd {
+        return linux_err!(EINVAL);
+    }
+    match do_dup2(oldfd, newfd, flags) {
+        Ok(fd) => fd,
+        Err(e) => linux_err_from!(e),
+    }
+}
+
+pub fn dup2(oldfd: usize, newfd: usize) -> usize {
+    info!("dup2: oldfd {} newfd {}", oldfd, newfd);
+    if oldfd == newfd {
+        // Just check whether oldfd is valid.
+        return match fdget(oldfd) {
+            Ok(_) => newfd,
+            Err(e) => linux_err_from!(e),
+        };
+    }
+    match do_dup2(oldfd, newfd, 0) {
+        Ok(fd) => fd,
+        Err(e) => linux_err_from!(e),
+    }
+}
+
+/// Makes newfd refer to the file of oldfd, and closes the file
+/// which newfd referred to before.
+fn do_dup2(oldfd: usize, newfd: usize, flags: usize) -> LinuxResult<usize> {
+    let current = task::current();
+    if newfd >= current.rlimit(RLIMIT_NOFILE) as usize {
+        return Err(LinuxError::EBADF);
+    }
+    let old = {
+        let mut filetable = current.filetable.lock();
+        let file = filetable.get_file(oldfd).ok_or(LinuxError::EBADF)?;
+        let old = filetable.install(newfd, file);
+        filetable.set_cloexec(newfd, (flags & O_CLOEXEC) != 0);
+        old
+    };
+    // The replaced file may be released here, outside the lock.
+    drop(old);
+    Ok(newfd)
+}
+
+pub fn fcntl(fd: usize, cmd: usize, arg: usize) -> usize {
+    info!("fcntl: fd {} cmd {} arg {:#X}", fd, cmd, arg);
+    match do_fcntl(fd, cmd, arg) {
+        Ok(ret) => ret,
+        Err(e) => linux_err_from!(e),
+    }
+}
+
+fn do_fcntl(fd: usize, cmd: usize, arg: usize) -> LinuxResult<usize> {
+    let file = fdget(fd)?;
+    match cmd {
+        F_DUPFD | F_DUPFD_CLOEXEC => {
+            if arg >= task::current().rlimit(RLIMIT_NOFILE) as usize {
+                return Err(LinuxError::EINVAL);
+            }
+            let flags = if cmd == F_DUPFD_CLOEXEC { O_CLOEXEC } else { 0 };
+            alloc_fd(arg, file, flags)
+        }
+        F_GETFD => {
+            let cloexec = task::current().filetable.lock().get_cloexec(fd);
+            Ok(if cloexec { FD_CLOEXEC } else { 0 })
+        }
+        F_SETFD => {
+            let current = task::current();
+            current.filetable.lock().set_cloexec(fd, (arg & FD_CLOEXEC) != 0);
+            Ok(0)
+        }
+        F_GETFL => {
+            let file = file.lock();
+            let mut flags = match (file.readable(), file.writable()) {
+                (true, true) => O_RDWR,
+                (false, true) => O_WRONLY,
+                _ => O_RDONLY,
+            };
+            if file.is_append() {
+                flags |= O_APPEND;
+            }
+            if file.is_nonblock() {
+                flags |= O_NONBLOCK;
+            }
+            Ok(flags)
+        }
+        F_SETFL => {
+            // Only O_APPEND and O_NONBLOCK can be changed,
+            // access mode and creation flags are ignored.
+            let mut file = file.lock();
+            file.set_append((arg & O_APPEND) != 0);
+            file.set_nonblock((arg & O_NONBLOCK) != 0);
+            Ok(0)
+        }
+        _ => Err(LinuxError::EINVAL),
+    }
+}
+
+/// Opens the console as stdin, stdout and stderr of the current task,
+/// which are inherited by all the tasks forked from it.
+///
+/// Each of them is a file of its own: a read of the console waits for
+/// input with the file locked, which mustn't hold up stdout and stderr.
+pub fn console_on_rootfs() {
+    let current = task::current();
+    let mut filetable = current.filetable.lock();
+    for fd in 0..3 {
+        assert_eq!(filetable.insert(console::open()), fd);
+    }
+}
+
+fn fdget(fd: usize) -> LinuxResult<FileRef> {
+    let current = task::current();
+    let file = current.filetable.lock().get_file(fd);
+    file.ok_or(LinuxError::EBADF)
+}
+
+fn is_char_device(file: &FileRef) -> bool {
+    file.lock()
+        .get_attr()
+        .map_or(false, |attr| attr.file_type().is_char_device())
 }
 
 fn handle_path(dfd: usize, filename: &str) -> String {
@@ -104,82 +300,151 @@ fn handle_path(dfd: usize, filename: &str) -> String {
     String::from(filename)
 }
 
-pub fn read(fd: usize, ubuf: &mut [u8]) -> usize {
-    let count = ubuf.len();
-    let current = task::current();
-    let file = current.filetable.lock().get_file(fd).unwrap();
+pub fn read(fd: usize, ubuf: usize, count: usize) -> usize {
+    let file = match fdget(fd) {
+        Ok(f) => f,
+        Err(e) => return linux_err_from!(e),
+    };
+    if !access_ok(ubuf, count) {
+        return linux_err!(EFAULT);
+    }
+    // A device returns what it has, don't wait for the rest.
+    let partial = is_char_device(&file);
 
-    let mut kbuf = vec![0u8; count];
+    let mut kbuf = vec![0u8; count.min(RW_CHUNK)];
     let mut pos = 0;
     while pos < count {
-        let ret = file.lock().read(&mut kbuf[pos..]).unwrap();
-        if ret == 0 {
+        let len = (count - pos).min(RW_CHUNK);
+        let ret = do_read(&file, &mut kbuf[..len]);
+        let ret = ret.and_then(|ret| copy_to_user(ubuf + pos, &kbuf[..ret]).map(|_| ret));
+        let ret = match ret {
+            Ok(ret) => ret,
+            // Report what has been read before the error.
+            Err(_) if pos > 0 => break,
+            Err(e) => return linux_err_from!(e),
+        };
+        pos += ret;
+        if ret == 0 || partial {
             break;
         }
-        pos += ret;
     }
 
     info!(
         "linux_syscall_read: fd {}, count {}, ret {}",
         fd, count, pos
     );
-
-    ubuf.copy_from_slice(&kbuf);
     pos
 }
 
-pub fn pread64(fd: usize, ubuf: &mut [u8], offset: usize) -> usize {
-    info!("pread64: fd {} len {} offset {}", fd, ubuf.len(), offset);
+/// Reads the file into `buf`. A device without data returns WouldBlock,
+/// then wait for it here unless O_NONBLOCK is set. The file isn't locked
+/// while waiting, so the others sharing it can go on.
+fn do_read(file: &FileRef, buf: &mut [u8]) -> LinuxResult<usize> {
+    loop {
+        let mut locked = file.lock();
+        match locked.read(buf) {
+            Err(AxError::WouldBlock) if !locked.is_nonblock() => (),
+            ret => return ret.map_err(LinuxError::from),
+        }
+        drop(locked);
+        if task::current().signal_pending() {
+            return Err(LinuxError::from(AxError::Interrupted));
+        }
+        task::yield_now();
+    }
+}
+
+pub fn pread64(fd: usize, ubuf: usize, count: usize, offset: usize) -> usize {
+    info!("pread64: fd {} len {} offset {}", fd, count, offset);
     let pos = lseek(fd, offset, SEEK_SET);
     assert_eq!(pos, offset);
-    read(fd, ubuf)
+    read(fd, ubuf, count)
 }
 
-pub fn write(fd: usize, ubuf: &[u8]) -> usize {
-    if fd == 1 || fd == 2 {
-        return write_to_stdio(ubuf);
-    }
-
-    let count = ubuf.len();
-    let current = task::current();
-    let file = current.filetable.lock().get_file(fd).unwrap();
-
-    let mut kbuf = vec![0u8; count];
-    kbuf.copy_from_slice(ubuf);
+pub fn write(fd: usize, ubuf: usize, count: usize) -> usize {
+    let file = match fdget(fd) {
+        Ok(f) => f,
+        Err(e) => return linux_err_from!(e),
+    };
+    let pos = match write_from_user(&file, ubuf, count) {
+        Ok(pos) => pos,
+        Err(e) => return linux_err_from!(e),
+    };
+    info!("write: fd {}, count {}, ret {}", fd, count, pos);
+    pos
+}
 
+/// Writes `count` bytes at user address `ubuf` to the file, one chunk
+/// each time. An error after some bytes are written ends it short.
+fn write_from_user(file: &FileRef, ubuf: usize, count: usize) -> LinuxResult<usize> {
+    if !access_ok(ubuf, count) {
+        return Err(LinuxError::EFAULT);
+    }
+    let mut kbuf = vec![0u8; count.min(RW_CHUNK)];
     let mut pos = 0;
     while pos < count {
-        let ret = file.lock().write(&kbuf[pos..]).unwrap();
+        let len = (count - pos).min(RW_CHUNK);
+        let ret = copy_from_user(&mut kbuf[..len], ubuf + pos)
+            .and_then(|_| do_write(file, &kbuf[..len]));
+        match ret {
+            Ok(ret) => {
+                pos += ret;
+                if ret < len {
+                    break;
+                }
+            }
+            Err(_) if pos > 0 => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(pos)
+}
+
+fn do_write(file: &FileRef, kbuf: &[u8]) -> LinuxResult<usize> {
+    let mut pos = 0;
+    while pos < kbuf.len() {
+        let ret = file.lock().write(&kbuf[pos..])?;
         if ret == 0 {
             break;
         }
         pos += ret;
     }
-    info!("write: fd {}, count {}, ret {}", fd, count, pos);
-    pos
+    Ok(pos)
 }
 
-fn write_to_stdio(ubuf: &[u8]) -> usize {
-    axhal::console::write_bytes(ubuf);
-    ubuf.len()
-}
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct iovec {
     iov_base: usize,
     iov_len: usize,
 }
 
-pub fn writev(fd: usize, iov_array: &[iovec]) -> usize {
-    assert!(fd == 1 || fd == 2);
-    for iov in iov_array {
+unsafe impl Pod for iovec {}
+
+pub fn writev(fd: usize, iov_array: usize, iovcnt: usize) -> usize {
+    let file = match fdget(fd) {
+        Ok(f) => f,
+        Err(e) => return linux_err_from!(e),
+    };
+    let mut total = 0;
+    for i in 0..iovcnt {
+        let iov: iovec = match get_user(iov_array + i * core::mem::size_of::<iovec>()) {
+            Ok(iov) => iov,
+            Err(e) => return linux_err_from!(e),
+        };
         debug!("iov: {:#X} {:#X}", iov.iov_base, iov.iov_len);
-        let bytes = unsafe { core::slice::from_raw_parts(iov.iov_base as *const _, iov.iov_len) };
-        let s = String::from_utf8(bytes.into());
-        error!("{}", s.unwrap());
+        match write_from_user(&file, iov.iov_base, iov.iov_len) {
+            Ok(len) => {
+                total += len;
+                if len < iov.iov_len {
+                    break;
+                }
+            }
+            Err(_) if total > 0 => break,
+            Err(e) => return linux_err_from!(e),
+        }
     }
-    iov_array.len()
+    total
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -205,17 +470,15 @@ pub struct KernelStat {
     pub st_ctime_nsec: isize,
 }
 
-pub fn fstatat(dfd: usize, path: usize, statbuf_ptr: usize, flags: usize) -> usize {
-    let statbuf = statbuf_ptr as *mut KernelStat;
-
-    if dfd == 1 {
-        return fstatat_stdio(dfd, path, statbuf, flags);
-    }
-    assert!(dfd > 2);
+unsafe impl Pod for KernelStat {}
 
+pub fn fstatat(dfd: usize, path: usize, statbuf: usize, flags: usize) -> usize {
     info!("fstatat dfd {:#x} flags {:#x}", dfd, flags);
     let metadata = if (flags & AT_EMPTY_PATH) == 0 {
-        let path = get_user_str(path);
+        let path = match get_user_str(path) {
+            Ok(path) => path,
+            Err(e) => return linux_err_from!(e),
+        };
         warn!("!!! NON-EMPTY for path: {}\n", path);
         match openat(dfd, &path, flags, 0) {
             Ok(file) => file.get_attr().unwrap(),
@@ -224,16 +487,15 @@ pub fn fstatat(dfd: usize, path: usize, statbuf_ptr: usize, flags: usize) -> usi
             }
         }
     } else {
-        let current = task::current();
-        let filetable = current.filetable.lock();
-        let file = match filetable.get_file(dfd) {
-            Some(f) => f,
-            None => {
-                return (-2isize) as usize;
-            }
+        let file = match fdget(dfd) {
+            Ok(f) => f,
+            Err(e) => return linux_err_from!(e),
         };
         let locked_file = file.lock();
-        locked_file.get_attr().unwrap()
+        match locked_file.get_attr() {
+            Ok(attr) => attr,
+            Err(e) => return linux_err_from!(e),
+        }
     };
 
     let ty = metadata.file_type() as u8;
@@ -242,40 +504,21 @@ pub fn fstatat(dfd: usize, path: usize, statbuf_ptr: usize, flags: usize) -> usi
     let st_size = metadata.size();
     warn!("st_size: {}", st_size);
 
-    unsafe {
-        *statbuf = KernelStat {
-            st_ino: 1,
-            st_nlink: 1,
-            st_mode,
-            st_uid: 1000,
-            st_gid: 1000,
-            st_size: st_size,
-            st_blocks: metadata.blocks() as _,
-            st_blksize: 512,
-            ..Default::default()
-        };
-    }
-    0
-}
-
-fn fstatat_stdio(_dfd: usize, _path: usize, statbuf: *mut KernelStat, _flags: usize) -> usize {
-    // Todo: Handle stdin(0), stdout(1) and stderr(2)
-    unsafe {
-        *statbuf = KernelStat {
-            st_mode: 0x2180,
-            st_nlink: 1,
-            st_blksize: 0x1000,
-            st_ino: 0x2a,
-            st_dev: 2,
-            st_rdev: 0x500001,
-            st_size: 0,
-            st_blocks: 0,
-            //st_uid: 1000,
-            //st_gid: 1000,
-            ..Default::default()
-        };
+    let kstat = KernelStat {
+        st_ino: 1,
+        st_nlink: 1,
+        st_mode,
+        st_uid: 1000,
+        st_gid: 1000,
+        st_size: st_size,
+        st_blocks: metadata.blocks() as _,
+        st_blksize: 512,
+        ..Default::default()
+    };
+    match put_user(kstat, statbuf) {
+        Ok(()) => 0,
+        Err(e) => linux_err_from!(e),
     }
-    return 0;
 }
 
 // IOCTL
@@ -294,32 +537,40 @@ struct Termios {
     c_cc: [u8; NCCS], /* control characters */
 }
 
+unsafe impl Pod for Termios {}
+
 pub fn ioctl(fd: usize, request: usize, udata: usize) -> usize {
     info!(
         "linux_syscall_ioctl fd {}, request {:#X}, udata {:#X}",
         fd, request, udata
     );
 
-    assert!(fd == 1 || fd == 2);
-    assert_eq!(request, TCGETS);
+    let file = match fdget(fd) {
+        Ok(f) => f,
+        Err(e) => return linux_err_from!(e),
+    };
+    // Only the console is a terminal.
+    if !is_char_device(&file) || request != TCGETS {
+        return linux_err!(ENOTTY);
+    }
 
     let cc: [u8; NCCS] = [
         0x3, 0x1c, 0x7f, 0x15, 0x4, 0x0, 0x1, 0x0, 0x11, 0x13, 0x1a, 0x0, 0x12, 0xf, 0x17, 0x16,
         0x0, 0x0, 0x0,
     ];
 
-    let ubuf = udata as *mut Termios;
-    unsafe {
-        *ubuf = Termios {
-            c_iflag: 0x500,
-            c_oflag: 0x5,
-            c_cflag: 0xcbd,
-            c_lflag: 0x8a3b,
-            c_line: 0,
-            c_cc: cc,
-        };
+    let termios = Termios {
+        c_iflag: 0x500,
+        c_oflag: 0x5,
+        c_cflag: 0xcbd,
+        c_lflag: 0x8a3b,
+        c_line: 0,
+        c_cc: cc,
+    };
+    match put_user(termios, udata) {
+        Ok(()) => 0,
+        Err(e) => linux_err_from!(e),
     }
-    0
 }
 
 pub fn mkdirat(dfd: usize, pathname: &str, mode: usize) -> usize {
@@ -337,14 +588,18 @@ pub fn mkdirat(dfd: usize, pathname: &str, mode: usize) -> usize {
     }
 }
 
-pub fn getcwd(buf: &mut [u8]) -> usize {
-    let cwd = _getcwd();
+pub fn getcwd(buf: usize, size: usize) -> usize {
+    let mut cwd = _getcwd();
     info!("getcwd {}", cwd);
+    cwd.push('\0');
     let bytes = cwd.as_bytes();
-    let count = bytes.len();
-    buf[0..count].copy_from_slice(bytes);
-    buf[count] = 0u8;
-    count + 1
+    if bytes.len() > size {
+        return linux_err!(ERANGE);
+    }
+    match copy_to_user(buf, bytes) {
+        Ok(()) => bytes.len(),
+        Err(e) => linux_err_from!(e),
+    }
 }
 
 fn _getcwd() -> String {
@@ -366,8 +621,13 @@ pub fn chdir(path: &str) -> usize {
 pub fn lseek(fd: usize, offset: usize, whence: usize) -> usize {
     info!("lseek: fd: {} offset: {} whence: {}", fd, offset, whence);
 
-    let current = task::current();
-    let file = current.filetable.lock().get_file(fd).unwrap();
+    let file = match fdget(fd) {
+        Ok(f) => f,
+        Err(e) => return linux_err_from!(e),
+    };
+    if is_char_device(&file) {
+        return linux_err!(ESPIPE);
+    }
 
     let pos = match whence {
         SEEK_SET => file.lock().seek(SeekFrom::Start(offset as u64)),
@@ -386,8 +646,10 @@ pub fn lseek(fd: usize, offset: usize, whence: usize) -> usize {
 pub fn ftruncate(fd: usize, length: usize) -> usize {
     info!("ftruncate: fd: {} length: {}", fd, length);
 
-    let current = task::current();
-    let file = current.filetable.lock().get_file(fd).unwrap();
+    let file = match fdget(fd) {
+        Ok(f) => f,
+        Err(e) => return linux_err_from!(e),
+    };
     file.lock().truncate(length as u64).unwrap_or_else(|e| {
         panic!("ftruncate err: {:?}", e);
     });
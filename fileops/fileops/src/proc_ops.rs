@@ -3,7 +3,8 @@
 use alloc::sync::Arc;
 use alloc::string::String;
 use alloc::format;
-use axfs_vfs::VfsNodeOps;
+use alloc::collections::BTreeMap;
+use axfs_vfs::{VfsNodeOps, VfsNodeRef};
 use axfs_vfs::VfsNodeAttr;
 use axfs_vfs::VfsNodePerm;
 use axfs_vfs::VfsResult;
@@ -11,6 +12,20 @@ use axfs_vfs::VfsNodeType;
 use axerrno::AxResult;
 use axfile::fops::File;
 use crate::OpenOptions;
+use spinpreempt::SpinLock;
+
+/// Entries registered by other components, e.g., knobs under /proc/sys.
+static PROC_ENTRIES: SpinLock<BTreeMap<String, VfsNodeRef>> = SpinLock::new(BTreeMap::new());
+
+/// Registers `node` as the proc entry at `path`.
+/// It takes precedence over the same path in procfs.
+pub fn proc_create(path: &str, node: VfsNodeRef) {
+    PROC_ENTRIES.lock().insert(String::from(path), node);
+}
+
+pub(crate) fn lookup(path: &str) -> Option<VfsNodeRef> {
+    PROC_ENTRIES.lock().get(path).cloned()
+}
 
 struct ProcNode {
     path: String,
@@ -9,43 +9,105 @@ use spinpreempt::SpinLock;
 
 pub struct FileTable {
     table: SlotVec<FileTableEntry>,
+    /// Number of tasks sharing this table.
+    pub users: usize,
 }
 
 impl FileTable {
     pub const fn new() -> Self {
         Self {
             table: SlotVec::new(),
+            users: 1,
         }
     }
 
     pub fn get_file(&self, fd: usize) -> Option<Arc<Mutex<File>>> {
         self.table
-            .get(fd-3)
+            .get(fd)
             .map(|entry| entry.file.clone())
     }
 
+    /// Installs the file at the lowest free fd, just as POSIX requires.
     pub fn insert(&mut self, item: Arc<Mutex<File>>) -> usize {
         let entry = FileTableEntry::new(item);
-        self.table.put(entry) + 3
+        self.table.put(entry)
     }
 
-    pub fn remove(&mut self, fd: usize) {
-        self.table.remove(fd-3);
+    /// Installs the file at the lowest free fd not less than `start`.
+    /// Returns None if there's no free fd below `nofile`.
+    pub fn alloc_fd(&mut self, start: usize, item: Arc<Mutex<File>>, nofile: usize) -> Option<usize> {
+        let fd = self.table.first_free(start);
+        if fd >= nofile {
+            return None;
+        }
+        self.table.put_at(fd, FileTableEntry::new(item));
+        Some(fd)
+    }
+
+    /// Installs the file at the fd, and returns the file which was there.
+    /// The new fd is always clear of close-on-exec.
+    pub fn install(&mut self, fd: usize, item: Arc<Mutex<File>>) -> Option<Arc<Mutex<File>>> {
+        self.table
+            .put_at(fd, FileTableEntry::new(item))
+            .map(|entry| entry.file)
+    }
+
+    /// Closes the fd. Returns the file, or None if the fd isn't open.
+    pub fn remove(&mut self, fd: usize) -> Option<Arc<Mutex<File>>> {
+        self.table.remove(fd).map(|entry| entry.file)
+    }
+
+    /// Whether the fd is closed on execve.
+    pub fn get_cloexec(&self, fd: usize) -> bool {
+        self.table
+            .get(fd)
+            .map_or(false, |entry| entry.cloexec)
+    }
+
+    pub fn set_cloexec(&mut self, fd: usize, cloexec: bool) {
+        if let Some(entry) = self.table.get_mut(fd) {
+            entry.cloexec = cloexec;
+        }
+    }
+
+    /// Closes the fds with close-on-exec for execve.
+    /// Returns the files, which should be dropped out of the lock.
+    pub fn close_on_exec(&mut self) -> Vec<Arc<Mutex<File>>> {
+        let fds: Vec<usize> = (0..self.table.slots.len())
+            .filter(|&fd| self.get_cloexec(fd))
+            .collect();
+        fds.into_iter()
+            .filter_map(|fd| self.remove(fd))
+            .collect()
+    }
+
+    /// Duplicates the table for fork. The open files are shared
+    /// between the two tables.
+    pub fn dup(&self) -> Self {
+        Self {
+            table: self.table.clone(),
+            users: 1,
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct FileTableEntry {
     file: Arc<Mutex<File>>,
+    /// Close-on-exec flag of this fd (FD_CLOEXEC).
+    cloexec: bool,
 }
 
 impl FileTableEntry {
     pub fn new(file: Arc<Mutex<File>>) -> Self {
         Self {
             file,
+            cloexec: false,
         }
     }
 }
 
+#[derive(Clone)]
 pub struct SlotVec<T> {
     // The slots to store items.
     slots: Vec<Option<T>>,
@@ -68,6 +130,12 @@ impl<T> SlotVec<T> {
         }
         self.slots[idx].as_ref()
     }
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut T> {
+        if idx >= self.slots.len() {
+            return None;
+        }
+        self.slots[idx].as_mut()
+    }
     /// Put an item into the vector.
     /// It may be put into any existing empty slots or the back of the vector.
     ///
@@ -85,6 +153,27 @@ impl<T> SlotVec<T> {
         idx
     }
 
+    /// Return the index of the first empty slot at or after `start`.
+    pub fn first_free(&self, start: usize) -> usize {
+        (start..self.slots.len())
+            .find(|&idx| self.slots[idx].is_none())
+            .unwrap_or(core::cmp::max(start, self.slots.len()))
+    }
+
+    /// Put an item at position `idx`, the vector grows if necessary.
+    ///
+    /// Return the item replaced by it.
+    pub fn put_at(&mut self, idx: usize, entry: T) -> Option<T> {
+        if idx >= self.slots.len() {
+            self.slots.resize_with(idx + 1, || None);
+        }
+        let old = self.slots[idx].replace(entry);
+        if old.is_none() {
+            self.num_occupied += 1;
+        }
+        old
+    }
+
     /// Remove and return the item at position `idx`.
     ///
     /// Return `None` if `idx` is out of bounds or the item has been removed.
@@ -0,0 +1,44 @@
+use filetable::SlotVec;
+
+#[test]
+fn test_first_free() {
+    let mut v = SlotVec::new();
+    assert_eq!(v.first_free(0), 0);
+    assert_eq!(v.first_free(5), 5);
+
+    assert_eq!(v.put(10), 0);
+    assert_eq!(v.put(11), 1);
+    assert_eq!(v.put(12), 2);
+    assert_eq!(v.first_free(0), 3);
+    assert_eq!(v.first_free(1), 3);
+    assert_eq!(v.first_free(7), 7);
+
+    assert_eq!(v.remove(1), Some(11));
+    assert_eq!(v.first_free(0), 1);
+    assert_eq!(v.first_free(2), 3);
+}
+
+#[test]
+fn test_put_at() {
+    let mut v = SlotVec::new();
+    assert_eq!(v.put_at(3, 30), None);
+    assert_eq!(v.get(3), Some(&30));
+    assert_eq!(v.get(0), None);
+    assert_eq!(v.get(4), None);
+
+    // Holes below the item are reused by put.
+    assert_eq!(v.first_free(0), 0);
+    assert_eq!(v.put(0), 0);
+    assert_eq!(v.put(1), 1);
+    assert_eq!(v.put(2), 2);
+    assert_eq!(v.put(4), 4);
+
+    // Replacing returns the old item and keeps the count.
+    assert_eq!(v.put_at(3, 33), Some(30));
+    assert_eq!(v.get(3), Some(&33));
+    assert_eq!(v.put(5), 5);
+
+    assert_eq!(v.remove(3), Some(33));
+    assert_eq!(v.remove(3), None);
+    assert_eq!(v.put(6), 3);
+}
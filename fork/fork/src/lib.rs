@@ -8,14 +8,16 @@ extern crate alloc;
 use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::sync::Arc;
+use core::sync::atomic::Ordering;
 
 use axerrno::{LinuxError, LinuxResult};
 use task::{current, Tid, TaskRef, TaskStruct};
 use spinbase::SpinNoIrq;
-use task::SIGCHLD;
+use task::{SIGCHLD, SigAltStack};
 use taskctx::SchedInfo;
 use taskctx::TaskStack;
 use axtype::align_up_4k;
+use uaccess::put_user;
 use taskctx::THREAD_SIZE;
 
 bitflags::bitflags! {
@@ -105,8 +107,8 @@ impl KernelCloneArgs {
 
         let tid = task.tid();
         if self.flags.contains(CloneFlags::CLONE_PARENT_SETTID) {
-            let ptid_ptr = self.parent_tid as *mut usize;
-            unsafe { (*ptid_ptr) = tid; }
+            // Todo: fail the clone with EFAULT before the task is created.
+            let _ = put_user(tid as i32, self.parent_tid);
         }
 
         self.wake_up_new_task(task.clone());
@@ -137,11 +139,18 @@ impl KernelCloneArgs {
         };
 
         let mut task = current().dup_task_struct();
+        // A new thread which shares the mm can't share the altstack.
+        if self.flags.contains(CloneFlags::CLONE_VM) &&
+            !self.flags.contains(CloneFlags::CLONE_VFORK) {
+            *task.sas_stack.lock() = SigAltStack::default();
+        }
 
-        //copy_files();
-        self.copy_fs(&mut task)?;
+        // Checks that may fail go first, so that no shared users count
+        // has been taken when we bail out.
+        self.copy_signal(&mut task)?;
         self.copy_sighand(&mut task)?;
-        //copy_signal();
+        self.copy_fs(&mut task)?;
+        self.copy_files(&mut task)?;
         self.copy_mm(&mut task)?;
         self.copy_thread(&mut task, tid)?;
 
@@ -155,6 +164,25 @@ impl KernelCloneArgs {
         Ok(arc_task)
     }
 
+    fn copy_signal(&self, task: &mut TaskStruct) -> LinuxResult {
+        if self.flags.contains(CloneFlags::CLONE_THREAD) {
+            // The group is being killed, so don't add a thread to it.
+            if task::current().group_exit.lock().exiting {
+                return Err(LinuxError::EINTR);
+            }
+            task.group_exit = task::current().group_exit.clone();
+            task.rlimits = task::current().rlimits.clone();
+            task.pgid = task::current().pgid.clone();
+            task.jobctl = task::current().jobctl.clone();
+            task.child_subreaper = task::current().child_subreaper.clone();
+            task.wait_chldexit = task::current().wait_chldexit.clone();
+        } else {
+            task.rlimits.lock().rlim = task::current().rlimits.lock().rlim;
+            task.pgid.store(task::current().pgid(), Ordering::Release);
+        }
+        Ok(())
+    }
+
     fn copy_sighand(&self, task: &mut TaskStruct) -> LinuxResult {
         if self.flags.contains(CloneFlags::CLONE_SIGHAND) {
             task.sighand = task::current().sighand.clone();
@@ -188,13 +216,14 @@ impl KernelCloneArgs {
         let exit_signal: i32;
         if self.flags.contains(CloneFlags::CLONE_PARENT) ||
             self.flags.contains(CloneFlags::CLONE_THREAD) {
-            real_parent = current_ctx.real_parent.clone();
+            real_parent = current_ctx.real_parent.lock().clone();
             if self.flags.contains(CloneFlags::CLONE_THREAD) {
                 exit_signal = -1;
             } else {
-                // Todo: add exit_signal in taskctx
-                //exit_signal = current_ctx.group_leader.exit_signal;
-                exit_signal = SIGCHLD as i32;
+                exit_signal = match &current_ctx.group_leader {
+                    Some(leader) => leader.exit_signal,
+                    None => current_ctx.exit_signal,
+                };
             }
         } else {
             real_parent = Some(current_ctx.as_ctx_ref().clone());
@@ -208,7 +237,6 @@ impl KernelCloneArgs {
             group_leader.clone().unwrap().siblings.lock().push(tid);
         }
 
-        // Todo: in exit_mm_release && exec_mm_release, handle clear_child_tid.
         /*
          * This _must_ happen before we call free_task(), i.e. before we jump
          * to any of the bad_fork_* labels. This is to avoid freeing
@@ -232,8 +260,6 @@ impl KernelCloneArgs {
                 0
             };
 
-        warn!("Todo: handle exit_signal {}", exit_signal);
-
         let mut sched_info = SchedInfo::new();
         //sched_info.init(self.entry, task_entry as usize, 0.into());
         /////////////////////
@@ -242,7 +268,8 @@ impl KernelCloneArgs {
         /////////////////////
         sched_info.init_tid(tid);
         sched_info.init_tgid(tgid);
-        sched_info.real_parent = real_parent;
+        sched_info.real_parent = SpinNoIrq::new(real_parent);
+        sched_info.exit_signal = exit_signal;
         sched_info.group_leader = group_leader;
         sched_info.set_child_tid = set_child_tid;
         sched_info.clear_child_tid = clear_child_tid;
@@ -263,14 +290,26 @@ impl KernelCloneArgs {
         if self.flags.contains(CloneFlags::CLONE_VM) {
             info!("copy_mm: CLONE_VM");
             task.mm = current().mm.clone();
+            task.mm().lock().mmget();
         } else {
             info!("copy_mm: NO CLONE_VM");
-            let mm = current().mm().lock().deep_dup();
+            let mm = current().mm().lock().dup_cow();
             task.mm = Some(Arc::new(SpinNoIrq::new(mm)));
         }
         Ok(())
     }
 
+    fn copy_files(&self, task: &mut TaskStruct) -> LinuxResult {
+        if self.flags.contains(CloneFlags::CLONE_FILES) {
+            info!("copy_files: CLONE_FILES");
+            task.filetable = task::current().filetable.clone();
+            task.filetable.lock().users += 1;
+            return Ok(());
+        }
+        *task.filetable.lock() = task::current().filetable.lock().dup();
+        Ok(())
+    }
+
     fn copy_fs(&self, task: &mut TaskStruct) -> LinuxResult {
         if self.flags.contains(CloneFlags::CLONE_FS) {
             /* task.fs is already what we want */
@@ -298,8 +337,7 @@ pub extern "C" fn task_entry() -> ! {
 
     let task = crate::current();
     if task.sched_info.set_child_tid != 0 {
-        let ctid_ptr = task.sched_info.set_child_tid as *mut usize;
-        unsafe { (*ctid_ptr) = task.sched_info.tid(); }
+        let _ = put_user(task.sched_info.tid() as i32, task.sched_info.set_child_tid);
     }
 
     if let Some(entry) = task.sched_info.entry {
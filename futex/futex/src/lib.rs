@@ -0,0 +1,464 @@
+//! Fast user-space locking.
+//!
+//! Waiters are queued by futex key. The key is (mm, address) for private
+//! futexes and for futexes in private mappings, and the kernel address of
+//! the physical page plus offset for futexes in shared mappings, so that
+//! tasks of different processes which share the page meet on the same key.
+
+#![no_std]
+
+#[macro_use]
+extern crate log;
+extern crate alloc;
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use axerrno::{linux_err, linux_err_from, LinuxError, LinuxResult};
+use axhal::time::{current_time, TimeValue, NANOS_PER_SEC};
+use axtype::align_down_4k;
+use mm::VM_SHARED;
+use spinpreempt::SpinLock;
+use uaccess::{futex_atomic_cmpxchg, get_user, put_user, Pod};
+use uaccess::{fault_in_readable, get_user_nofault};
+use spinpreempt::SpinLockGuard;
+use wait_queue::WaitQueue;
+
+/// futex operations
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+pub const FUTEX_REQUEUE: usize = 3;
+pub const FUTEX_CMP_REQUEUE: usize = 4;
+pub const FUTEX_WAIT_BITSET: usize = 9;
+pub const FUTEX_WAKE_BITSET: usize = 10;
+
+pub const FUTEX_PRIVATE_FLAG: usize = 128;
+pub const FUTEX_CLOCK_REALTIME: usize = 256;
+const FUTEX_CMD_MASK: usize = !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME);
+
+/// bitset with all bits set for the FUTEX_xxx_BITSET OPs to request a
+/// match of any bit.
+pub const FUTEX_BITSET_MATCH_ANY: u32 = 0xffffffff;
+
+/// Are there any waiters for this robust futex.
+pub const FUTEX_WAITERS: u32 = 0x80000000;
+/// The kernel signals via this bit that a thread holding a futex
+/// has exited without unlocking the futex.
+pub const FUTEX_OWNER_DIED: u32 = 0x40000000;
+/// The rest of the robust-futex field is for the TID.
+pub const FUTEX_TID_MASK: u32 = 0x3fffffff;
+
+/// Upper limit of robust list entries, which avoids endless loop
+/// on a circular list.
+const ROBUST_LIST_LIMIT: usize = 2048;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum FutexKey {
+    /// (mm id, user address)
+    Private(usize, usize),
+    /// Kernel address of the futex word in a shared page
+    Shared(usize),
+}
+
+struct FutexQ {
+    bitset: u32,
+    /// Set under FUTEX_QUEUES lock when the waiter is taken off the queue.
+    woken: AtomicBool,
+    wq: WaitQueue,
+}
+
+impl FutexQ {
+    fn new(bitset: u32) -> Self {
+        Self {
+            bitset,
+            woken: AtomicBool::new(false),
+            wq: WaitQueue::new(),
+        }
+    }
+
+    fn woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+
+    fn wake(&self) {
+        self.woken.store(true, Ordering::Release);
+        self.wq.notify_one(false);
+    }
+}
+
+static FUTEX_QUEUES: SpinLock<FutexQueues> = SpinLock::new(BTreeMap::new());
+
+/// Per-thread list head of robust futexes in user space.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RobustListHead {
+    /// The head of the list, which points back to itself if empty.
+    next: usize,
+    /// Relative offset from a list entry to the futex word.
+    futex_offset: isize,
+    /// The entry which is being acquired or released by the thread.
+    list_op_pending: usize,
+}
+
+unsafe impl Pod for RobustListHead {}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Timespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+unsafe impl Pod for Timespec {}
+
+type FutexQueues = BTreeMap<FutexKey, VecDeque<Arc<FutexQ>>>;
+
+/// Locks FUTEX_QUEUES and reads the futex word at `uaddr`.
+/// A fault can't be handled with the spin lock held, so the lock is
+/// dropped to fault in the page, and then we try again.
+fn lock_and_get_value(uaddr: usize) -> LinuxResult<(SpinLockGuard<'static, FutexQueues>, u32)> {
+    loop {
+        let queues = FUTEX_QUEUES.lock();
+        match get_user_nofault::<u32>(uaddr) {
+            Ok(uval) => return Ok((queues, uval)),
+            Err(_) => {
+                drop(queues);
+                fault_in_readable(uaddr, core::mem::size_of::<u32>())?;
+            }
+        }
+    }
+}
+
+/// Gets the key of the futex word at `uaddr`.
+/// It reads the word at first to fault in the page.
+fn get_futex_key(uaddr: usize, fshared: bool) -> LinuxResult<FutexKey> {
+    if uaddr % core::mem::size_of::<u32>() != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let _: u32 = get_user(uaddr)?;
+
+    let mm = task::current().mm();
+    let mm = mm.lock();
+    if fshared {
+        let vma = mm.find_vma(uaddr).ok_or(LinuxError::EFAULT)?;
+        if (vma.vm_flags & VM_SHARED) != 0 {
+            let va = align_down_4k(uaddr);
+            if let Some(dva) = mm.mapped.get(&va) {
+                return Ok(FutexKey::Shared(dva + (uaddr - va)));
+            }
+        }
+    }
+    Ok(FutexKey::Private(mm.id(), uaddr))
+}
+
+fn futex_deadline(cmd: usize, timeout: usize) -> LinuxResult<Option<TimeValue>> {
+    if timeout == 0 {
+        return Ok(None);
+    }
+    let ts: Timespec = get_user(timeout)?;
+    if ts.tv_sec < 0 || ts.tv_nsec < 0 || ts.tv_nsec >= NANOS_PER_SEC as i64 {
+        return Err(LinuxError::EINVAL);
+    }
+    let dur = Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32);
+    // FUTEX_WAIT takes a relative timeout, and FUTEX_WAIT_BITSET
+    // takes an absolute one.
+    if cmd == FUTEX_WAIT {
+        Ok(Some(current_time() + dur))
+    } else {
+        Ok(Some(dur))
+    }
+}
+
+/// Removes the waiter from its queue.
+/// Returns false if it has been taken off by a waker.
+fn unqueue(q: &Arc<FutexQ>) -> bool {
+    let mut queues = FUTEX_QUEUES.lock();
+    if q.woken() {
+        return false;
+    }
+    let mut found = None;
+    for (key, queue) in queues.iter_mut() {
+        if let Some(index) = queue.iter().position(|x| Arc::ptr_eq(x, q)) {
+            queue.remove(index);
+            found = Some((*key, queue.is_empty()));
+            break;
+        }
+    }
+    if let Some((key, true)) = found {
+        queues.remove(&key);
+    }
+    found.is_some()
+}
+
+fn futex_wait(
+    uaddr: usize, fshared: bool, val: u32, deadline: Option<TimeValue>, bitset: u32
+) -> LinuxResult<usize> {
+    if bitset == 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let key = get_futex_key(uaddr, fshared)?;
+    let q = Arc::new(FutexQ::new(bitset));
+    {
+        // Compare the value and queue under the lock,
+        // so that we cannot miss a wakeup from futex_wake.
+        let (mut queues, uval) = lock_and_get_value(uaddr)?;
+        if uval != val {
+            return Err(LinuxError::EAGAIN);
+        }
+        queues.entry(key).or_default().push_back(q.clone());
+    }
+
+    let task = task::current();
+    let timed_out = || deadline.is_some_and(|deadline| current_time() >= deadline);
+    // No timer wakeup, but the waiter is put back to run queue when
+    // it blocks, so it checks the deadline each time it's scheduled.
+    q.wq.wait_until(|| q.woken() || timed_out() || task.signal_pending());
+
+    if q.woken() || !unqueue(&q) {
+        return Ok(0);
+    }
+    if timed_out() {
+        Err(LinuxError::ETIMEDOUT)
+    } else if deadline.is_some() {
+        // Restarting would wait for the whole timeout again.
+        Err(LinuxError::EINTR)
+    } else {
+        Err(LinuxError::ERESTARTSYS)
+    }
+}
+
+fn futex_wake(uaddr: usize, fshared: bool, nr_wake: usize, bitset: u32) -> LinuxResult<usize> {
+    if bitset == 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let key = get_futex_key(uaddr, fshared)?;
+    let mut queues = FUTEX_QUEUES.lock();
+    let queue = match queues.get_mut(&key) {
+        Some(queue) => queue,
+        None => return Ok(0),
+    };
+
+    let mut woken = 0;
+    queue.retain(|q| {
+        if woken < nr_wake && (q.bitset & bitset) != 0 {
+            q.wake();
+            woken += 1;
+            false
+        } else {
+            true
+        }
+    });
+    if queue.is_empty() {
+        queues.remove(&key);
+    }
+    Ok(woken)
+}
+
+/// Wakes up `nr_wake` waiters on `uaddr`, and moves at most `nr_requeue`
+/// of the remaining ones to `uaddr2`. For FUTEX_CMP_REQUEUE, the word at
+/// `uaddr` is checked against `cmpval` at first.
+fn futex_requeue(
+    uaddr: usize, fshared: bool, uaddr2: usize,
+    nr_wake: usize, nr_requeue: usize, cmpval: Option<u32>
+) -> LinuxResult<usize> {
+    let key1 = get_futex_key(uaddr, fshared)?;
+    let key2 = get_futex_key(uaddr2, fshared)?;
+
+    let mut queues = match cmpval {
+        Some(cmpval) => {
+            let (queues, uval) = lock_and_get_value(uaddr)?;
+            if uval != cmpval {
+                return Err(LinuxError::EAGAIN);
+            }
+            queues
+        },
+        None => FUTEX_QUEUES.lock(),
+    };
+
+    let mut queue = queues.remove(&key1).unwrap_or_default();
+    let mut count = 0;
+    while count < nr_wake {
+        match queue.pop_front() {
+            Some(q) => q.wake(),
+            None => break,
+        }
+        count += 1;
+    }
+    let mut moved = VecDeque::new();
+    while moved.len() < nr_requeue {
+        match queue.pop_front() {
+            Some(q) => moved.push_back(q),
+            None => break,
+        }
+    }
+    count += moved.len();
+
+    if !queue.is_empty() {
+        queues.insert(key1, queue);
+    }
+    if !moved.is_empty() {
+        queues.entry(key2).or_default().append(&mut moved);
+    }
+    Ok(count)
+}
+
+pub fn do_futex(
+    uaddr: usize, op: usize, val: usize, timeout: usize, uaddr2: usize, val3: usize
+) -> LinuxResult<usize> {
+    let cmd = op & FUTEX_CMD_MASK;
+    let fshared = (op & FUTEX_PRIVATE_FLAG) == 0;
+    info!("futex: uaddr {:#X} op {:#X} val {:#X} timeout {:#X} uaddr2 {:#X} val3 {:#X}",
+          uaddr, op, val, timeout, uaddr2, val3);
+
+    // No realtime clock yet.
+    if (op & FUTEX_CLOCK_REALTIME) != 0 {
+        return Err(LinuxError::ENOSYS);
+    }
+
+    match cmd {
+        FUTEX_WAIT | FUTEX_WAIT_BITSET => {
+            let bitset = if cmd == FUTEX_WAIT {
+                FUTEX_BITSET_MATCH_ANY
+            } else {
+                val3 as u32
+            };
+            let deadline = futex_deadline(cmd, timeout)?;
+            futex_wait(uaddr, fshared, val as u32, deadline, bitset)
+        },
+        FUTEX_WAKE => futex_wake(uaddr, fshared, val, FUTEX_BITSET_MATCH_ANY),
+        FUTEX_WAKE_BITSET => futex_wake(uaddr, fshared, val, val3 as u32),
+        FUTEX_REQUEUE | FUTEX_CMP_REQUEUE => {
+            // 'timeout' is nr_requeue for requeue.
+            if (val as i32) < 0 || (timeout as i32) < 0 {
+                return Err(LinuxError::EINVAL);
+            }
+            let cmpval = (cmd == FUTEX_CMP_REQUEUE).then_some(val3 as u32);
+            futex_requeue(uaddr, fshared, uaddr2, val, timeout, cmpval)
+        },
+        _ => {
+            warn!("futex: unsupported op {:#X}", op);
+            Err(LinuxError::ENOSYS)
+        }
+    }
+}
+
+pub fn futex(
+    uaddr: usize, op: usize, val: usize, timeout: usize, uaddr2: usize, val3: usize
+) -> usize {
+    match do_futex(uaddr, op, val, timeout, uaddr2, val3) {
+        Ok(ret) => ret,
+        Err(e) => linux_err_from!(e),
+    }
+}
+
+/// Wakes up a waiter on `uaddr`, e.g., the joiner of an exiting thread.
+pub fn wake(uaddr: usize) -> LinuxResult<usize> {
+    futex_wake(uaddr, true, 1, FUTEX_BITSET_MATCH_ANY)
+}
+
+pub fn set_robust_list(head: usize, len: usize) -> usize {
+    info!("set_robust_list: head {:#X} len {}", head, len);
+    if len != core::mem::size_of::<RobustListHead>() {
+        return linux_err!(EINVAL);
+    }
+    let mut ctx = taskctx::current_ctx();
+    ctx.as_ctx_mut().robust_list = head;
+    0
+}
+
+pub fn get_robust_list(tid: usize, head_ptr: usize, len_ptr: usize) -> usize {
+    let head = if tid == 0 {
+        taskctx::current_ctx().robust_list
+    } else {
+        match task::get_task(tid) {
+            Some(task) => task.sched_info.robust_list,
+            None => return linux_err!(ESRCH),
+        }
+    };
+    let len = core::mem::size_of::<RobustListHead>();
+    if let Err(e) = put_user(len, len_ptr).and_then(|_| put_user(head, head_ptr)) {
+        return linux_err_from!(e);
+    }
+    0
+}
+
+/// Marks the robust futex at `uaddr` as FUTEX_OWNER_DIED if it's held by
+/// the exiting thread, and wakes up a waiter.
+/// For the pending one, the lock may have been released but not woken up.
+fn handle_futex_death(uaddr: usize, tid: usize, pi: bool, pending: bool) -> LinuxResult {
+    let mut uval: u32 = get_user(uaddr)?;
+    if pending && !pi && uval == 0 {
+        futex_wake(uaddr, true, 1, FUTEX_BITSET_MATCH_ANY)?;
+        return Ok(());
+    }
+    loop {
+        if (uval & FUTEX_TID_MASK) as usize != tid {
+            return Ok(());
+        }
+        let mval = (uval & FUTEX_WAITERS) | FUTEX_OWNER_DIED;
+        let cur = futex_atomic_cmpxchg(uaddr, uval, mval)?;
+        if cur == uval {
+            break;
+        }
+        uval = cur;
+    }
+    // Wake a waiter, it will see FUTEX_OWNER_DIED and recover the lock.
+    // Todo: PI futexes aren't supported yet.
+    if !pi && (uval & FUTEX_WAITERS) != 0 {
+        futex_wake(uaddr, true, 1, FUTEX_BITSET_MATCH_ANY)?;
+    }
+    Ok(())
+}
+
+/// The lowest bit of a list entry marks a PI futex.
+#[inline]
+fn robust_entry(entry: usize) -> (usize, bool) {
+    (entry & !1, (entry & 1) != 0)
+}
+
+/// Fetches the next entry of the list.
+fn fetch_robust_entry(uaddr: usize) -> LinuxResult<(usize, bool)> {
+    let entry: usize = get_user(uaddr)?;
+    Ok(robust_entry(entry))
+}
+
+/// Walks the robust list of the exiting thread, and releases the futexes
+/// which are still held by it. The list is in user space, so be careful.
+pub fn exit_robust_list() {
+    let ctx = taskctx::current_ctx();
+    let head = ctx.robust_list;
+    if head == 0 {
+        return;
+    }
+    if let Err(e) = do_exit_robust_list(head, ctx.tid()) {
+        warn!("exit_robust_list: tid {} head {:#X} err {:?}", ctx.tid(), head, e);
+    }
+}
+
+fn do_exit_robust_list(head: usize, tid: usize) -> LinuxResult {
+    let robust: RobustListHead = get_user(head)?;
+    let (mut entry, mut pi) = robust_entry(robust.next);
+    let (pending, pending_pi) = robust_entry(robust.list_op_pending);
+    let futex_word = |entry: usize| entry.wrapping_add_signed(robust.futex_offset);
+
+    let mut limit = ROBUST_LIST_LIMIT;
+    while entry != head {
+        // Fetch the next entry before the futex is released, because the
+        // owner of the lock may free the entry then.
+        let next = fetch_robust_entry(entry);
+        if entry != pending {
+            let _ = handle_futex_death(futex_word(entry), tid, pi, false);
+        }
+        (entry, pi) = next?;
+        limit -= 1;
+        if limit == 0 {
+            warn!("robust list of tid {} is too long", tid);
+            break;
+        }
+    }
+    if pending != 0 {
+        handle_futex_death(futex_word(pending), tid, pending_pi, true)?;
+    }
+    Ok(())
+}
@@ -7,18 +7,25 @@ extern crate alloc;
 
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::cell::OnceCell;
+use core::ops::Bound;
 use axfile::fops::File;
 use page_table::paging::pgd_alloc;
 use page_table::paging::MappingFlags;
 use page_table::paging::PageTable;
 use page_table::paging::PagingResult;
+use page_table::paging::PageSize;
+use page_table::PagingError;
+use axerrno::{LinuxError, LinuxResult};
 use axhal::mem::virt_to_phys;
 use axtype::PAGE_SIZE;
 use core::sync::atomic::AtomicUsize;
 use core::sync::atomic::Ordering;
 use spinbase::SpinNoIrq;
 use mutex::Mutex;
+use axhal::arch::flush_tlb;
+use axalloc::{virt_to_page, get_page, put_page, page_count, PG_SHARED};
 
 pub type FileRef = Arc<Mutex<File>>;
 
@@ -33,6 +40,41 @@ pub const VM_READ: usize =   0x00000001;
 pub const VM_WRITE: usize =  0x00000002;
 pub const VM_EXEC: usize =   0x00000004;
 pub const VM_SHARED: usize = 0x00000008;
+/* general info on the segment */
+pub const VM_GROWSDOWN: usize = 0x00000100;
+pub const VM_LOCKED: usize =    0x00002000;
+
+/// Translates `vm_flags` of a vma into flags of user PTEs.
+///
+/// Write implies read, because riscv has no write-only pages.
+/// Returns empty flags for PROT_NONE, and such pages must not be mapped.
+pub fn vm_get_page_prot(vm_flags: usize) -> MappingFlags {
+    let mut flags = MappingFlags::empty();
+    if (vm_flags & (VM_READ | VM_WRITE)) != 0 {
+        flags |= MappingFlags::READ;
+    }
+    if (vm_flags & VM_WRITE) != 0 {
+        flags |= MappingFlags::WRITE;
+    }
+    if (vm_flags & VM_EXEC) != 0 {
+        flags |= MappingFlags::EXECUTE;
+    }
+    if !flags.is_empty() {
+        flags |= MappingFlags::USER;
+    }
+    flags
+}
+
+/// PTE flags for page `dva` in a vma with `vm_flags`.
+/// Private pages shared after fork are write-protected for COW.
+fn page_prot(dva: usize, vm_flags: usize) -> MappingFlags {
+    let mut flags = vm_get_page_prot(vm_flags);
+    let page = virt_to_page(dva);
+    if !page.test_flags(PG_SHARED) && page.count() > 1 {
+        flags.remove(MappingFlags::WRITE);
+    }
+    flags
+}
 
 #[derive(Clone)]
 pub struct VmAreaStruct {
@@ -71,11 +113,19 @@ pub struct MmStruct {
     pgd: Arc<SpinNoIrq<PageTable>>,
     brk: usize,
 
-    // Todo: temprarily record mapped (va, pa)
+    /// User pages mapped in this mm (va -> kernel va of the page).
+    /// Each entry holds a reference to the page.
     pub mapped: BTreeMap<usize, usize>,
 
     /// Pages that have PG_mlocked set
     pub locked_vm: usize,
+
+    /// User address of the page holding the sigreturn trampoline,
+    /// or 0 if there's none.
+    sigpage: usize,
+
+    /// Number of tasks using this mm (e.g., threads and vfork child).
+    users: usize,
 }
 
 impl MmStruct {
@@ -86,13 +136,20 @@ impl MmStruct {
             pgd: Arc::new(SpinNoIrq::new(pgd_alloc())),
             brk: 0,
 
-            // Todo: temprarily record mapped (va, pa)
             mapped: BTreeMap::new(),
             locked_vm: 0,
+            sigpage: 0,
+
+            users: 1,
         }
     }
 
-    pub fn deep_dup(&self) -> Self {
+    /// Duplicates the address space for fork.
+    ///
+    /// Private pages are not copied here. They are shared between parent
+    /// and child and mapped read-only in both, and the first write fault
+    /// on either side gets its own copy (see [`MmStruct::break_cow`]).
+    pub fn dup_cow(&self) -> Self {
         let mut pgd = pgd_alloc();
 
         let mut vmas = BTreeMap::new();
@@ -103,28 +160,29 @@ impl MmStruct {
         }
 
         let mut mapped = BTreeMap::<usize, usize>::new();
+        let mut parent_pgd = self.pgd.lock();
         for (va, dva) in &self.mapped {
             let va = *va;
-            let old_page = *dva;
-            debug!("mapped: {:#X} -> {:#X}", va, old_page);
-            let new_page: usize = axalloc::global_allocator()
-                .alloc_pages(1, PAGE_SIZE) .unwrap();
-
-            unsafe {
-                core::ptr::copy_nonoverlapping(
-                    old_page as *const u8,
-                    new_page as *mut u8,
-                    PAGE_SIZE
-                );
-            }
-
-            let pa = virt_to_phys(new_page.into());
+            let page = *dva;
+            let vm_flags = self.find_vma(va).map_or(VM_NONE, |vma| vma.vm_flags);
+            get_page(page);
+            virt_to_page(page).inc_mapcount();
+            mapped.insert(va, page);
 
-            let flags = MappingFlags::READ | MappingFlags::WRITE |
-                MappingFlags::EXECUTE | MappingFlags::USER;
-            pgd.map_region(va.into(), pa.into(), PAGE_SIZE, flags, true).unwrap();
-            mapped.insert(va, new_page);
+            // Now page is shared by parent and child. Private pages lose
+            // write permission in both, so that the first write breaks COW.
+            let flags = page_prot(page, vm_flags);
+            if flags.is_empty() {
+                continue;
+            }
+            debug!("mapped: {:#X} -> {:#X} {:?}", va, page, flags);
+            let _ = parent_pgd.update(va.into(), None, Some(flags));
+            let pa = virt_to_phys(page.into());
+            pgd.map_region(va.into(), pa, PAGE_SIZE, flags, true).unwrap();
         }
+        drop(parent_pgd);
+        flush_tlb(None);
+
         Self {
             id: MM_UNIQUE_ID.fetch_add(1, Ordering::SeqCst),
             vmas,
@@ -133,6 +191,193 @@ impl MmStruct {
 
             mapped,
             locked_vm: self.locked_vm,
+            sigpage: self.sigpage,
+
+            users: 1,
+        }
+    }
+
+    /// Handles a write fault on a copy-on-write page at `va`.
+    ///
+    /// If we are the last owner or the page is shared (MAP_SHARED),
+    /// just take the page back by restoring the write permission,
+    /// unless the PTE is already writable.
+    /// Otherwise copy it into a new private page.
+    /// Returns the kernel virtual address of the writable page,
+    /// or ENOMEM if no page is left for the copy.
+    pub fn break_cow(&mut self, va: usize, vm_flags: usize) -> LinuxResult<usize> {
+        let old_page = *self.mapped.get(&va).unwrap();
+        let flags = vm_get_page_prot(vm_flags);
+
+        if virt_to_page(old_page).test_flags(PG_SHARED) || page_count(old_page) == 1 {
+            let mut pgd = self.pgd.lock();
+            if let Ok((_, old_flags, _)) = pgd.query(va.into()) {
+                if old_flags.contains(MappingFlags::WRITE) {
+                    return Ok(old_page);
+                }
+            }
+            debug!("break_cow: reuse {:#X} -> {:#X}", va, old_page);
+            pgd.update(va.into(), None, Some(flags)).unwrap();
+            flush_tlb(Some(va.into()));
+            return Ok(old_page);
+        }
+
+        let new_page: usize = axalloc::global_allocator()
+            .alloc_pages(1, PAGE_SIZE)
+            .map_err(|_| LinuxError::ENOMEM)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                old_page as *const u8,
+                new_page as *mut u8,
+                PAGE_SIZE
+            );
+        }
+        debug!("break_cow: copy {:#X} -> {:#X}", va, new_page);
+
+        let pa = virt_to_phys(new_page.into());
+        self.pgd.lock().update(va.into(), Some(pa), Some(flags)).unwrap();
+        flush_tlb(Some(va.into()));
+        self.mapped.insert(va, new_page);
+        virt_to_page(new_page).inc_mapcount();
+        virt_to_page(old_page).dec_mapcount();
+        put_page(old_page);
+        Ok(new_page)
+    }
+
+    /// Maps the page at kernel virtual address `dva` at user address `va`,
+    /// and records it in `mapped`. The caller passes its own reference
+    /// of the page to this mm.
+    pub fn map_page(&mut self, va: usize, dva: usize, vm_flags: usize) -> PagingResult {
+        if let Some(old) = self.mapped.remove(&va) {
+            warn!("map_page: replace {:#X} at {:#X}", old, va);
+            let _ = self.unmap_region(va, PAGE_SIZE);
+            virt_to_page(old).dec_mapcount();
+            put_page(old);
+        }
+        let flags = page_prot(dva, vm_flags);
+        if !flags.is_empty() {
+            let pa = virt_to_phys(dva.into());
+            self.pgd.lock().map_region(va.into(), pa, PAGE_SIZE, flags, true)?;
+        }
+        virt_to_page(dva).inc_mapcount();
+        self.mapped.insert(va, dva);
+        Ok(())
+    }
+
+    /// Changes the protection of populated pages in [start, end)
+    /// according to the new `vm_flags`.
+    ///
+    /// PTEs of PROT_NONE pages are cleared, but the pages are kept in
+    /// `mapped`, so they can be mapped again by a later mprotect.
+    pub fn change_protection(&mut self, start: usize, end: usize, vm_flags: usize) {
+        let mut pgd = self.pgd.lock();
+        for (va, dva) in self.mapped.range(start..end) {
+            let flags = page_prot(*dva, vm_flags);
+            if flags.is_empty() {
+                let _ = pgd.unmap((*va).into());
+            } else if pgd.update((*va).into(), None, Some(flags)).is_err() {
+                let pa = virt_to_phys((*dva).into());
+                pgd.map((*va).into(), pa, PageSize::Size4K, flags).unwrap();
+            }
+        }
+        drop(pgd);
+        flush_tlb(None);
+    }
+
+    /// Finds the vma which contains `va`.
+    pub fn find_vma(&self, va: usize) -> Option<&VmAreaStruct> {
+        self.vmas
+            .upper_bound(Bound::Included(&va))
+            .value()
+            .filter(|vma| va < vma.vm_end)
+    }
+
+    /// Unmaps populated pages in [start, end) and drops their references.
+    pub fn unmap_pages(&mut self, start: usize, end: usize) {
+        let mut pages = self.mapped.split_off(&start);
+        let mut rest = pages.split_off(&end);
+        self.mapped.append(&mut rest);
+
+        let mut pgd = self.pgd.lock();
+        for (va, dva) in pages {
+            let _ = pgd.unmap(va.into());
+            virt_to_page(dva).dec_mapcount();
+            put_page(dva);
+        }
+        drop(pgd);
+        flush_tlb(None);
+    }
+
+    /// Moves populated pages in [start, end) to `new_start` without copying.
+    /// Returns EEXIST if the target range has populated pages, and nothing
+    /// is moved on failure.
+    pub fn move_pages(
+        &mut self,
+        start: usize,
+        end: usize,
+        new_start: usize,
+        vm_flags: usize,
+    ) -> LinuxResult {
+        let new_end = new_start + (end - start);
+        if self.mapped.range(new_start..new_end).next().is_some() {
+            return Err(LinuxError::EEXIST);
+        }
+        let pages: Vec<(usize, usize)> = self.mapped
+            .range(start..end)
+            .map(|(va, dva)| (*va, *dva))
+            .collect();
+
+        let mut pgd = self.pgd.lock();
+        for (i, &(va, dva)) in pages.iter().enumerate() {
+            let new_va = va - start + new_start;
+            debug!("move_pages: {:#X} -> {:#X}", va, new_va);
+            let flags = page_prot(dva, vm_flags);
+            if flags.is_empty() {
+                continue;
+            }
+            let pa = virt_to_phys(dva.into());
+            if let Err(e) = pgd.map(new_va.into(), pa, PageSize::Size4K, flags) {
+                for &(va, _) in &pages[..i] {
+                    let _ = pgd.unmap((va - start + new_start).into());
+                }
+                drop(pgd);
+                flush_tlb(None);
+                return Err(match e {
+                    PagingError::NoMemory => LinuxError::ENOMEM,
+                    _ => LinuxError::EEXIST,
+                });
+            }
+        }
+        for (va, dva) in pages {
+            let _ = pgd.unmap(va.into());
+            self.mapped.remove(&va);
+            self.mapped.insert(va - start + new_start, dva);
+        }
+        drop(pgd);
+        flush_tlb(None);
+        Ok(())
+    }
+
+    /// Unmaps all pages recorded in `mapped` and drops their references.
+    pub fn unmap_all(&mut self) {
+        while let Some((va, dva)) = self.mapped.pop_first() {
+            let _ = self.unmap_region(va, PAGE_SIZE);
+            virt_to_page(dva).dec_mapcount();
+            put_page(dva);
+        }
+    }
+
+    /// Adds a user of this mm, e.g., a new thread.
+    pub fn mmget(&mut self) {
+        self.users += 1;
+    }
+
+    /// Drops a user of this mm. The last user unmaps all pages, though
+    /// the mm itself may be still referenced by the dead tasks.
+    pub fn mmput(&mut self) {
+        self.users -= 1;
+        if self.users == 0 {
+            self.unmap_all();
         }
     }
 
@@ -156,9 +401,16 @@ impl MmStruct {
         self.brk = brk;
     }
 
-    pub fn map_region(&self, va: usize, pa: usize, len: usize, _uflags: usize) -> PagingResult {
-        let flags =
-            MappingFlags::READ | MappingFlags::WRITE | MappingFlags::EXECUTE | MappingFlags::USER;
+    pub fn sigpage(&self) -> usize {
+        self.sigpage
+    }
+
+    pub fn set_sigpage(&mut self, sigpage: usize) {
+        self.sigpage = sigpage;
+    }
+
+    pub fn map_region(&self, va: usize, pa: usize, len: usize, vm_flags: usize) -> PagingResult {
+        let flags = vm_get_page_prot(vm_flags);
         self.pgd
             .lock()
             .map_region(va.into(), pa.into(), len, flags, true)
@@ -168,3 +420,9 @@ impl MmStruct {
         self.pgd.lock().unmap_region(va.into(), len)
     }
 }
+
+impl Drop for MmStruct {
+    fn drop(&mut self) {
+        self.unmap_all();
+    }
+}
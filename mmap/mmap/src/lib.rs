@@ -8,20 +8,19 @@ use axerrno::LinuxResult;
 use axfile::fops::File;
 use axhal::arch::TASK_UNMAPPED_BASE;
 use axhal::mem::{phys_to_virt, virt_to_phys};
+use axalloc::{virt_to_page, get_page, PG_CACHE, PG_SHARED};
 use axio::SeekFrom;
 use core::ops::Bound;
 use memory_addr::{align_up_4k, align_down_4k, is_aligned_4k, PAGE_SHIFT, PAGE_SIZE_4K};
 pub use mm::FileRef;
-use mm::VmAreaStruct;
+use mm::{MmStruct, VmAreaStruct};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use axerrno::LinuxError;
 use axhal::arch::TASK_SIZE;
-use mm::{VM_READ, VM_WRITE, VM_EXEC, VM_SHARED};
-#[cfg(target_arch = "riscv64")]
+use mm::{VM_READ, VM_WRITE, VM_EXEC, VM_SHARED, VM_GROWSDOWN, VM_LOCKED};
+use task::RLIMIT_STACK;
 use axhal::arch::{EXC_INST_PAGE_FAULT, EXC_LOAD_PAGE_FAULT, EXC_STORE_PAGE_FAULT};
-#[cfg(target_arch = "riscv64")]
-use task::SIGSEGV;
-#[cfg(target_arch = "riscv64")]
-use signal::force_sig_fault;
 
 pub const PROT_READ: usize = 0x1;
 pub const PROT_WRITE: usize = 0x2;
@@ -44,7 +43,7 @@ pub const MAP_FIXED: usize = 0x10;
 pub const MAP_ANONYMOUS: usize = 0x20;
 
 /// stack-like segment
-const MAP_GROWSDOWN: usize = 0x0100;
+pub const MAP_GROWSDOWN: usize = 0x0100;
 /// ETXTBSY
 const MAP_DENYWRITE: usize = 0x0800;
 /// mark it as an executable */
@@ -76,6 +75,10 @@ pub const MAP_FIXED_NOREPLACE: usize = 0x100000;
 /// For anonymous mmap, memory could be uninitialized
 const MAP_UNINITIALIZED: usize = 0x4000000;
 
+/// Flags for mremap
+pub const MREMAP_MAYMOVE: usize = 1;
+pub const MREMAP_FIXED: usize = 2;
+
 const LEGACY_MAP_MASK: usize =
     MAP_SHARED | MAP_PRIVATE | MAP_FIXED | MAP_ANONYMOUS | MAP_DENYWRITE |
     MAP_EXECUTABLE | MAP_UNINITIALIZED | MAP_GROWSDOWN | MAP_LOCKED | MAP_NORESERVE |
@@ -107,7 +110,7 @@ pub fn mmap(
         }
         filetable.get_file(fd)
     };
-    if len == 0 {
+    if len == 0 || !is_aligned_4k(offset) {
         return Err(LinuxError::EINVAL);
     }
     _mmap(va, len, prot, flags, file, offset)
@@ -121,7 +124,12 @@ pub fn _mmap(
     file: Option<FileRef>,
     offset: usize,
 ) -> LinuxResult<usize> {
-    assert!(is_aligned_4k(va));
+    if len == 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    if len > TASK_SIZE {
+        return Err(LinuxError::ENOMEM);
+    }
     len = align_up_4k(len);
     info!("mmap va {:#X} offset {:#X} flags {:#X} prot {:#X}", va, offset, flags, prot);
 
@@ -130,48 +138,37 @@ pub fn _mmap(
         flags |= MAP_FIXED;
     }
 
+    let mm = task::current().mm();
+    let mut locked_mm = mm.lock();
+
     if (flags & MAP_FIXED) == 0 {
-        va = get_unmapped_vma(va, len);
+        va = get_unmapped_vma(&locked_mm, len)?;
         debug!("Get unmapped vma {:#X}", va);
+    } else if !is_aligned_4k(va) {
+        return Err(LinuxError::EINVAL);
     }
 
     if va > TASK_SIZE - len {
         return Err(LinuxError::ENOMEM);
     }
 
-    let mm = task::current().mm();
-    if let Some(mut overlap) = find_overlap(va, len) {
-        debug!("find overlap {:#X}-{:#X}", overlap.vm_start, overlap.vm_end);
-        assert!(
-            overlap.vm_start <= va && va + len <= overlap.vm_end,
-            "{:#X}-{:#X}; overlap {:#X}-{:#X}",
-            va,
-            va + len,
-            overlap.vm_start,
-            overlap.vm_end
-        );
-
+    if has_overlap(&locked_mm, va, va + len) {
         if (flags & MAP_FIXED_NOREPLACE) != 0 {
             return Err(LinuxError::EEXIST);
         }
-
-        if va + len < overlap.vm_end {
-            let bias = (va + len - overlap.vm_start) >> PAGE_SHIFT;
-            let mut new = overlap.clone();
-            new.vm_start = va + len;
-            new.vm_pgoff += bias;
-            mm.lock().vmas.insert(va + len, new);
-        }
-        if va > overlap.vm_start {
-            overlap.vm_end = va;
-            mm.lock().vmas.insert(overlap.vm_start, overlap);
-        }
+        do_munmap(&mut locked_mm, va, va + len);
     }
 
     let mut vm_flags = calc_vm_prot_bits(prot);
     if (flags & MAP_SHARED) != 0 {
         vm_flags |= VM_SHARED;
     }
+    if (flags & MAP_GROWSDOWN) != 0 {
+        vm_flags |= VM_GROWSDOWN;
+    }
+    if (flags & MAP_LOCKED) != 0 {
+        vm_flags |= VM_LOCKED;
+    }
     info!(
         "mmap region: {:#X} - {:#X}, vm_flags: {:#X}, prot {:#X}",
         va,
@@ -180,10 +177,11 @@ pub fn _mmap(
         prot
     );
     let vma = VmAreaStruct::new(va, va + len, offset >> PAGE_SHIFT, file, vm_flags);
-    mm.lock().vmas.insert(va, vma);
+    locked_mm.vmas.insert(va, vma);
+    merge_vmas(&mut locked_mm, va, va + len);
 
     if (flags & MAP_LOCKED) != 0 {
-        mm.lock().locked_vm = len >> PAGE_SHIFT;
+        locked_mm.locked_vm += len >> PAGE_SHIFT;
     }
 
     Ok(va)
@@ -206,97 +204,199 @@ fn calc_vm_prot_bits(prot: usize) -> usize {
     flags
 }
 
-fn find_overlap(va: usize, len: usize) -> Option<VmAreaStruct> {
-    debug!("find_overlap: va {:#X} len {:#X}", va, len);
+/// Whether any vma intersects [start, end).
+fn has_overlap(mm: &MmStruct, start: usize, end: usize) -> bool {
+    mm.find_vma(start).is_some() || mm.vmas.range(start..end).next().is_some()
+}
 
-    let mm = task::current().mm();
-    let locked_mm = mm.lock();
-    let ret = locked_mm.vmas.iter().find(|(_, vma)| {
-        in_vma(va, va + len, vma) || in_range(vma.vm_start, vma.vm_end, va, va + len)
-    });
+/// Splits the vma which contains `addr` into two at `addr`.
+/// Nothing happens if `addr` is already a boundary or in a hole.
+fn split_vma(mm: &mut MmStruct, addr: usize) {
+    let start = match mm.find_vma(addr) {
+        Some(vma) if vma.vm_start < addr => vma.vm_start,
+        _ => return,
+    };
+    debug!("split_vma: {:#X} at {:#X}", start, addr);
+    let vma = mm.vmas.get_mut(&start).unwrap();
+    let mut new = vma.clone();
+    vma.vm_end = addr;
+    new.vm_start = addr;
+    new.vm_pgoff += (addr - start) >> PAGE_SHIFT;
+    mm.vmas.insert(addr, new);
+}
 
-    if let Some((key, _)) = ret {
-        warn!("### Removed!!!");
-        mm.lock().vmas.remove(&key)
-    } else {
-        None
+/// Whether `next` directly follows `prev` and they can be one vma.
+fn can_merge(prev: &VmAreaStruct, next: &VmAreaStruct) -> bool {
+    if prev.vm_end != next.vm_start || prev.vm_flags != next.vm_flags {
+        return false;
+    }
+    match (prev.vm_file.get(), next.vm_file.get()) {
+        (None, None) => true,
+        (Some(f1), Some(f2)) => {
+            let pages = (prev.vm_end - prev.vm_start) >> PAGE_SHIFT;
+            Arc::ptr_eq(f1, f2) && prev.vm_pgoff + pages == next.vm_pgoff
+        },
+        _ => false,
     }
 }
 
-#[inline]
-const fn in_range(start: usize, end: usize, r_start: usize, r_end: usize) -> bool {
-    (start >= r_start && start < r_end) || (end > r_start && end <= r_end)
+/// Merges compatible adjacent vmas in [start, end) and its neighbours.
+fn merge_vmas(mm: &mut MmStruct, start: usize, end: usize) {
+    let first = match mm.vmas.upper_bound(Bound::Excluded(&start)).key() {
+        Some(key) => *key,
+        None => start,
+    };
+    let keys: Vec<usize> = mm.vmas.range(first..=end).map(|(k, _)| *k).collect();
+    let mut prev_key: Option<usize> = None;
+    for key in keys {
+        if let Some(prev_key) = prev_key {
+            if can_merge(&mm.vmas[&prev_key], &mm.vmas[&key]) {
+                debug!("merge_vmas: {:#X} and {:#X}", prev_key, key);
+                let next = mm.vmas.remove(&key).unwrap();
+                mm.vmas.get_mut(&prev_key).unwrap().vm_end = next.vm_end;
+                continue;
+            }
+        }
+        prev_key = Some(key);
+    }
 }
 
-#[inline]
-const fn in_vma(start: usize, end: usize, vma: &VmAreaStruct) -> bool {
-    in_range(start, end, vma.vm_start, vma.vm_end)
+/// Removes all vmas in [start, end) and releases their pages.
+fn do_munmap(mm: &mut MmStruct, start: usize, end: usize) {
+    debug!("do_munmap: {:#X} - {:#X}", start, end);
+    split_vma(mm, start);
+    split_vma(mm, end);
+    let keys: Vec<usize> = mm.vmas.range(start..end).map(|(k, _)| *k).collect();
+    for key in keys {
+        let vma = mm.vmas.remove(&key).unwrap();
+        if (vma.vm_flags & VM_LOCKED) != 0 {
+            mm.locked_vm -= (vma.vm_end - vma.vm_start) >> PAGE_SHIFT;
+        }
+    }
+    mm.unmap_pages(start, end);
 }
 
-pub fn get_unmapped_vma(_va: usize, len: usize) -> usize {
-    let mm = task::current().mm();
-    let locked_mm = mm.lock();
+fn get_unmapped_vma(mm: &MmStruct, len: usize) -> LinuxResult<usize> {
     let mut gap_end = TASK_UNMAPPED_BASE;
-    for (_, vma) in locked_mm.vmas.iter().rev() {
+    for (_, vma) in mm.vmas.iter().rev() {
         debug!(
             "get_unmapped_vma iterator: {:#X} {:#X} {:#X}",
             vma.vm_start, vma.vm_end, gap_end
         );
-        if vma.vm_end > gap_end {
+        if vma.vm_start >= gap_end {
             continue;
         }
-        if gap_end - vma.vm_end >= len {
+        if vma.vm_end <= gap_end && gap_end - vma.vm_end >= len {
             debug!(
                 "get_unmapped_vma: {:#X} {:#X} {:#X}",
                 vma.vm_start, vma.vm_end, gap_end - len
             );
-            return gap_end - len;
+            return Ok(gap_end - len);
         }
         gap_end = vma.vm_start;
     }
 
     if gap_end >= len {
         debug!("get_unmapped_vma: {:#X}", gap_end - len);
-        return gap_end - len;
+        return Ok(gap_end - len);
     }
-    unimplemented!("NO available unmapped vma!");
+    warn!("NO available unmapped vma for {:#X}!", len);
+    Err(LinuxError::ENOMEM)
 }
 
-// invalid permissions for mapped object
-#[cfg(target_arch = "riscv64")]
-const SEGV_ACCERR: usize = 2;
 
-pub fn faultin_page(va: usize, cause: usize) -> usize {
-    let va = align_down_4k(va);
-    info!("--------- faultin_page... va {:#X} cause {}", va, cause);
+/// Gap between a stack and the vma below it, to catch stack overflow.
+const STACK_GUARD_GAP: usize = 256 << PAGE_SHIFT;
+
+/// Extends the growsdown vma just above `va` down to `va`.
+/// The stack size is bounded by RLIMIT_STACK and the guard gap.
+fn expand_stack(mm: &mut MmStruct, va: usize) -> LinuxResult {
+    let (start, end) = match mm.vmas.range(va..).next() {
+        Some((_, vma)) if (vma.vm_flags & VM_GROWSDOWN) != 0 => (vma.vm_start, vma.vm_end),
+        _ => return Err(LinuxError::EFAULT),
+    };
+
+    let rlim = task::current().rlimit(RLIMIT_STACK);
+    if (end - va) as u64 > rlim {
+        warn!("stack overflow: {:#X} - {:#X} rlimit {:#X}", va, end, rlim);
+        return Err(LinuxError::ENOMEM);
+    }
+    if let Some((_, prev)) = mm.vmas.range(..va).next_back() {
+        if va - prev.vm_end < STACK_GUARD_GAP {
+            warn!("stack guard gap: {:#X} prev end {:#X}", va, prev.vm_end);
+            return Err(LinuxError::ENOMEM);
+        }
+    }
+
+    debug!("expand_stack: {:#X} -> {:#X}", start, va);
+    let mut vma = mm.vmas.remove(&start).unwrap();
+    vma.vm_pgoff = vma.vm_pgoff.saturating_sub((start - va) >> PAGE_SHIFT);
+    vma.vm_start = va;
+    mm.vmas.insert(va, vma);
+    Ok(())
+}
+
+/// Reasons of a failed page fault.
+/// Trap handler turns them into signals for user mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmFault {
+    /// Address isn't mapped to object.
+    MapErr,
+    /// Invalid permissions for mapped object.
+    AccErr,
+    /// Access beyond the end of the backing file.
+    SigBus,
+    /// No memory left to populate the page.
+    Oom,
+}
+
+impl From<VmFault> for LinuxError {
+    fn from(fault: VmFault) -> Self {
+        match fault {
+            VmFault::Oom => LinuxError::ENOMEM,
+            _ => LinuxError::EFAULT,
+        }
+    }
+}
+
+/// Handles a page fault at `addr`, and returns the kernel virtual address
+/// of the page. ZERO means that the page is already present.
+///
+/// `cause` is one of EXC_*_PAGE_FAULT. ZERO means that kernel populates
+/// the page for itself, so access permissions are not checked.
+pub fn faultin_page(addr: usize, cause: usize) -> Result<usize, VmFault> {
+    let va = align_down_4k(addr);
+    debug!("--------- faultin_page... va {:#X} cause {}", va, cause);
     let mm = task::current().mm();
     let mut locked_mm = mm.lock();
-    if locked_mm.mapped.get(&va).is_some() {
-        warn!("============== find page {:#X} already exists!", va);
-        return 0;
+
+    if va >= TASK_SIZE {
+        warn!("bad area: addr {:#X} cause {}", addr, cause);
+        return Err(VmFault::MapErr);
     }
+    if locked_mm.find_vma(va).is_none() && expand_stack(&mut locked_mm, va).is_err() {
+        warn!("bad area: addr {:#X} cause {}", addr, cause);
+        return Err(VmFault::MapErr);
+    }
+    let vma = locked_mm.find_vma(va).unwrap();
 
-    let vma = locked_mm
-        .vmas
-        .upper_bound(Bound::Included(&va))
-        .value()
-        .unwrap();
-    assert!(
-        va >= vma.vm_start && va < vma.vm_end,
-        "va {:#X} in {:#X} - {:#X}",
-        va,
-        vma.vm_start,
-        vma.vm_end
-    );
+    if access_error(cause, vma) {
+        warn!("access error: addr {:#X} cause {}", addr, cause);
+        return Err(VmFault::AccErr);
+    }
 
-    #[cfg(target_arch = "riscv64")]
-    {
-        if access_error(cause, vma) {
-            bad_area(SEGV_ACCERR, va);
-            return usize::MAX;
+    if locked_mm.mapped.get(&va).is_some() {
+        // The page is present, so a write to it must be a write
+        // to a page which is shared with parent or child after fork.
+        if cause == EXC_STORE_PAGE_FAULT && (vma.vm_flags & VM_WRITE) != 0 {
+            let vm_flags = vma.vm_flags;
+            return locked_mm.break_cow(va, vm_flags).map_err(|_| VmFault::Oom);
         }
+        debug!("find page {:#X} already exists!", va);
+        return Ok(0);
     }
 
+    let vm_flags = vma.vm_flags;
     let delta = va - vma.vm_start;
     let offset = (vma.vm_pgoff << PAGE_SHIFT) + delta;
 
@@ -304,17 +404,29 @@ pub fn faultin_page(va: usize, cause: usize) -> usize {
         assert!(vma.vm_file.get().is_some());
         let f = vma.vm_file.get().unwrap().clone();
         let f = f.lock();
-        if let Some(pa) = f.shared_map.get(&offset) {
-            locked_mm.map_region(va, *pa, PAGE_SIZE_4K, 1)
+        if let Some(dva) = f.shared_map.get(&offset) {
+            let dva = *dva;
+            // The mm takes its own reference besides the file's.
+            get_page(dva);
+            locked_mm.map_page(va, dva, vm_flags)
                 .unwrap_or_else(|e| { panic!("{:?}", e) });
 
-            return phys_to_virt((*pa).into()).into();
+            return Ok(dva);
+        }
+    }
+
+    // Access beyond the end of the file
+    if let Some(f) = vma.vm_file.get() {
+        let size = f.lock().get_attr().map_or(0, |attr| attr.size() as usize);
+        if offset >= align_up_4k(size) {
+            warn!("fault at {:#X} beyond EOF: offset {:#X} size {:#X}", va, offset, size);
+            return Err(VmFault::SigBus);
         }
     }
 
     let direct_va: usize = axalloc::global_allocator()
         .alloc_pages(1, PAGE_SIZE_4K)
-        .unwrap();
+        .map_err(|_| VmFault::Oom)?;
 
     // Todo: check whether we need to zero it.
     let buf = unsafe { core::slice::from_raw_parts_mut(direct_va as *mut u8, PAGE_SIZE_4K) };
@@ -325,24 +437,20 @@ pub fn faultin_page(va: usize, cause: usize) -> usize {
     if vma.vm_file.get().is_some() {
         let f = vma.vm_file.get().unwrap().clone();
         fill_cache(pa, PAGE_SIZE_4K, &mut f.lock(), offset);
+        virt_to_page(direct_va).set_flags(PG_CACHE);
         if (vma.vm_flags & VM_SHARED) != 0 {
-            f.lock().shared_map.insert(offset, pa);
+            virt_to_page(direct_va).set_flags(PG_SHARED);
+            get_page(direct_va);
+            f.lock().shared_map.insert(offset, direct_va);
         }
     }
-    locked_mm.map_region(va, pa, PAGE_SIZE_4K, 1)
+    locked_mm.map_page(va, direct_va, vm_flags)
         .unwrap_or_else(|e| { panic!("{:?}", e) });
 
-    if (vma.vm_flags & VM_SHARED) == 0 {
-        // Todo: temporarily record mapped va->pa(direct_va)
-        locked_mm.mapped.insert(va, direct_va);
-    }
-
-    phys_to_virt(pa.into()).into()
+    Ok(direct_va)
 }
 
-#[cfg(target_arch = "riscv64")]
 fn access_error(cause: usize, vma: &VmAreaStruct) -> bool {
-    // Todo: consider that the cause can be ZERO?!
     if cause == 0 {
         return false;
     }
@@ -359,25 +467,12 @@ fn access_error(cause: usize, vma: &VmAreaStruct) -> bool {
             (vma.vm_flags & VM_WRITE) == 0
         },
         _ => {
-            panic!("Unhandled cause {}", cause);
+            warn!("Unhandled cause {}", cause);
+            true
         }
     }
 }
 
-#[cfg(target_arch = "riscv64")]
-fn bad_area(code: usize, addr: usize) {
-    // User mode accesses just cause a SIGSEGV
-    // Todo: makesure now we are in userland.
-    // #define user_mode(regs) (((regs)->status & SR_PP) == 0)
-    error!("code {} addr {:#X}", code, addr);
-    do_trap(SIGSEGV, code, addr);
-}
-
-#[cfg(target_arch = "riscv64")]
-fn do_trap(signo: usize, code: usize, addr: usize) {
-    force_sig_fault(signo, code, addr);
-}
-
 fn fill_cache(pa: usize, len: usize, file: &mut File, offset: usize) {
     let offset = align_down_4k(offset);
     let va = phys_to_virt(pa.into()).as_usize();
@@ -446,119 +541,221 @@ pub fn msync(va: usize, len: usize, flags: usize) -> usize {
 
     if vma.vm_file.get().is_some() {
         let file = vma.vm_file.get().unwrap().clone();
-        sync_file(va, len, &mut file.lock(), offset);
+        sync_file(&locked_mm, va, len, &mut file.lock(), offset);
     }
     0
 }
 
-fn sync_file(va: usize, len: usize, file: &mut File, offset: usize) {
-    let buf = unsafe { core::slice::from_raw_parts(va as *const u8, len) };
-
-    let _ = file.seek(SeekFrom::Start(offset as u64));
-
-    let mut pos = 0;
-    while pos < len {
-        let ret = file.write(&buf[pos..]).unwrap();
-        if ret == 0 {
-            break;
+/// Writes back through kernel addresses of the pages, so that user
+/// memory isn't accessed directly. Pages not present are skipped.
+fn sync_file(mm: &MmStruct, va: usize, len: usize, file: &mut File, offset: usize) {
+    let end = va + len;
+    let mut cur = va;
+    while cur < end {
+        let page_va = align_down_4k(cur);
+        let next = core::cmp::min(page_va + PAGE_SIZE_4K, end);
+        if let Some(dva) = mm.mapped.get(&page_va) {
+            let start = dva + (cur - page_va);
+            let buf = unsafe { core::slice::from_raw_parts(start as *const u8, next - cur) };
+            let _ = file.seek(SeekFrom::Start((offset + cur - va) as u64));
+
+            let mut pos = 0;
+            while pos < buf.len() {
+                let ret = file.write(&buf[pos..]).unwrap();
+                if ret == 0 {
+                    break;
+                }
+                pos += ret;
+            }
         }
-        pos += ret;
+        cur = next;
     }
     debug!("msync: ok!");
 }
 
-pub fn munmap(va: usize, mut len: usize) -> usize {
-    assert!(is_aligned_4k(va));
-    len = align_up_4k(len);
+pub fn munmap(va: usize, mut len: usize) -> LinuxResult<usize> {
     debug!("munmap {:#X} - {:#X}", va, va + len);
+    if !is_aligned_4k(va) || len == 0 || len > TASK_SIZE {
+        return Err(LinuxError::EINVAL);
+    }
+    len = align_up_4k(len);
+    if va > TASK_SIZE - len {
+        return Err(LinuxError::EINVAL);
+    }
 
-    if let Some(mut overlap) = find_overlap(va, len) {
-        debug!("find overlap {:#X}-{:#X}", overlap.vm_start, overlap.vm_end);
-        assert!(
-            overlap.vm_start <= va && va + len <= overlap.vm_end,
-            "{:#X}-{:#X}; overlap {:#X}-{:#X}",
-            va,
-            va + len,
-            overlap.vm_start,
-            overlap.vm_end
-        );
+    let mm = task::current().mm();
+    do_munmap(&mut mm.lock(), va, va + len);
+    Ok(0)
+}
 
-        if va + len < overlap.vm_end {
-            let bias = (va + len - overlap.vm_start) >> PAGE_SHIFT;
-            let mut new = overlap.clone();
-            new.vm_start = va + len;
-            new.vm_pgoff += bias;
-            let mm = task::current().mm();
-            mm.lock().vmas.insert(va + len, new);
-        }
-        if va > overlap.vm_start {
-            overlap.vm_end = va;
-            let mm = task::current().mm();
-            mm.lock().vmas.insert(overlap.vm_start, overlap);
-        }
+pub fn mremap(
+    old_addr: usize,
+    old_len: usize,
+    new_len: usize,
+    flags: usize,
+    new_addr: usize,
+) -> LinuxResult<usize> {
+    info!(
+        "mremap: {:#X} len {:#X} -> {:#X} len {:#X} flags {:#X}",
+        old_addr, old_len, new_addr, new_len, flags
+    );
+    if (flags & !(MREMAP_MAYMOVE | MREMAP_FIXED)) != 0 || !is_aligned_4k(old_addr) {
+        return Err(LinuxError::EINVAL);
     }
+    if (flags & MREMAP_FIXED) != 0 && (flags & MREMAP_MAYMOVE) == 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    // Todo: old_len == 0 duplicates a shared mapping.
+    if old_len == 0 || new_len == 0 || old_len > TASK_SIZE || new_len > TASK_SIZE {
+        return Err(LinuxError::EINVAL);
+    }
+    let old_len = align_up_4k(old_len);
+    let new_len = align_up_4k(new_len);
+    let old_end = old_addr + old_len;
 
     let mm = task::current().mm();
     let mut locked_mm = mm.lock();
-    // Todo: handle temporary mmaped.
-    locked_mm.mapped.remove(&va);
-    match locked_mm.unmap_region(va, len) {
-        Ok(_) => 0,
-        Err(e) => {
-            warn!("unmap region err: {:#?}", e);
-            0
+
+    // The old range must be within a single vma.
+    let vma_end = match locked_mm.find_vma(old_addr) {
+        Some(vma) if old_end <= vma.vm_end => vma.vm_end,
+        _ => return Err(LinuxError::EFAULT),
+    };
+
+    if (flags & MREMAP_FIXED) != 0 {
+        if !is_aligned_4k(new_addr) || new_addr > TASK_SIZE - new_len {
+            return Err(LinuxError::EINVAL);
+        }
+        let new_end = new_addr + new_len;
+        if new_addr < old_end && old_addr < new_end {
+            return Err(LinuxError::EINVAL);
+        }
+        do_munmap(&mut locked_mm, new_addr, new_end);
+        let old_len = if new_len < old_len {
+            do_munmap(&mut locked_mm, old_addr + new_len, old_end);
+            new_len
+        } else {
+            old_len
+        };
+        move_vma(&mut locked_mm, old_addr, old_len, new_addr, new_len)?;
+        return Ok(new_addr);
+    }
+
+    if new_len <= old_len {
+        do_munmap(&mut locked_mm, old_addr + new_len, old_end);
+        return Ok(old_addr);
+    }
+
+    // Try to expand in place, if the gap after the vma is big enough.
+    let new_end = old_addr + new_len;
+    if old_end == vma_end
+        && old_addr <= TASK_SIZE - new_len
+        && !has_overlap(&locked_mm, old_end, new_end)
+        && !below_stack_guard(&locked_mm, new_end) {
+        let vma = locked_mm.find_vma(old_addr).unwrap();
+        let (start, vm_flags) = (vma.vm_start, vma.vm_flags);
+        locked_mm.vmas.get_mut(&start).unwrap().vm_end = new_end;
+        if (vm_flags & VM_LOCKED) != 0 {
+            locked_mm.locked_vm += (new_len - old_len) >> PAGE_SHIFT;
+        }
+        merge_vmas(&mut locked_mm, start, new_end);
+        return Ok(old_addr);
+    }
+
+    if (flags & MREMAP_MAYMOVE) == 0 {
+        return Err(LinuxError::ENOMEM);
+    }
+    let new_addr = get_unmapped_vma(&locked_mm, new_len)?;
+    move_vma(&mut locked_mm, old_addr, old_len, new_addr, new_len)?;
+    Ok(new_addr)
+}
+
+/// Whether a vma ending at `end` would be within the guard gap
+/// of a growsdown vma above it.
+fn below_stack_guard(mm: &MmStruct, end: usize) -> bool {
+    match mm.vmas.range(end..).next() {
+        Some((_, next)) if (next.vm_flags & VM_GROWSDOWN) != 0 => {
+            next.vm_start - end < STACK_GUARD_GAP
         },
+        _ => false,
+    }
+}
+
+/// Moves [old_addr, old_addr + old_len) to a free range at `new_addr`
+/// with size `new_len`. Populated pages are moved with their PTEs.
+fn move_vma(
+    mm: &mut MmStruct,
+    old_addr: usize,
+    old_len: usize,
+    new_addr: usize,
+    new_len: usize,
+) -> LinuxResult {
+    let old_end = old_addr + old_len;
+    split_vma(mm, old_addr);
+    split_vma(mm, old_end);
+    let vm_flags = mm.vmas[&old_addr].vm_flags;
+    debug!("move_vma: {:#X} - {:#X} -> {:#X}", old_addr, old_end, new_addr);
+
+    if let Err(e) = mm.move_pages(old_addr, old_end, new_addr, vm_flags) {
+        merge_vmas(mm, old_addr, old_end);
+        return Err(e);
+    }
+    let mut vma = mm.vmas.remove(&old_addr).unwrap();
+    vma.vm_start = new_addr;
+    vma.vm_end = new_addr + new_len;
+    mm.vmas.insert(new_addr, vma);
+    if new_len > old_len && (vm_flags & VM_LOCKED) != 0 {
+        mm.locked_vm += (new_len - old_len) >> PAGE_SHIFT;
     }
+    merge_vmas(mm, new_addr, new_addr + new_len);
+    Ok(())
 }
 
-pub fn mprotect(va: usize, len: usize, prot: usize) -> usize {
+pub fn mprotect(va: usize, mut len: usize, prot: usize) -> LinuxResult<usize> {
     info!("mprotect: va {:#X} len {:#X} prot {:#X}", va, len, prot);
-    assert!(is_aligned_4k(va));
+    if !is_aligned_4k(va) || len > TASK_SIZE {
+        return Err(LinuxError::EINVAL);
+    }
+    len = align_up_4k(len);
+    if len == 0 {
+        return Ok(0);
+    }
+    if va > TASK_SIZE - len {
+        return Err(LinuxError::ENOMEM);
+    }
+    let end = va + len;
 
-    let mut vma;
     let mm = task::current().mm();
-    if let Some(mut overlap) = find_overlap(va, len) {
-        debug!("find overlap {:#X}-{:#X}", overlap.vm_start, overlap.vm_end);
-        assert!(
-            overlap.vm_start <= va && va + len <= overlap.vm_end,
-            "{:#X}-{:#X}; overlap {:#X}-{:#X} vm_flags {:#X} vm_offset {:#X}",
-            va,
-            va + len,
-            overlap.vm_start,
-            overlap.vm_end,
-            overlap.vm_flags,
-            overlap.vm_pgoff,
-        );
-        vma = overlap.clone();
-        vma.vm_start = va;
-        vma.vm_end = va + len;
-        vma.vm_pgoff += (va - overlap.vm_start) >> PAGE_SHIFT;
-
-        if va + len < overlap.vm_end {
-            let bias = (va + len - overlap.vm_start) >> PAGE_SHIFT;
-            let mut new = overlap.clone();
-            new.vm_start = va + len;
-            new.vm_pgoff += bias;
-            mm.lock().vmas.insert(va + len, new);
-        }
-        if va > overlap.vm_start {
-            overlap.vm_end = va;
-            mm.lock().vmas.insert(overlap.vm_start, overlap);
+    let mut locked_mm = mm.lock();
+
+    // The whole range must be mapped.
+    let mut addr = va;
+    while addr < end {
+        match locked_mm.find_vma(addr) {
+            Some(vma) => addr = vma.vm_end,
+            None => return Err(LinuxError::ENOMEM),
         }
-    } else {
-        panic!("No such vma!");
     }
 
+    split_vma(&mut locked_mm, va);
+    split_vma(&mut locked_mm, end);
+
     /*
      * Each mprotect() call explicitly passes r/w/x permissions.
      * If a permission is not passed to mprotect(), it must be
      * cleared from the VMA.
      */
     let mask_off = VM_READ | VM_WRITE | VM_EXEC;
-    let newflags = calc_vm_prot_bits(prot) | (vma.vm_flags & !mask_off);
-
-    vma.vm_flags = newflags;
-    mm.lock().vmas.insert(va, vma);
-    info!("mprotect: newflags {:#X}", newflags);
-    0
+    let mut changed = Vec::new();
+    for vma in locked_mm.vmas.range_mut(va..end).map(|(_, vma)| vma) {
+        let newflags = calc_vm_prot_bits(prot) | (vma.vm_flags & !mask_off);
+        info!("mprotect: {:#X} - {:#X} newflags {:#X}", vma.vm_start, vma.vm_end, newflags);
+        vma.vm_flags = newflags;
+        changed.push((vma.vm_start, vma.vm_end, newflags));
+    }
+    for (start, end, newflags) in changed {
+        locked_mm.change_protection(start, end, newflags);
+    }
+    merge_vmas(&mut locked_mm, va, end);
+    Ok(0)
 }
@@ -1,4 +1,5 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use lazy_init::LazyInit;
 use scheduler::BaseScheduler;
 use taskctx::switch_mm;
@@ -19,10 +20,11 @@ type Scheduler = scheduler::CFScheduler<CtxRef>;
 // TODO: per-CPU
 pub(crate) static RUN_QUEUE: LazyInit<SpinNoIrq<AxRunQueue>> = LazyInit::new();
 
-/*
+// Dead tasks whose kernel stacks are to be freed after they switch away.
 // TODO: per-CPU
-static EXITED_TASKS: SpinNoIrq<VecDeque<AxTaskRef>> = SpinNoIrq::new(VecDeque::new());
+static EXITED_TASKS: SpinNoIrq<Vec<CtxRef>> = SpinNoIrq::new(Vec::new());
 
+/*
 static WAIT_FOR_EXIT: WaitQueue = WaitQueue::new();
 
 #[percpu::def_percpu]
@@ -175,6 +177,7 @@ impl AxRunQueue {
     /// slice, otherwise reset it.
     pub fn resched(&mut self, preempt: bool) {
         let prev = taskctx::current_ctx();
+        release_exited_tasks(&prev);
         if prev.is_running() {
             prev.set_state(TaskState::Ready);
             // Todo: imitate linux kernel to deal with idle task(tid == 0)
@@ -226,6 +229,9 @@ impl AxRunQueue {
         if prev_task.try_pgd().is_none() {
             prev_task.active_mm_id.store(0, Ordering::SeqCst);
         }
+        if prev_task.is_dead() {
+            EXITED_TASKS.lock().push(prev_task.as_ctx_ref().clone());
+        }
 
         unsafe {
             let prev_ctx_ptr = prev_task.ctx_mut_ptr();
@@ -242,6 +248,19 @@ impl AxRunQueue {
     }
 }
 
+/// Frees the kernel stacks of dead tasks, which have switched away
+/// for the last time, i.e., all of them except the current one.
+fn release_exited_tasks(curr: &CurrentCtx) {
+    let mut exited = EXITED_TASKS.lock();
+    for mut task in core::mem::take(&mut *exited) {
+        if curr.ptr_eq(&task) {
+            exited.push(task);
+        } else {
+            unsafe { taskctx::release_kstack(&mut task) };
+        }
+    }
+}
+
 /*
 
 fn gc_entry() {
@@ -1,7 +1,44 @@
-use axhal::arch::{TrapFrame, local_flush_icache_all};
+use core::mem::MaybeUninit;
+use axhal::arch::{TrapFrame, GeneralRegisters, FpState};
 use axtype::align_down;
-use crate::{RTSigFrame, KSignal, SIGFRAME_SIZE};
-use crate::{setup_sigcontext, restore_sigcontext};
+use uaccess::{copy_from_user, copy_to_user};
+use task::SIGSEGV;
+use crate::{KSignal, UserSigInfo, UserSigAltStack, SI_KERNEL};
+use crate::{force_sig_fault, user_siginfo};
+use crate::{on_sig_stack, sigsp, save_altstack, restore_altstack};
+use crate::{set_current_blocked, sigmask_to_save, signal_delivered};
+
+// Size of union __riscv_fp_state, its largest member is q_ext.
+const FP_STATE_SIZE: usize = 528;
+
+/// struct sigcontext: user_regs_struct and the floating-point state.
+#[repr(C, align(16))]
+struct SigContext {
+    pc: usize,
+    regs: GeneralRegisters,
+    /// d_ext of union __riscv_fp_state.
+    fpregs: FpState,
+    _reserved: [u8; FP_STATE_SIZE - core::mem::size_of::<FpState>()],
+}
+
+#[repr(C)]
+struct UContext {
+    flags: usize,
+    link: usize,
+    stack: UserSigAltStack,
+    sigmask: usize,
+    /// Reserved for the future expansion of sigset_t (1024 bits).
+    _unused: [u8; 120],
+    mcontext: SigContext,
+}
+
+#[repr(C)]
+struct RTSigFrame {
+    info: UserSigInfo,
+    uc: UContext,
+}
+
+const SIGFRAME_SIZE: usize = core::mem::size_of::<RTSigFrame>();
 
 pub fn rt_sigreturn() -> usize {
     info!("sigreturn ...");
@@ -10,56 +47,109 @@ pub fn rt_sigreturn() -> usize {
     let tf = ctx.pt_regs();
 
     let frame_addr = tf.regs.sp;
-    let frame = unsafe { &mut(*(frame_addr as *mut RTSigFrame)) };
-
-    // Validation: sigreturn_code must be 'li a7, 139; scall'.
-    // For riscv64, NR_sigreturn == 139.
-    assert_eq!(frame.sigreturn_code, 0x7308B00893);
+    let mut frame = MaybeUninit::<RTSigFrame>::uninit();
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(frame.as_mut_ptr() as *mut u8, SIGFRAME_SIZE)
+    };
+    if copy_from_user(buf, frame_addr).is_err() {
+        warn!("bad sigframe {:#X}", frame_addr);
+        force_sig_fault(SIGSEGV, SI_KERNEL, 0);
+        return 0;
+    }
+    let frame = unsafe { frame.assume_init() };
 
-    //__copy_from_user(&set, &frame->uc.uc_sigmask, sizeof(set))
-    //set_current_blocked(&set);
+    set_current_blocked(frame.uc.sigmask);
 
-    restore_sigcontext(tf, frame);
+    restore_sigcontext(tf, &frame.uc.mcontext);
+    restore_altstack(&frame.uc.stack);
 
-    // Todo: restore_altstack
     return tf.regs.a0;
 }
 
-fn get_sigframe(tf: &TrapFrame) -> usize {
-    let sp = tf.regs.sp - SIGFRAME_SIZE;
+pub fn current_user_stack_pointer() -> usize {
+    taskctx::current_ctx().pt_regs().regs.sp
+}
+
+pub fn syscall_result(tf: &TrapFrame) -> usize {
+    tf.regs.a0
+}
+
+pub fn set_syscall_result(tf: &mut TrapFrame, ret: usize) {
+    tf.regs.a0 = ret;
+}
+
+/// Rewinds to the ecall, which has overwritten a0 with the result.
+pub fn restart_syscall(tf: &mut TrapFrame, orig_a0: usize) {
+    tf.regs.a0 = orig_a0;
+    tf.sepc -= 4;
+}
+
+fn restore_sigcontext(tf: &mut TrapFrame, sc: &SigContext) {
+    // sstatus is kept, user can't change the privilege mode.
+    tf.regs = sc.regs.clone();
+    tf.sepc = sc.pc;
+    sc.fpregs.restore();
+}
+
+fn setup_sigcontext(sc: &mut SigContext, tf: &TrapFrame) {
+    sc.regs = tf.regs.clone();
+    sc.pc = tf.sepc;
+    // FP registers of the CPU are still the user's.
+    sc.fpregs.save();
+}
+
+fn get_sigframe(ksig: &KSignal, tf: &TrapFrame) -> usize {
+    let sp = tf.regs.sp;
+    // Overflow on the alternate signal stack. Return a bad address
+    // so that the frame can't be copied and we get a SIGSEGV.
+    if on_sig_stack(sp) && !on_sig_stack(sp.wrapping_sub(SIGFRAME_SIZE)) {
+        return usize::MAX;
+    }
+    // A bad sp from user wraps around, and the copy will fail.
+    let sp = sigsp(sp, ksig).wrapping_sub(SIGFRAME_SIZE);
     /* Align the stack frame. */
     align_down(sp, 16)
 }
 
 pub fn handle_signal(ksig: &KSignal, tf: &mut TrapFrame) {
-    extern "C" {
-        fn __user_rt_sigreturn();
+    // The handler returns to the trampoline in the sigpage set up by exec.
+    let sigpage = task::current().mm().lock().sigpage();
+    if sigpage == 0 {
+        warn!("no sigpage for signo {}", ksig.signo);
+        sys::do_group_exit(SIGSEGV as u32);
     }
 
-    let frame_addr = get_sigframe(tf);
-    let frame = unsafe { &mut(*(frame_addr as *mut RTSigFrame)) };
-    setup_sigcontext(frame, tf);
+    let frame_addr = get_sigframe(ksig, tf);
+    let mut frame: RTSigFrame = unsafe { MaybeUninit::zeroed().assume_init() };
+    frame.info = user_siginfo(&ksig.info);
+    save_altstack(&mut frame.uc.stack, tf.regs.sp);
+    setup_sigcontext(&mut frame.uc.mcontext, tf);
+    frame.uc.sigmask = sigmask_to_save();
 
-    // Note: Now we store user_rt_sigreturn code into user stack,
-    // but it's unsafe to execute code on stack.
-    // Consider to implement vdso and put that code in vdso page.
-    let user_rt_sigreturn = __user_rt_sigreturn as usize as *const usize;
-    frame.sigreturn_code = unsafe { *user_rt_sigreturn };
+    let buf = unsafe {
+        core::slice::from_raw_parts(&frame as *const RTSigFrame as *const u8, SIGFRAME_SIZE)
+    };
+    if copy_to_user(frame_addr, buf).is_err() {
+        // The signal can't be delivered, so kill the task just as
+        // Linux does in force_sigsegv.
+        warn!("bad sigframe {:#X} for signo {}", frame_addr, ksig.signo);
+        sys::do_group_exit(SIGSEGV as u32);
+    }
+    signal_delivered(ksig);
 
-    let ra = &(frame.sigreturn_code) as *const usize;
-    /* Make sure the two instructions are pushed to icache. */
-    local_flush_icache_all();
-    tf.regs.ra = ra as usize;
+    let base = &frame as *const RTSigFrame as usize;
+    let offset = |field: usize| frame_addr + (field - base);
+    tf.regs.ra = sigpage;
 
     assert!(ksig.action.handler != 0);
     tf.sepc = ksig.action.handler;
     tf.regs.sp = frame_addr;
     tf.regs.a0 = ksig.signo;    // a0: signal number
-    /*
-    tf.regs.a1 = &frame.info;   // a1: siginfo pointer
-    tf.regs.a2 = &frame.uc;     // a2: ucontext pointer
-    */
+    // The handler with SA_SIGINFO takes the two more arguments,
+    // and they're harmless for the one without it.
+    tf.regs.a1 = offset(&frame.info as *const UserSigInfo as usize);    // a1: siginfo pointer
+    tf.regs.a2 = offset(&frame.uc as *const UContext as usize);         // a2: ucontext pointer
 
     info!("handle_signal signo {} frame {:#X} tf.epc {:#x}",
-          ksig.signo, frame.sigreturn_code, tf.sepc);
+          ksig.signo, frame_addr, tf.sepc);
 }
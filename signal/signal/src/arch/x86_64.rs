@@ -1,10 +1,280 @@
-use axhal::arch::TrapFrame;
-use crate::KSignal;
+use core::mem::MaybeUninit;
+use axerrno::{LinuxResult, LinuxError};
+use axhal::arch::{TrapFrame, FxsaveArea};
+use axtype::align_down;
+use uaccess::{copy_from_user, copy_to_user, get_user, put_user};
+use task::{SIGSEGV, SA_RESTORER};
+use crate::{KSignal, UserSigInfo, UserSigAltStack, SI_KERNEL};
+use crate::{force_sig_fault, user_siginfo};
+use crate::{on_sig_stack, sigsp, save_altstack, restore_altstack};
+use crate::{set_current_blocked, sigmask_to_save, signal_delivered};
+
+// Bits of rflags which user can change by sigreturn:
+// CF, PF, AF, ZF, SF, TF, DF, OF, RF and AC.
+const FIX_EFLAGS: u64 = 0x50DD5;
+const X86_EFLAGS_TF: u64 = 1 << 8;
+const X86_EFLAGS_DF: u64 = 1 << 10;
+
+// Red zone below rsp which can't be touched by the signal frame.
+const RED_ZONE_SIZE: usize = 128;
+
+// Bits of mxcsr which can be set. The others raise #GP on fxrstor.
+const MXCSR_MASK: u32 = 0xFFBF;
+
+// uc_flags: the frame saves ss, and sigreturn restores it as is.
+const UC_SIGCONTEXT_SS: usize = 0x2;
+const UC_STRICT_RESTORE_SS: usize = 0x4;
+
+/// struct sigcontext
+#[repr(C)]
+struct SigContext {
+    r8: u64,
+    r9: u64,
+    r10: u64,
+    r11: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rdi: u64,
+    rsi: u64,
+    rbp: u64,
+    rbx: u64,
+    rdx: u64,
+    rax: u64,
+    rcx: u64,
+    rsp: u64,
+    rip: u64,
+    eflags: u64,
+    cs: u16,
+    gs: u16,
+    fs: u16,
+    ss: u16,
+    err: u64,
+    trapno: u64,
+    oldmask: u64,
+    cr2: u64,
+    /// Pointer to the floating-point state, which is in the legacy fxsave
+    /// format without FP_XSTATE_MAGIC1, since XSAVE isn't enabled.
+    fpstate: u64,
+    _reserved: [u64; 8],
+}
+
+#[repr(C)]
+struct UContext {
+    flags: usize,
+    link: usize,
+    stack: UserSigAltStack,
+    mcontext: SigContext,
+    sigmask: usize,
+}
+
+#[repr(C)]
+struct RTSigFrame {
+    /// Return address of the handler, which is sa_restorer.
+    pretcode: usize,
+    uc: UContext,
+    info: UserSigInfo,
+}
+
+const SIGFRAME_SIZE: usize = core::mem::size_of::<RTSigFrame>();
 
 pub fn rt_sigreturn() -> usize {
-    unimplemented!("impl rt_sigreturn");
+    info!("sigreturn ...");
+
+    let ctx = taskctx::current_ctx();
+    let tf = ctx.pt_regs();
+
+    // 'ret' of the handler has popped pretcode.
+    let frame_addr = tf.rsp as usize - core::mem::size_of::<usize>();
+    let mut frame = MaybeUninit::<RTSigFrame>::uninit();
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(frame.as_mut_ptr() as *mut u8, SIGFRAME_SIZE)
+    };
+    if copy_from_user(buf, frame_addr).is_err() {
+        warn!("bad sigframe {:#X}", frame_addr);
+        force_sig_fault(SIGSEGV, SI_KERNEL, 0);
+        return 0;
+    }
+    let frame = unsafe { frame.assume_init() };
+
+    set_current_blocked(frame.uc.sigmask);
+
+    if restore_sigcontext(tf, &frame.uc.mcontext).is_err() {
+        warn!("bad rip {:#X} in sigframe", frame.uc.mcontext.rip);
+        force_sig_fault(SIGSEGV, SI_KERNEL, 0);
+        return 0;
+    }
+    if restore_fpstate(frame.uc.mcontext.fpstate as usize).is_err() {
+        warn!("bad fpstate {:#X}", frame.uc.mcontext.fpstate);
+        force_sig_fault(SIGSEGV, SI_KERNEL, 0);
+        return 0;
+    }
+    restore_altstack(&frame.uc.stack);
+
+    return tf.rax as usize;
+}
+
+pub fn current_user_stack_pointer() -> usize {
+    taskctx::current_ctx().pt_regs().rsp as usize
+}
+
+pub fn syscall_result(tf: &TrapFrame) -> usize {
+    tf.rax as usize
 }
 
-pub fn handle_signal(_ksig: &KSignal, _tf: &mut TrapFrame) {
-    unimplemented!("impl handle_signal");
+pub fn set_syscall_result(tf: &mut TrapFrame, ret: usize) {
+    tf.rax = ret as u64;
+}
+
+/// Rewinds to the 2-byte syscall instruction, which has overwritten
+/// rax (the syscall number) with the result.
+pub fn restart_syscall(tf: &mut TrapFrame, orig_rax: usize) {
+    tf.rax = orig_rax as u64;
+    tf.rip -= 2;
+}
+
+fn restore_sigcontext(tf: &mut TrapFrame, sc: &SigContext) -> LinuxResult {
+    // Returning to a non-canonical rip faults in the kernel mode,
+    // either by sysret or by iret.
+    if ((sc.rip as i64) << 16 >> 16) as u64 != sc.rip {
+        return Err(LinuxError::EFAULT);
+    }
+    tf.r8 = sc.r8;
+    tf.r9 = sc.r9;
+    tf.r10 = sc.r10;
+    tf.r11 = sc.r11;
+    tf.r12 = sc.r12;
+    tf.r13 = sc.r13;
+    tf.r14 = sc.r14;
+    tf.r15 = sc.r15;
+    tf.rdi = sc.rdi;
+    tf.rsi = sc.rsi;
+    tf.rbp = sc.rbp;
+    tf.rbx = sc.rbx;
+    tf.rdx = sc.rdx;
+    tf.rax = sc.rax;
+    tf.rcx = sc.rcx;
+    tf.rsp = sc.rsp;
+    tf.rip = sc.rip;
+    // cs and ss are kept, user can't change the privilege level.
+    tf.rflags = (tf.rflags & !FIX_EFLAGS) | (sc.eflags & FIX_EFLAGS);
+    Ok(())
+}
+
+/// Saves the FP/SIMD registers of the CPU, which are still the user's.
+///
+/// The kernel doesn't set CR4.OSXSAVE, so user can't enable any state
+/// beyond x87 and SSE, and the 512-byte fxsave area holds all of it. Its
+/// software reserved bytes are left zero, so there is no FP_XSTATE_MAGIC1
+/// and no UC_FP_XSTATE, just like Linux on a CPU without XSAVE.
+fn save_fpstate(fp_addr: usize) -> LinuxResult {
+    let mut fpstate = FxsaveArea::new();
+    fpstate.save();
+    put_user(fpstate, fp_addr)
+}
+
+/// Restores the FP/SIMD registers. The initial state is used if there's
+/// no saved one.
+fn restore_fpstate(fp_addr: usize) -> LinuxResult {
+    let mut fpstate = if fp_addr != 0 {
+        get_user::<FxsaveArea>(fp_addr)?
+    } else {
+        FxsaveArea::new()
+    };
+    fpstate.mxcsr &= MXCSR_MASK;
+    fpstate.restore();
+    Ok(())
+}
+
+fn setup_sigcontext(sc: &mut SigContext, tf: &TrapFrame, mask: usize) {
+    sc.r8 = tf.r8;
+    sc.r9 = tf.r9;
+    sc.r10 = tf.r10;
+    sc.r11 = tf.r11;
+    sc.r12 = tf.r12;
+    sc.r13 = tf.r13;
+    sc.r14 = tf.r14;
+    sc.r15 = tf.r15;
+    sc.rdi = tf.rdi;
+    sc.rsi = tf.rsi;
+    sc.rbp = tf.rbp;
+    sc.rbx = tf.rbx;
+    sc.rdx = tf.rdx;
+    sc.rax = tf.rax;
+    sc.rcx = tf.rcx;
+    sc.rsp = tf.rsp;
+    sc.rip = tf.rip;
+    sc.eflags = tf.rflags;
+    sc.cs = tf.cs as u16;
+    sc.ss = tf.ss as u16;
+    sc.err = tf.error_code;
+    sc.trapno = tf.vector;
+    sc.oldmask = mask as u64;
+}
+
+/// Returns addresses of the frame and the FP state which is above it.
+fn get_sigframe(ksig: &KSignal, tf: &TrapFrame) -> (usize, usize) {
+    let onsigstack = on_sig_stack(tf.rsp as usize);
+    // A bad sp from user wraps around, and the copy will fail.
+    let sp = sigsp((tf.rsp as usize).wrapping_sub(RED_ZONE_SIZE), ksig);
+    let fp_addr = align_down(sp.wrapping_sub(core::mem::size_of::<FxsaveArea>()), 64);
+    let sp = fp_addr.wrapping_sub(SIGFRAME_SIZE);
+    // Align the stack frame just like the handler is called,
+    // i.e., (rsp + 8) is aligned to 16 on entry.
+    let sp = align_down(sp, 16).wrapping_sub(core::mem::size_of::<usize>());
+    // Overflow on the alternate signal stack. Return a bad address
+    // so that the frame can't be copied and we get a SIGSEGV.
+    if onsigstack && !on_sig_stack(sp) {
+        return (usize::MAX, usize::MAX);
+    }
+    (sp, fp_addr)
+}
+
+pub fn handle_signal(ksig: &KSignal, tf: &mut TrapFrame) {
+    // x86_64 has no vdso, so the handler must return to sa_restorer.
+    if (ksig.action.flags & SA_RESTORER) == 0 {
+        warn!("no sa_restorer for signo {}", ksig.signo);
+        sys::do_group_exit(SIGSEGV as u32);
+    }
+
+    let (frame_addr, fp_addr) = get_sigframe(ksig, tf);
+    let mut frame: RTSigFrame = unsafe { MaybeUninit::zeroed().assume_init() };
+    frame.pretcode = ksig.action.restorer;
+    frame.info = user_siginfo(&ksig.info);
+    save_altstack(&mut frame.uc.stack, tf.rsp as usize);
+    let mask = sigmask_to_save();
+    setup_sigcontext(&mut frame.uc.mcontext, tf, mask);
+    frame.uc.mcontext.fpstate = fp_addr as u64;
+    frame.uc.flags = UC_SIGCONTEXT_SS | UC_STRICT_RESTORE_SS;
+    frame.uc.sigmask = mask;
+
+    let buf = unsafe {
+        core::slice::from_raw_parts(&frame as *const RTSigFrame as *const u8, SIGFRAME_SIZE)
+    };
+    if copy_to_user(frame_addr, buf).is_err() || save_fpstate(fp_addr).is_err() {
+        // The signal can't be delivered, so kill the task just as
+        // Linux does in force_sigsegv.
+        warn!("bad sigframe {:#X} for signo {}", frame_addr, ksig.signo);
+        sys::do_group_exit(SIGSEGV as u32);
+    }
+    signal_delivered(ksig);
+
+    let base = &frame as *const RTSigFrame as usize;
+    let offset = |field: usize| (frame_addr + (field - base)) as u64;
+
+    assert!(ksig.action.handler != 0);
+    tf.rip = ksig.action.handler as u64;
+    tf.rsp = frame_addr as u64;
+    tf.rdi = ksig.signo as u64;     // rdi: signal number
+    // The handler with SA_SIGINFO takes the two more arguments,
+    // and they're harmless for the one without it.
+    tf.rsi = offset(&frame.info as *const UserSigInfo as usize);    // rsi: siginfo pointer
+    tf.rdx = offset(&frame.uc as *const UContext as usize);         // rdx: ucontext pointer
+    tf.rax = 0;
+    // The handler starts with a clean direction flag and no single-step.
+    tf.rflags &= !(X86_EFLAGS_DF | X86_EFLAGS_TF);
+
+    info!("handle_signal signo {} frame {:#X} tf.rip {:#x}",
+          ksig.signo, frame_addr, tf.rip);
 }
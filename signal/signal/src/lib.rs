@@ -1,100 +1,539 @@
 #![no_std]
 
+extern crate alloc;
+
 #[macro_use]
 extern crate log;
 
 mod arch;
 pub use arch::rt_sigreturn;
+use arch::current_user_stack_pointer;
 
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
 use taskctx::Tid;
-use task::{SigInfo, SigAction, SA_RESTORER, SA_RESTART};
-use axerrno::LinuxResult;
-use task::{SIGKILL, SIGSTOP};
+use task::{TaskStruct, SigInfo, SigAction, SigPending, SigAltStack, NSIG};
+use task::{SA_NODEFER, SA_RESETHAND, SA_ONSTACK, SA_NOCLDSTOP, SA_RESTART};
+use axerrno::{LinuxResult, LinuxError, linux_err, linux_err_from};
+use uaccess::{get_user, put_user, Pod};
+use task::{SIGRTMIN, SIGKILL, SIGSTOP, SIGCONT, SIGTSTP, SIGTTIN, SIGTTOU};
+use task::{SIGQUIT, SIGILL, SIGTRAP, SIGABRT, SIGBUS, SIGFPE, SIGSEGV};
+use task::{SIGXCPU, SIGXFSZ, SIGSYS, SIGCHLD, SIGURG, SIGWINCH};
 use axhal::arch::TrapFrame;
+use axhal::arch::sysno::LINUX_SYSCALL_RT_SIGRETURN;
+use axhal::time::{current_time, NANOS_PER_SEC};
 
 /// si_code values
 /// Digital reserves positive values for kernel-generated signals.
 
 // sent by kill, sigsend, raise
 const SI_USER: usize = 0;
+// sent by tkill system call
+const SI_TKILL: usize = -6isize as usize;
+// sent by the kernel from somewhere
+pub const SI_KERNEL: usize = 0x80;
+
+// SIGILL si_codes
+// illegal opcode
+pub const ILL_ILLOPC: usize = 1;
+// illegal operand
+pub const ILL_ILLOPN: usize = 2;
+
+// SIGFPE si_codes
+// integer divide by zero
+pub const FPE_INTDIV: usize = 1;
+// floating point divide by zero
+pub const FPE_FLTDIV: usize = 3;
+// floating point overflow
+pub const FPE_FLTOVF: usize = 4;
+// floating point underflow
+pub const FPE_FLTUND: usize = 5;
+// floating point inexact result
+pub const FPE_FLTRES: usize = 6;
+// floating point invalid operation
+pub const FPE_FLTINV: usize = 7;
+
+// SIGSEGV si_codes
+// address not mapped to object
+pub const SEGV_MAPERR: usize = 1;
+// invalid permissions for mapped object
+pub const SEGV_ACCERR: usize = 2;
+
+// SIGBUS si_codes
+// invalid address alignment
+pub const BUS_ADRALN: usize = 1;
+// non-existent physical address
+pub const BUS_ADRERR: usize = 2;
+
+// SIGCHLD si_codes
+// child has stopped
+const CLD_STOPPED: i32 = 5;
+// stopped child has continued
+const CLD_CONTINUED: i32 = 6;
+
+// default signal handling
+const SIG_DFL: usize = 0;
+// ignore signal
+const SIG_IGN: usize = 1;
+
+// Set in the wait status if a core dump is written.
+const WCOREFLAG: u32 = 0x80;
 
-#[derive(Clone)]
-struct UContext {
-    _flags: usize,
-    _stack: usize,
-    _sigmask: usize,
-    mcontext: TrapFrame,
+// Signals whose default action is to stop the task.
+const SIG_KERNEL_STOP_MASK: usize =
+    sigmask(SIGSTOP) | sigmask(SIGTSTP) | sigmask(SIGTTIN) | sigmask(SIGTTOU);
+
+/// Default actions for signals (see signal(7)).
+#[derive(PartialEq)]
+enum SigDefault {
+    /// Terminate the process.
+    Term,
+    /// Terminate the process and dump core.
+    Core,
+    /// Stop the process.
+    Stop,
+    /// Continue the process if it's stopped.
+    Cont,
+    /// Ignore the signal.
+    Ign,
+}
+
+fn sig_default_action(sig: usize) -> SigDefault {
+    match sig {
+        SIGCHLD | SIGURG | SIGWINCH => SigDefault::Ign,
+        SIGCONT => SigDefault::Cont,
+        SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => SigDefault::Stop,
+        SIGQUIT | SIGILL | SIGTRAP | SIGABRT | SIGBUS | SIGFPE |
+        SIGSEGV | SIGXCPU | SIGXFSZ | SIGSYS => SigDefault::Core,
+        _ => SigDefault::Term,
+    }
 }
 
+// ss_flags of sigaltstack
+const SS_ONSTACK: usize = 1;
+const SS_DISABLE: usize = 2;
+// Disable the alternate stack when a handler runs on it.
+const SS_AUTODISARM: usize = 1 << 31;
+const SS_FLAG_BITS: usize = SS_AUTODISARM;
+
+// Minimal size of an alternate signal stack.
+const MINSIGSTKSZ: usize = 2048;
+
+// 'how' of rt_sigprocmask
+const SIG_BLOCK: usize = 0;
+const SIG_UNBLOCK: usize = 1;
+const SIG_SETMASK: usize = 2;
+
+/// siginfo_t in user space.
 #[repr(C)]
-#[derive(Clone)]
-struct RTSigFrame {
-    info: SigInfo,
-    uc: UContext,
-    sigreturn_code: usize,
+#[derive(Clone, Copy)]
+struct UserSigInfo {
+    si_signo: i32,
+    si_errno: i32,
+    si_code: i32,
+    _pad: i32,
+    /// Union of fields, e.g., pid for kill and addr for faults.
+    fields: [usize; 14],
+}
+
+unsafe impl Pod for UserSigInfo {}
+
+/// stack_t in user space.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UserSigAltStack {
+    ss_sp: usize,
+    ss_flags: i32,
+    _pad: i32,
+    ss_size: usize,
+}
+
+unsafe impl Pod for UserSigAltStack {}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Timespec {
+    tv_sec: i64,
+    tv_nsec: i64,
 }
 
-pub const SIGFRAME_SIZE: usize = core::mem::size_of::<RTSigFrame>();
+unsafe impl Pod for Timespec {}
 
 struct KSignal {
     action: SigAction,
-    _info: SigInfo,
+    info: SigInfo,
     signo: usize,
 }
 
-//#define SI_KERNEL   0x80        /* sent by the kernel from somewhere */
 //#define SI_QUEUE    -1      /* sent by sigqueue */
 //#define SI_TIMER    -2      /* sent by timer expiration */
 //#define SI_MESGQ    -3      /* sent by real time mesq state change */
 //#define SI_ASYNCIO  -4      /* sent by AIO completion */
 //#define SI_SIGIO    -5      /* sent by queued SIGIO */
-//#define SI_TKILL    -6      /* sent by tkill system call */
 //#define SI_DETHREAD -7      /* sent by execve() killing subsidiary threads */
 //#define SI_ASYNCNL  -60     /* sent by glibc async name lookup completion */
 //
 //#define SI_FROMUSER(siptr)  ((siptr)->si_code <= 0)
 //#define SI_FROMKERNEL(siptr)    ((siptr)->si_code > 0)
 
-pub fn kill(tid: Tid, sig: usize) -> usize {
-    info!("kill tid {} sig {}", tid, sig);
-    assert!(tid > 0);
-    let info = prepare_kill_siginfo(sig, tid);
-    kill_proc_info(sig, info, tid).unwrap();
+/// Sends a signal to a process (pid > 0), the process group of current
+/// (pid == 0), all processes except init and current (pid == -1), or the
+/// process group -pid (pid < -1).
+pub fn kill(pid: usize, sig: usize) -> usize {
+    let pid = pid as isize;
+    info!("kill pid {} sig {}", pid, sig);
+    if sig > NSIG {
+        return linux_err!(EINVAL);
+    }
+    let info = prepare_kill_siginfo(sig, SI_USER);
+    let ret = match pid {
+        0 => kill_pgrp_info(sig, &info, task::current().pgid()),
+        -1 => kill_all_info(sig, &info),
+        pid if pid < 0 => kill_pgrp_info(sig, &info, -pid as usize),
+        pid => kill_pid_info(sig, &info, pid as Tid),
+    };
+    match ret {
+        Ok(_) => 0,
+        Err(e) => linux_err_from!(e),
+    }
+}
+
+/// Sends a signal to the thread `tid`.
+pub fn tkill(tid: usize, sig: usize) -> usize {
+    info!("tkill tid {} sig {}", tid, sig);
+    if (tid as isize) <= 0 {
+        return linux_err!(EINVAL);
+    }
+    do_tkill(0, tid, sig)
+}
+
+/// Sends a signal to the thread `tid` in the thread group `tgid`.
+pub fn tgkill(tgid: usize, tid: usize, sig: usize) -> usize {
+    info!("tgkill tgid {} tid {} sig {}", tgid, tid, sig);
+    if (tgid as isize) <= 0 || (tid as isize) <= 0 {
+        return linux_err!(EINVAL);
+    }
+    do_tkill(tgid, tid, sig)
+}
+
+fn do_tkill(tgid: Tid, tid: Tid, sig: usize) -> usize {
+    if sig > NSIG {
+        return linux_err!(EINVAL);
+    }
+    let task = match task::get_task(tid) {
+        Some(task) if (tgid == 0 || task.tgid() == tgid) && !task_exited(&task) => task,
+        _ => return linux_err!(ESRCH),
+    };
+    if sig != 0 {
+        send_signal(sig, prepare_kill_siginfo(sig, SI_TKILL), &task);
+    }
     0
 }
 
-pub fn prepare_kill_siginfo(sig: usize, tid: Tid) -> SigInfo {
+/// Signals sent by user carry the pid of the sender.
+pub fn prepare_kill_siginfo(sig: usize, code: usize) -> SigInfo {
     SigInfo {
         signo: sig as i32,
         errno: 0,
-        code: SI_USER as i32,
-        tid: tid,
+        code: code as i32,
+        tid: taskctx::current_ctx().tgid(),
+        addr: 0,
+        status: 0,
     }
 }
 
-fn kill_proc_info(sig: usize, info: SigInfo, tid: Tid) -> LinuxResult {
-    assert!(tid > 0);
-    if sig != 0 {
-        do_send_sig_info(sig, info, tid)
-    } else {
-        Ok(())
+#[inline]
+fn task_exited(task: &TaskStruct) -> bool {
+    task.exit_state.load(Ordering::Acquire) != 0
+}
+
+/// Returns the tgids of the user processes which match `filter`.
+fn processes(filter: impl Fn(&TaskStruct) -> bool) -> Vec<Tid> {
+    task::all_tasks()
+        .into_iter()
+        .filter(|t| t.tid() == t.tgid() && t.mm.is_some() && filter(t))
+        .map(|t| t.tgid())
+        .collect()
+}
+
+fn kill_pid_info(sig: usize, info: &SigInfo, pid: Tid) -> LinuxResult {
+    let task = task::get_task(pid).ok_or(LinuxError::ESRCH)?;
+    group_send_sig_info(sig, info, task.tgid())
+}
+
+/// Sends the signal to every process in the group. It succeeds if
+/// the signal is sent to any one of them.
+fn kill_pgrp_info(sig: usize, info: &SigInfo, pgid: usize) -> LinuxResult {
+    send_to_processes(sig, info, processes(|t| t.pgid() == pgid))
+}
+
+fn kill_all_info(sig: usize, info: &SigInfo) -> LinuxResult {
+    let current = taskctx::current_ctx().tgid();
+    send_to_processes(sig, info, processes(|t| t.tgid() > 1 && t.tgid() != current))
+}
+
+fn send_to_processes(sig: usize, info: &SigInfo, tgids: Vec<Tid>) -> LinuxResult {
+    let mut ret = Err(LinuxError::ESRCH);
+    for tgid in tgids {
+        match group_send_sig_info(sig, info, tgid) {
+            Ok(_) => ret = Ok(()),
+            Err(e) if ret.is_err() => ret = Err(e),
+            Err(_) => (),
+        }
     }
+    ret
 }
 
-fn do_send_sig_info(sig: usize, info: SigInfo, tid: Tid) -> LinuxResult {
-    let task = task::get_task(tid).unwrap();
-    let mut pending = task.sigpending.lock();
-    pending.list.push(info);
-    sigaddset(&mut pending.signal, sig);
-    info!("do_send_sig_info tid {} sig {} ok!", tid, sig);
+/// Sends a signal to the thread group `tgid`. It's delivered to the
+/// first thread which doesn't block it, or the leader if all block it.
+fn group_send_sig_info(sig: usize, info: &SigInfo, tgid: Tid) -> LinuxResult {
+    let threads: Vec<_> = task::all_tasks()
+        .into_iter()
+        .filter(|t| t.tgid() == tgid)
+        .collect();
+    if threads.is_empty() {
+        return Err(LinuxError::ESRCH);
+    }
+    if sig == 0 {
+        return Ok(());
+    }
+
+    let live = || threads.iter().filter(|t| !task_exited(t));
+    let target = live()
+        .find(|t| !sigismember(t.blocked.load(Ordering::Acquire), sig))
+        .or_else(|| live().next());
+    // Nothing to do for a zombie process.
+    if let Some(target) = target {
+        send_signal(sig, info.clone(), target);
+    }
     Ok(())
 }
 
+/// Queues the signal to the thread and wakes it up.
+fn send_signal(sig: usize, info: SigInfo, task: &TaskStruct) {
+    // Job control signals take effect on all threads in the group.
+    if sig == SIGCONT || (SIG_KERNEL_STOP_MASK & sigmask(sig)) != 0 {
+        let threads = task::all_tasks()
+            .into_iter()
+            .filter(|t| t.tgid() == task.tgid());
+        for t in threads {
+            prepare_signal(&t, &mut t.sigpending.lock(), sig);
+            if sig == SIGCONT {
+                t.signal_wait.notify_one(false);
+            }
+        }
+    }
+
+    {
+        let mut pending = task.sigpending.lock();
+        // Standard signals are not queued again if one is pending.
+        if sig < SIGRTMIN && sigismember(pending.signal, sig) {
+            info!("send_signal tid {} sig {} already pending", task.tid(), sig);
+            return;
+        }
+        pending.list.push(info);
+        sigaddset(&mut pending.signal, sig);
+    }
+    // SIGCONT must wake up the stopped task even if it's blocked.
+    if sig == SIGCONT || !sigismember(task.blocked.load(Ordering::Acquire), sig) {
+        task.signal_wait.notify_one(false);
+    }
+    info!("send_signal tid {} sig {} ok!", task.tid(), sig);
+}
+
+/// Handles job control signals at the time they're sent.
+/// SIGCONT resumes the stopped task and discards pending stop signals,
+/// and a stop signal discards pending SIGCONT.
+fn prepare_signal(task: &TaskStruct, pending: &mut SigPending, sig: usize) {
+    if (SIG_KERNEL_STOP_MASK & sigmask(sig)) != 0 {
+        flush_sigqueue_mask(pending, sigmask(SIGCONT));
+    } else if sig == SIGCONT {
+        flush_sigqueue_mask(pending, SIG_KERNEL_STOP_MASK);
+        {
+            let mut jobctl = task.jobctl.lock();
+            if jobctl.group_stop != 0 {
+                jobctl.group_stop = 0;
+                jobctl.group_stop_count = 0;
+                jobctl.stop_signo = 0;
+                jobctl.continued = true;
+                jobctl.notify_continued = true;
+            }
+        }
+        task.stopped.store(0, Ordering::Release);
+    }
+}
+
+/// Removes the pending signals in `mask`.
+fn flush_sigqueue_mask(pending: &mut SigPending, mask: usize) {
+    if (pending.signal & mask) == 0 {
+        return;
+    }
+    sigdelsetmask(&mut pending.signal, mask);
+    pending.list.retain(|info| (mask & sigmask(info.signo as usize)) == 0);
+}
+
+/// Stops current thread until SIGCONT or SIGKILL comes.
+///
+/// The first thread which dequeues a stop signal starts a group stop,
+/// and the other threads take part in it when they get to get_signal.
+/// The last thread which stops tells the parent.
+fn do_signal_stop(task: &TaskStruct, signo: usize) {
+    info!("task {} is stopped by signal {}", task.tid(), signo);
+    let mut others = Vec::new();
+    let notify = {
+        let mut jobctl = task.jobctl.lock();
+        if jobctl.group_stop == 0 {
+            let threads: Vec<_> = task::all_tasks()
+                .into_iter()
+                .filter(|t| t.tgid() == task.tgid() && !task_exited(t))
+                .collect();
+            jobctl.group_stop = signo;
+            jobctl.group_stop_count = threads.len();
+            for t in threads {
+                t.stopped.store(signo, Ordering::Release);
+                if t.tid() != task.tid() {
+                    others.push(t);
+                }
+            }
+        }
+        jobctl.group_stop_count = jobctl.group_stop_count.saturating_sub(1);
+        if jobctl.group_stop_count == 0 {
+            jobctl.stop_signo = jobctl.group_stop;
+            jobctl.continued = false;
+            true
+        } else {
+            false
+        }
+    };
+    // Kick the others out of waits, so they come to stop.
+    for t in others {
+        t.signal_wait.notify_one(false);
+    }
+    if notify {
+        do_notify_parent_cldstop(task, CLD_STOPPED, signo);
+    }
+
+    task.signal_wait.wait_until(|| {
+        task.stopped.load(Ordering::Acquire) == 0 ||
+            task.group_exit.lock().exiting ||
+            sigismember(task.sigpending.lock().signal, SIGKILL)
+    });
+    // Leave the group stop to handle SIGKILL.
+    task.stopped.store(0, Ordering::Release);
+    info!("task {} continues", task.tid());
+}
+
+/// Tells the parent that the thread group is stopped or continued.
+/// SIGCHLD is sent unless the parent sets SA_NOCLDSTOP for it, and
+/// the parent is woken up if it's in wait.
+fn do_notify_parent_cldstop(task: &TaskStruct, why: i32, signo: usize) {
+    let parent = match task::get_task(task.sched_info.ppid()) {
+        Some(parent) => parent,
+        None => return,
+    };
+
+    let flags = parent.sighand.lock().action[SIGCHLD - 1].flags;
+    if (flags & SA_NOCLDSTOP) == 0 {
+        let info = SigInfo {
+            signo: SIGCHLD as i32,
+            errno: 0,
+            code: why,
+            tid: task.tgid(),
+            addr: 0,
+            status: signo as i32,
+        };
+        let _ = group_send_sig_info(SIGCHLD, &info, parent.tgid());
+    }
+    parent.wait_chldexit.notify_all(false);
+}
+
+/// Dequeues a pending signal which is not in `mask`.
+/// The signal with the lowest number is dequeued at first.
+fn dequeue_signal(task: &TaskStruct, mask: usize) -> Option<SigInfo> {
+    let mut pending = task.sigpending.lock();
+    let avail = pending.signal & !mask;
+    if avail == 0 {
+        return None;
+    }
+    let sig = avail.trailing_zeros() as usize + 1;
+    let index = pending.list.iter().position(|info| info.signo as usize == sig)?;
+    let info = pending.list.remove(index);
+    if !pending.list.iter().any(|info| info.signo as usize == sig) {
+        sigdelsetmask(&mut pending.signal, sigmask(sig));
+    }
+    Some(info)
+}
+
+/// Sets blocked signals of current thread.
+/// SIGKILL and SIGSTOP can't be blocked.
+fn set_current_blocked(mut set: usize) {
+    sigdelsetmask(&mut set, sigmask(SIGKILL) | sigmask(SIGSTOP));
+    task::current().blocked.store(set, Ordering::Release);
+}
+
+/// Returns the mask which is saved in the signal frame.
+/// It's the one before rt_sigsuspend if there is.
+fn sigmask_to_save() -> usize {
+    let task = task::current();
+    let saved = task.saved_sigmask.lock().take();
+    saved.unwrap_or_else(|| task.blocked.load(Ordering::Acquire))
+}
+
+/// Blocks sa_mask and the signal itself (unless SA_NODEFER)
+/// during the handler runs.
+fn signal_delivered(ksig: &KSignal) {
+    let mut blocked = task::current().blocked.load(Ordering::Acquire);
+    blocked |= ksig.action.mask;
+    if (ksig.action.flags & SA_NODEFER) == 0 {
+        sigaddset(&mut blocked, ksig.signo);
+    }
+    set_current_blocked(blocked);
+}
+
+/// Restores the mask which is saved by rt_sigsuspend.
+fn restore_saved_sigmask() {
+    let saved = task::current().saved_sigmask.lock().take();
+    if let Some(saved) = saved {
+        set_current_blocked(saved);
+    }
+}
+
+fn user_siginfo(info: &SigInfo) -> UserSigInfo {
+    let signo = info.signo as usize;
+    let mut fields = [0; 14];
+    fields[0] = if signo == SIGSEGV || signo == SIGBUS {
+        info.addr
+    } else {
+        info.tid
+    };
+    if signo == SIGCHLD {
+        // si_status follows si_pid and si_uid.
+        fields[1] = info.status as u32 as usize;
+    }
+    UserSigInfo {
+        si_signo: info.signo,
+        si_errno: info.errno,
+        si_code: info.code,
+        _pad: 0,
+        fields,
+    }
+}
+
+fn copy_siginfo_to_user(uinfo: usize, info: &SigInfo) -> LinuxResult {
+    put_user(user_siginfo(info), uinfo)
+}
+
 #[inline]
-fn sigmask(sig: usize) -> usize {
+const fn sigmask(sig: usize) -> usize {
     1 << (sig - 1)
 }
 
+#[inline]
+fn sigismember(set: usize, sig: usize) -> bool {
+    (set & sigmask(sig)) != 0
+}
+
 #[inline]
 fn sigaddset(set: &mut usize, sig: usize) {
     *set |= 1 << (sig - 1);
@@ -106,74 +545,445 @@ fn sigdelsetmask(set: &mut usize, mask: usize) {
 }
 
 pub fn rt_sigaction(sig: usize, act: usize, oact: usize, sigsetsize: usize) -> usize {
-    assert_eq!(sigsetsize, 8);
     info!("rt_sigaction: sig {} act {:#X} oact {:#X}", sig, act, oact);
-    assert!(act != 0);
+    match do_sigaction(sig, act, oact, sigsetsize) {
+        Ok(_) => 0,
+        Err(e) => linux_err_from!(e),
+    }
+}
+
+fn do_sigaction(sig: usize, act: usize, oact: usize, sigsetsize: usize) -> LinuxResult {
+    if sigsetsize != core::mem::size_of::<usize>() {
+        return Err(LinuxError::EINVAL);
+    }
+    if sig == 0 || sig > NSIG {
+        return Err(LinuxError::EINVAL);
+    }
+    // SIGKILL and SIGSTOP can't be caught or ignored.
+    if act != 0 && (sig == SIGKILL || sig == SIGSTOP) {
+        return Err(LinuxError::EINVAL);
+    }
+
+    // Read the new one before writing the old one,
+    // because 'act' and 'oact' may be the same.
+    let new: Option<SigAction> = if act != 0 {
+        Some(get_user(act)?)
+    } else {
+        None
+    };
 
     let task = task::current();
+    let old = {
+        let mut sighand = task.sighand.lock();
+        let old = sighand.action[sig - 1];
+        if let Some(mut kact) = new {
+            info!("act: {:#X} {:#X} {:#X}", kact.handler, kact.flags, kact.mask);
+            sigdelsetmask(&mut kact.mask, sigmask(SIGKILL) | sigmask(SIGSTOP));
+            sighand.action[sig - 1] = kact;
+        }
+        old
+    };
+
+    // Pending signals are discarded if they're to be ignored.
+    if let Some(kact) = new {
+        if kact.handler == SIG_IGN ||
+            (kact.handler == SIG_DFL && sig_default_action(sig) == SigDefault::Ign) {
+            flush_sigqueue_mask(&mut task.sigpending.lock(), sigmask(sig));
+        }
+    }
 
     if oact != 0 {
-        let oact = oact as *mut SigAction;
-        unsafe {
-            *oact = task.sighand.lock().action[sig - 1];
+        put_user(old, oact)?;
+    }
+    Ok(())
+}
+
+/// Resets caught signals to the default on execve, since the handlers
+/// are gone with the old image. Ignored signals are still ignored.
+/// The alternate signal stack is disabled as well.
+pub fn flush_signal_handlers(task: &TaskStruct) {
+    for action in task.sighand.lock().action.iter_mut() {
+        if action.handler != SIG_IGN {
+            action.handler = SIG_DFL;
+        }
+        *action = SigAction {
+            handler: action.handler,
+            ..Default::default()
+        };
+    }
+    *task.sas_stack.lock() = SigAltStack::default();
+}
+
+/// Sets and/or gets the alternate signal stack.
+pub fn sigaltstack(uss: usize, uoss: usize) -> usize {
+    info!("sigaltstack: ss {:#X} oss {:#X}", uss, uoss);
+    let ss: Option<UserSigAltStack> = if uss != 0 {
+        match get_user(uss) {
+            Ok(ss) => Some(ss),
+            Err(e) => return linux_err_from!(e),
+        }
+    } else {
+        None
+    };
+    let old = match do_sigaltstack(ss.as_ref(), current_user_stack_pointer()) {
+        Ok(old) => old,
+        Err(e) => return linux_err_from!(e),
+    };
+    if uoss != 0 {
+        if let Err(e) = put_user(old, uoss) {
+            return linux_err_from!(e);
         }
     }
+    0
+}
+
+fn do_sigaltstack(ss: Option<&UserSigAltStack>, sp: usize) -> LinuxResult<UserSigAltStack> {
+    let task = task::current();
+    let cur = *task.sas_stack.lock();
+    let old = UserSigAltStack {
+        ss_sp: cur.sp,
+        ss_flags: (sas_ss_flags(sp) | (cur.flags & SS_AUTODISARM)) as i32,
+        _pad: 0,
+        ss_size: cur.size,
+    };
+
+    if let Some(ss) = ss {
+        // The stack can't be changed while we're running on it.
+        if on_sig_stack(sp) {
+            return Err(LinuxError::EPERM);
+        }
+        let flags = ss.ss_flags as u32 as usize;
+        let mode = flags & !SS_FLAG_BITS;
+        if mode != SS_DISABLE && mode != SS_ONSTACK && mode != 0 {
+            return Err(LinuxError::EINVAL);
+        }
+        let new = if mode == SS_DISABLE {
+            SigAltStack::default()
+        } else {
+            if ss.ss_size < MINSIGSTKSZ {
+                return Err(LinuxError::ENOMEM);
+            }
+            SigAltStack {
+                sp: ss.ss_sp,
+                size: ss.ss_size,
+                flags: flags & SS_FLAG_BITS,
+            }
+        };
+        *task.sas_stack.lock() = new;
+    }
+    Ok(old)
+}
+
+/// Whether `sp` is on the alternate signal stack of current thread.
+fn on_sig_stack(sp: usize) -> bool {
+    let ss = *task::current().sas_stack.lock();
+    // With SS_AUTODISARM, the stack is disabled when a handler runs on it,
+    // so nested signals never see it.
+    if (ss.flags & SS_AUTODISARM) != 0 {
+        return false;
+    }
+    sp > ss.sp && sp - ss.sp <= ss.size
+}
+
+fn sas_ss_flags(sp: usize) -> usize {
+    if task::current().sas_stack.lock().size == 0 {
+        return SS_DISABLE;
+    }
+    if on_sig_stack(sp) {
+        SS_ONSTACK
+    } else {
+        0
+    }
+}
+
+/// Returns the stack pointer for the signal frame. It switches to
+/// the alternate stack for SA_ONSTACK if we're not on it yet.
+fn sigsp(sp: usize, ksig: &KSignal) -> usize {
+    if (ksig.action.flags & SA_ONSTACK) != 0 && sas_ss_flags(sp) == 0 {
+        let ss = *task::current().sas_stack.lock();
+        return ss.sp + ss.size;
+    }
+    sp
+}
+
+/// Saves the alternate stack in the signal frame.
+/// `sp` is the stack pointer before the signal is delivered.
+fn save_altstack(uss: &mut UserSigAltStack, sp: usize) {
+    let task = task::current();
+    let cur = *task.sas_stack.lock();
+    uss.ss_sp = cur.sp;
+    uss.ss_flags = (sas_ss_flags(sp) | (cur.flags & SS_AUTODISARM)) as i32;
+    uss.ss_size = cur.size;
+    if (cur.flags & SS_AUTODISARM) != 0 {
+        *task.sas_stack.lock() = SigAltStack::default();
+    }
+}
+
+/// Restores the alternate stack from the signal frame by rt_sigreturn.
+fn restore_altstack(uss: &UserSigAltStack) {
+    // Just as Linux, the stack is left unchanged if it's invalid.
+    let _ = do_sigaltstack(Some(uss), current_user_stack_pointer());
+}
+
+pub fn rt_sigprocmask(how: usize, nset: usize, oset: usize, sigsetsize: usize) -> usize {
+    info!("rt_sigprocmask: how {} nset {:#X} oset {:#X}", how, nset, oset);
+    if sigsetsize != core::mem::size_of::<usize>() {
+        return linux_err!(EINVAL);
+    }
 
-    if act != 0 {
-        let act = unsafe { &(*(act as *const SigAction)) };
-        info!("act: {:#X} {:#X} {:#X}", act.handler, act.flags, act.mask);
-        assert!((act.flags & SA_RESTART) != 0);
-        assert!((act.flags & SA_RESTORER) == 0);
+    let old = task::current().blocked.load(Ordering::Acquire);
+    if nset != 0 {
+        let set: usize = match get_user(nset) {
+            Ok(set) => set,
+            Err(e) => return linux_err_from!(e),
+        };
+        let new = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return linux_err!(EINVAL),
+        };
+        set_current_blocked(new);
+    }
 
-        let mut kact = act.clone();
-        sigdelsetmask(&mut kact.mask, sigmask(SIGKILL) | sigmask(SIGSTOP));
-        info!("get_signal signo {} handler {:#X}", sig, kact.handler);
-        task.sighand.lock().action[sig - 1] = kact;
+    if oset != 0 {
+        if let Err(e) = put_user(old, oset) {
+            return linux_err_from!(e);
+        }
     }
     0
 }
 
+/// Returns the signals which are pending but blocked.
+pub fn rt_sigpending(uset: usize, sigsetsize: usize) -> usize {
+    if sigsetsize > core::mem::size_of::<usize>() {
+        return linux_err!(EINVAL);
+    }
+    let task = task::current();
+    let set = task.sigpending.lock().signal & task.blocked.load(Ordering::Acquire);
+    if let Err(e) = put_user(set, uset) {
+        return linux_err_from!(e);
+    }
+    0
+}
+
+/// Replaces the mask temporarily and waits for a signal.
+/// The old mask is restored after the handler returns.
+pub fn rt_sigsuspend(unewset: usize, sigsetsize: usize) -> usize {
+    if sigsetsize != core::mem::size_of::<usize>() {
+        return linux_err!(EINVAL);
+    }
+    let newset: usize = match get_user(unewset) {
+        Ok(set) => set,
+        Err(e) => return linux_err_from!(e),
+    };
+
+    let task = task::current();
+    let old = task.blocked.load(Ordering::Acquire);
+    set_current_blocked(newset);
+    task.signal_wait.wait_until(|| task.signal_pending());
+    *task.saved_sigmask.lock() = Some(old);
+    linux_err!(ERESTARTNOHAND)
+}
+
+pub fn rt_sigtimedwait(uthese: usize, uinfo: usize, uts: usize, sigsetsize: usize) -> usize {
+    info!("rt_sigtimedwait: these {:#X} info {:#X} ts {:#X}", uthese, uinfo, uts);
+    match do_sigtimedwait(uthese, uinfo, uts, sigsetsize) {
+        Ok(signo) => signo,
+        Err(e) => linux_err_from!(e),
+    }
+}
+
+fn do_sigtimedwait(uthese: usize, uinfo: usize, uts: usize, sigsetsize: usize) -> LinuxResult<usize> {
+    if sigsetsize != core::mem::size_of::<usize>() {
+        return Err(LinuxError::EINVAL);
+    }
+    let mut these: usize = get_user(uthese)?;
+    sigdelsetmask(&mut these, sigmask(SIGKILL) | sigmask(SIGSTOP));
+
+    let deadline = if uts != 0 {
+        let ts: Timespec = get_user(uts)?;
+        if ts.tv_sec < 0 || ts.tv_nsec < 0 || ts.tv_nsec >= NANOS_PER_SEC as i64 {
+            return Err(LinuxError::EINVAL);
+        }
+        Some(current_time() + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+    } else {
+        None
+    };
+
+    let task = task::current();
+    let info = match dequeue_signal(&task, !these) {
+        Some(info) => info,
+        None => {
+            // Unblock the signals we're waiting for, so that they can
+            // wake us up. Todo: No timer wakeup yet, see futex_wait.
+            let blocked = task.blocked.load(Ordering::Acquire);
+            task.blocked.store(blocked & !these, Ordering::Release);
+            let timed_out = || deadline.is_some_and(|deadline| current_time() >= deadline);
+            task.signal_wait.wait_until(|| task.signal_pending() || timed_out());
+            task.blocked.store(blocked, Ordering::Release);
+
+            match dequeue_signal(&task, !these) {
+                Some(info) => info,
+                None if timed_out() => return Err(LinuxError::EAGAIN),
+                // Never restarted regardless of SA_RESTART, see signal(7).
+                None => return Err(LinuxError::EINTR),
+            }
+        }
+    };
+    if uinfo != 0 {
+        copy_siginfo_to_user(uinfo, &info)?;
+    }
+    Ok(info.signo as usize)
+}
+
 pub fn do_signal(tf: &mut TrapFrame) {
+    do_signal_restart(tf, None);
+}
+
+/// Delivers pending signals on return from the syscall `sysno`, and
+/// restarts it if its result asks for that. `orig` is the register which
+/// the result has overwritten, i.e., a0 on riscv64 and rax on x86_64.
+pub fn do_signal_syscall(tf: &mut TrapFrame, sysno: usize, orig: usize) {
+    // rt_sigreturn returns the restored register rather than a result.
+    let orig = (sysno != LINUX_SYSCALL_RT_SIGRETURN).then_some(orig);
+    do_signal_restart(tf, orig);
+}
+
+fn do_signal_restart(tf: &mut TrapFrame, orig: Option<usize>) {
     info!("do_signal ...");
     if let Some(ksig) = get_signal() {
+        if let Some(orig) = orig {
+            syscall_restart(tf, orig, Some(&ksig));
+        }
         /* Actually deliver the signal */
         arch::handle_signal(&ksig, tf);
         return;
     }
 
-    // Todo: handle 'regs->cause == EXC_SYSCALL';
+    // No handler runs, so the syscall can always be restarted.
+    if let Some(orig) = orig {
+        syscall_restart(tf, orig, None);
+    }
+
+    // No signal is delivered, so just put the saved sigmask back.
+    restore_saved_sigmask();
+}
+
+const ERESTARTSYS: usize = linux_err!(ERESTARTSYS);
+const ERESTARTNOINTR: usize = linux_err!(ERESTARTNOINTR);
+const ERESTARTNOHAND: usize = linux_err!(ERESTARTNOHAND);
+
+/// Fixes up a syscall which is interrupted by a signal. It's restarted
+/// with `orig` put back, or it fails with EINTR. `ksig` is the signal
+/// whose handler is going to run, if any.
+fn syscall_restart(tf: &mut TrapFrame, orig: usize, ksig: Option<&KSignal>) {
+    let restart = match arch::syscall_result(tf) {
+        ERESTARTNOHAND => ksig.is_none(),
+        ERESTARTSYS => ksig.map_or(true, |ksig| (ksig.action.flags & SA_RESTART) != 0),
+        ERESTARTNOINTR => true,
+        _ => return,
+    };
+    if restart {
+        arch::restart_syscall(tf, orig);
+    } else {
+        arch::set_syscall_result(tf, linux_err!(EINTR));
+    }
 }
 
 fn get_signal() -> Option<KSignal> {
     let task = task::current();
-    let _info = task.sigpending.lock().list.pop()?;
-    let signo = _info.signo as usize;
+    loop {
+        // Another thread has called exit_group or got a fatal signal,
+        // so just exit with the status of the group.
+        if task.group_exit.lock().exiting {
+            sys::do_group_exit(0);
+        }
 
-    let action = task.sighand.lock().action[signo - 1];
-    assert!(action.handler != 0);
-    info!("get_signal signo {} handler {:#X}", signo, action.handler);
-    Some(KSignal {action, _info, signo})
-}
+        // The first thread which runs after SIGCONT tells the parent.
+        let notify = core::mem::take(&mut task.jobctl.lock().notify_continued);
+        if notify {
+            do_notify_parent_cldstop(&task, CLD_CONTINUED, SIGCONT);
+        }
 
-fn restore_sigcontext(tf: &mut TrapFrame, frame: &RTSigFrame) {
-    *tf = frame.uc.mcontext.clone();
-    // Todo: Restore the floating-point state. */
-}
+        // Another thread has started a group stop.
+        let stop_signo = task.stopped.load(Ordering::Acquire);
+        if stop_signo != 0 {
+            do_signal_stop(&task, stop_signo);
+            continue;
+        }
+
+        let blocked = task.blocked.load(Ordering::Acquire);
+        let info = dequeue_signal(&task, blocked)?;
+        let signo = info.signo as usize;
 
-fn setup_sigcontext(frame: &mut RTSigFrame, tf: &TrapFrame) {
-    frame.uc.mcontext = tf.clone();
-    // Todo: Save the floating-point state.
+        let action = {
+            let mut sighand = task.sighand.lock();
+            let action = sighand.action[signo - 1];
+            // SA_RESETHAND: the handler is used only once.
+            if action.handler != SIG_IGN && action.handler != SIG_DFL &&
+                (action.flags & SA_RESETHAND) != 0 {
+                sighand.action[signo - 1].handler = SIG_DFL;
+            }
+            action
+        };
+        info!("get_signal signo {} handler {:#X}", signo, action.handler);
+        match action.handler {
+            SIG_IGN => continue,
+            SIG_DFL => {
+                match sig_default_action(signo) {
+                    SigDefault::Ign | SigDefault::Cont => continue,
+                    SigDefault::Stop => {
+                        do_signal_stop(&task, signo);
+                        continue;
+                    },
+                    SigDefault::Term => {
+                        // The wait status of a task killed by a signal
+                        // is the signo.
+                        sys::do_group_exit(signo as u32);
+                    },
+                    SigDefault::Core => {
+                        // Todo: write a real core file.
+                        warn!("task {} killed by signal {} (core dumped)",
+                              task.tid(), signo);
+                        sys::do_group_exit(signo as u32 | WCOREFLAG);
+                    },
+                }
+            },
+            _ => return Some(KSignal {action, info, signo}),
+        }
+    }
 }
 
-pub fn force_sig_fault(signo: usize, code: usize, _addr: usize) {
+/// Force a synchronous fault signal on current task.
+/// It can't be ignored or blocked, so in that case the handler is reset
+/// to default and the signal is unblocked.
+pub fn force_sig_fault(signo: usize, code: usize, addr: usize) {
     let tid = taskctx::current_ctx().tid();
     let info = SigInfo {
         signo: signo as i32,
         errno: 0,
         code: code as i32,
         tid: tid,
+        addr,
+        status: 0,
     };
 
+    {
+        let task = task::current();
+        let mut sighand = task.sighand.lock();
+        let blocked = task.blocked.load(Ordering::Acquire);
+        if sighand.action[signo - 1].handler == SIG_IGN || sigismember(blocked, signo) {
+            sighand.action[signo - 1].handler = SIG_DFL;
+            task.blocked.store(blocked & !sigmask(signo), Ordering::Release);
+        }
+    }
+
     info!("force tid {} sig {}", tid, signo);
-    do_send_sig_info(signo, info, tid).unwrap();
+    send_signal(signo, info, &task::current());
+}
+
+pub fn init() {
+    // Let sys send signals, which can't depend on us.
+    task::register_send_signal(|task, sig, info| send_signal(sig, info, task));
 }
@@ -1,19 +1,55 @@
 #![cfg_attr(not(test), no_std)]
 
+extern crate alloc;
+use alloc::vec::Vec;
 use core::sync::atomic::Ordering;
 use taskctx::Tid;
-use axtype::PAGE_SIZE;
-use axconfig::TASK_STACK_SIZE;
-#[cfg(target_arch = "x86_64")]
+use task::{RLimit64, RLIM_NLIMITS, SigInfo, TaskStruct, TaskRef, GroupExit};
+use task::{SigPending, SIGKILL, SIGCHLD, SIGCONT, SA_NOCLDWAIT};
+use filetable::FileTable;
 use axerrno::linux_err;
 use axerrno::{LinuxResult, LinuxError, linux_err_from};
 use taskctx::TaskState;
+use uaccess::{get_user, put_user, Pod};
 
 #[macro_use]
 extern crate log;
 
+// Options of wait4 and waitid
 const WNOHANG: usize = 0x00000001;
+const WUNTRACED: usize = 0x00000002;
+const WSTOPPED: usize = WUNTRACED;
 const WEXITED: usize = 0x00000004;
+const WCONTINUED: usize = 0x00000008;
+// Don't reap, just poll status.
+const WNOWAIT: usize = 0x01000000;
+// Don't wait on children of other threads in this group
+const __WNOTHREAD: usize = 0x20000000;
+// Wait on all children, regardless of type
+const __WALL: usize = 0x40000000;
+// Wait only on non-SIGCHLD children
+const __WCLONE: usize = 0x80000000;
+
+// idtype of waitid
+const P_ALL: usize = 0;
+const P_PID: usize = 1;
+const P_PGID: usize = 2;
+
+// si_code of SIGCHLD
+const CLD_EXITED: i32 = 1;
+const CLD_KILLED: i32 = 2;
+const CLD_DUMPED: i32 = 3;
+const CLD_STOPPED: i32 = 5;
+const CLD_CONTINUED: i32 = 6;
+
+// ignore signal
+const SIG_IGN: usize = 1;
+
+// si_code: sent by the kernel from somewhere
+const SI_KERNEL: i32 = 0x80;
+
+// Set in the wait status if a core dump is written.
+const WCOREFLAG: u32 = 0x80;
 
 // Used in tsk->exit_state:
 const EXIT_DEAD: usize = 0x0010;
@@ -24,20 +60,9 @@ const EXIT_TRACE: usize = EXIT_ZOMBIE | EXIT_DEAD;
 #[cfg(target_arch = "x86_64")]
 const ARCH_SET_FS: usize = 0x1002;
 
-const RLIMIT_STACK: usize = 3; /* max stack size */
-//const RLIM_NLIMITS: usize = 16;
-
-#[allow(dead_code)]
-struct RLimit64 {
-    rlim_cur: u64,
-    rlim_max: u64,
-}
-
-impl RLimit64 {
-    pub fn new(rlim_cur: u64, rlim_max: u64) -> Self {
-        Self { rlim_cur, rlim_max }
-    }
-}
+// Options of prctl
+const PR_SET_CHILD_SUBREAPER: usize = 36;
+const PR_GET_CHILD_SUBREAPER: usize = 37;
 
 #[allow(dead_code)]
 #[derive(Debug, PartialEq)]
@@ -49,6 +74,42 @@ enum PidType {
     MAX,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct TimeVal {
+    tv_sec: i64,
+    tv_usec: i64,
+}
+
+/// struct rusage
+/// Resource usage isn't accounted yet, so all of them are zero.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct RUsage {
+    ru_utime: TimeVal,
+    ru_stime: TimeVal,
+    /// From ru_maxrss to ru_nivcsw
+    _counters: [i64; 14],
+}
+
+unsafe impl Pod for RUsage {}
+
+/// siginfo_t with the fields of SIGCHLD, which is filled by waitid.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct WaitSigInfo {
+    si_signo: i32,
+    si_errno: i32,
+    si_code: i32,
+    _pad: i32,
+    si_pid: i32,
+    si_uid: u32,
+    si_status: i32,
+    _rest: [u8; 100],
+}
+
+unsafe impl Pod for WaitSigInfo {}
+
 pub fn gettid() -> usize {
     taskctx::current_ctx().tid()
 }
@@ -58,7 +119,7 @@ pub fn getpid() -> usize {
 }
 
 pub fn getppid() -> usize {
-    let ppid = taskctx::current_ctx().real_parent.as_ref().unwrap().tid();
+    let ppid = taskctx::current_ctx().ppid();
     info!("getppid: {}", ppid);
     ppid
 }
@@ -74,27 +135,50 @@ pub fn setpgid() -> usize {
 }
 
 pub fn prlimit64(tid: Tid, resource: usize, new_rlim: usize, old_rlim: usize) -> usize {
-    warn!(
+    info!(
         "linux_syscall_prlimit64: tid {}, resource: {}, {:?} {:?}",
         tid, resource, new_rlim, old_rlim
     );
 
-    assert!(tid == 0);
+    if resource >= RLIM_NLIMITS {
+        return linux_err!(EINVAL);
+    }
+    let task = if tid == 0 {
+        task::current().as_task_ref().clone()
+    } else {
+        match task::get_task(tid) {
+            Some(task) => task,
+            None => return linux_err!(ESRCH),
+        }
+    };
 
-    let old_rlim = old_rlim as *mut RLimit64;
+    let new_rlim = if new_rlim != 0 {
+        let new_rlim: RLimit64 = match get_user(new_rlim) {
+            Ok(rlim) => rlim,
+            Err(e) => return linux_err_from!(e),
+        };
+        if new_rlim.rlim_cur > new_rlim.rlim_max {
+            return linux_err!(EINVAL);
+        }
+        Some(new_rlim)
+    } else {
+        None
+    };
 
-    match resource {
-        RLIMIT_STACK => {
-            let stack_size = TASK_STACK_SIZE as u64;
-            unsafe {
-                *old_rlim = RLimit64::new(stack_size, stack_size);
-            }
-            0
+    let old = {
+        let mut rlimits = task.rlimits.lock();
+        let old = rlimits.rlim[resource];
+        if let Some(new_rlim) = new_rlim {
+            rlimits.rlim[resource] = new_rlim;
         }
-        _ => {
-            unimplemented!("Resource Type: {}", resource);
+        old
+    };
+    if old_rlim != 0 {
+        if let Err(e) = put_user(old, old_rlim) {
+            return linux_err_from!(e);
         }
     }
+    0
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -117,196 +201,665 @@ pub fn arch_prctl(code: usize, addr: usize) -> usize {
     }
 }
 
+pub fn prctl(option: usize, arg2: usize) -> usize {
+    info!("prctl: option {} arg2 {:#X}", option, arg2);
+    let task = task::current();
+    match option {
+        PR_SET_CHILD_SUBREAPER => {
+            task.child_subreaper.store(arg2 != 0, Ordering::Release);
+            0
+        },
+        PR_GET_CHILD_SUBREAPER => {
+            let subreaper = task.child_subreaper.load(Ordering::Acquire) as i32;
+            if let Err(e) = put_user(subreaper, arg2) {
+                return linux_err_from!(e);
+            }
+            0
+        },
+        _ => {
+            warn!("prctl option {} is not supported", option);
+            linux_err!(EINVAL)
+        }
+    }
+}
+
 pub fn wait4(pid: usize, wstatus: usize, options: usize, rusage: usize) -> usize {
-    let pid = pid as isize;
-    info!("wait4: pid {:#X} wstatus {:#X} options {:#X} rusage {:#X}",
+    let pid = pid as i32;
+    info!("wait4: pid {} wstatus {:#X} options {:#X} rusage {:#X}",
            pid, wstatus, options, rusage);
 
-    if rusage != 0 {
-        // Todo: deal with rusage in future.
-        warn!("+++ Handle rusage in wait4 +++");
-    }
-    if options != 0 {
-        // Todo: deal with options in future.
-        warn!("+++ Handle options in wait4 +++");
+    if (options & !(WNOHANG|WUNTRACED|WCONTINUED|__WNOTHREAD|__WCLONE|__WALL)) != 0 {
+        return linux_err!(EINVAL);
     }
-    assert!((options & WNOHANG) == 0);
 
-    let pid_type =
+    let (pid_type, id) =
         if pid == -1 {
-            PidType::MAX
+            (PidType::MAX, 0)
+        } else if pid == i32::MIN {
+            // -INT_MIN can't be a pgid.
+            return linux_err!(ESRCH);
         } else if pid < 0 {
-            //pid = find_get_pid(-pid);
-            PidType::PGID
+            (PidType::PGID, -pid as usize)
         } else if pid == 0 {
-            //pid = get_task_pid(current, PIDTYPE_PGID);
-            PidType::PGID
+            (PidType::PGID, task::current().pgid())
         } else /* pid > 0 */ {
-            PidType::PID
+            (PidType::PID, pid as usize)
         };
 
-    let mut status = 0u32;
-    let tid = match do_wait(pid_type, pid as usize, options|WEXITED, &mut status) {
-        Ok(tid) => tid,
-        Err(e) => linux_err_from!(e),
-        //Err(e) => panic!("do_wait err: {:?}", e),
+    let info = match do_wait(pid_type, id, options|WEXITED) {
+        Ok(Some(info)) => info,
+        // WNOHANG and no child is waitable.
+        Ok(None) => return 0,
+        Err(e) => return linux_err_from!(e),
     };
 
-    let wstatus = wstatus as *mut u32;
-    unsafe {
-        (*wstatus) = status;
+    if wstatus != 0 {
+        if let Err(e) = put_user(info.wait_status(), wstatus) {
+            return linux_err_from!(e);
+        }
+    }
+    if rusage != 0 {
+        if let Err(e) = put_user(RUsage::default(), rusage) {
+            return linux_err_from!(e);
+        }
     }
-    tid
+    info.pid
 }
 
-fn do_wait(
-    pid_type: PidType, tid: Tid, options: usize, status: &mut u32
-) -> LinuxResult<Tid> {
-    info!("do_wait: pidtype {:?} pid {:#X} options {:#X}; curr {}",
-          pid_type, tid, options, taskctx::current_ctx().tid());
+pub fn waitid(which: usize, upid: usize, infop: usize, options: usize, rusage: usize) -> usize {
+    let upid = upid as i32;
+    info!("waitid: which {} pid {} infop {:#X} options {:#X} rusage {:#X}",
+           which, upid, infop, options, rusage);
 
-    // Todo: sleep with intr
-    //set_current_state(TASK_INTERRUPTIBLE);
+    if (options & !(WNOHANG|WNOWAIT|WEXITED|WSTOPPED|WCONTINUED|
+                    __WNOTHREAD|__WCLONE|__WALL)) != 0 {
+        return linux_err!(EINVAL);
+    }
+    if (options & (WEXITED|WSTOPPED|WCONTINUED)) == 0 {
+        return linux_err!(EINVAL);
+    }
 
-    loop {
-        if pid_type == PidType::PID {
-            if let Some(tid) = wait_pid(tid, status) {
-                return Ok(tid);
+    let (pid_type, id) = match which {
+        P_ALL => (PidType::MAX, 0),
+        P_PID => {
+            if upid <= 0 {
+                return linux_err!(EINVAL);
             }
-        } else {
-            if children_count() > 0 {
-                if let Some(tid) = wait_children(status) {
-                    return Ok(tid);
-                }
+            (PidType::PID, upid as usize)
+        },
+        P_PGID => {
+            if upid < 0 {
+                return linux_err!(EINVAL);
             }
+            let pgid = if upid == 0 {
+                task::current().pgid()
+            } else {
+                upid as usize
+            };
+            (PidType::PGID, pgid)
+        },
+        // Todo: P_PIDFD
+        _ => return linux_err!(EINVAL),
+    };
 
-            if siblings_count() > 0 {
-                let ctx = taskctx::current_ctx();
-                for sibling in ctx.siblings.lock().iter() {
-                    panic!("sibling[{}]", sibling);
-                    // Todo: query tid_map to get sibling reference.
-                    /*
-                    for child in sibling.children.lock().iter() {
-                        info!("Current[{}]: has child[{}]",
-                              sibling.tid(), child);
-                    }
-                    */
-                }
-            }
+    let info = match do_wait(pid_type, id, options) {
+        Ok(info) => info,
+        Err(e) => return linux_err_from!(e),
+    };
 
-            if children_count() == 0 && siblings_count() == 0 {
-                return Err(LinuxError::ECHILD);
-            }
+    if infop != 0 {
+        // All fields are zero for WNOHANG if no child is waitable.
+        let mut siginfo = WaitSigInfo {
+            si_signo: 0,
+            si_errno: 0,
+            si_code: 0,
+            _pad: 0,
+            si_pid: 0,
+            si_uid: 0,
+            si_status: 0,
+            _rest: [0; 100],
+        };
+        if let Some(info) = info {
+            siginfo.si_signo = SIGCHLD as i32;
+            siginfo.si_code = info.cause;
+            siginfo.si_pid = info.pid as i32;
+            siginfo.si_status = info.status as i32;
+        }
+        if let Err(e) = put_user(siginfo, infop) {
+            return linux_err_from!(e);
         }
-
-        // Todo: wait on Exit_WaitQueue of child and resched.
-        let task = task::current();
-        let rq = run_queue::task_rq(&task.sched_info);
-        rq.lock().resched(false);
     }
+    if rusage != 0 {
+        if let Err(e) = put_user(RUsage::default(), rusage) {
+            return linux_err_from!(e);
+        }
+    }
+    0
 }
 
-fn wait_pid(tid: Tid, status: &mut u32) -> Option<Tid> {
-    let tid = wait_task_zombie(tid, status)?;
+/// A child whose state change is reported by wait.
+struct WaitInfo {
+    pid: Tid,
+    /// si_code of SIGCHLD, e.g., CLD_EXITED
+    cause: i32,
+    /// Exit code or signal, just as si_status of SIGCHLD
+    status: u32,
+}
 
-    let ctx = taskctx::current_ctx();
-    ctx.children.lock().retain(|&cid| cid != tid);
-    return Some(tid);
+impl WaitInfo {
+    /// Encodes the status for wait4,
+    /// see WIFEXITED, WIFSIGNALED, WIFSTOPPED and WIFCONTINUED.
+    fn wait_status(&self) -> u32 {
+        match self.cause {
+            CLD_EXITED => (self.status & 0xff) << 8,
+            CLD_KILLED => self.status,
+            CLD_DUMPED => self.status | WCOREFLAG,
+            CLD_STOPPED => (self.status << 8) | 0x7f,
+            _ => 0xffff,
+        }
+    }
 }
 
-fn children_count() -> usize {
-    taskctx::current_ctx().children.lock().len()
+/// Decodes the wait status of a zombie into si_code and si_status.
+fn decode_exit_code(exit_code: u32) -> (i32, u32) {
+    if (exit_code & 0x7f) == 0 {
+        (CLD_EXITED, (exit_code >> 8) & 0xff)
+    } else if (exit_code & WCOREFLAG) != 0 {
+        (CLD_DUMPED, exit_code & 0x7f)
+    } else {
+        (CLD_KILLED, exit_code & 0x7f)
+    }
 }
 
-fn siblings_count() -> usize {
-    taskctx::current_ctx().siblings.lock().len()
+/// Waits for a child selected by pid_type and id to change its state.
+/// Returns None for WNOHANG if no child is waitable yet.
+fn do_wait(
+    pid_type: PidType, id: usize, options: usize
+) -> LinuxResult<Option<WaitInfo>> {
+    info!("do_wait: pidtype {:?} pid {:#X} options {:#X}; curr {}",
+          pid_type, id, options, taskctx::current_ctx().tid());
+
+    let task = task::current();
+    loop {
+        let children = eligible_children(&pid_type, id, options);
+        if children.is_empty() {
+            return Err(LinuxError::ECHILD);
+        }
+        for child in children.iter() {
+            if let Some(info) = wait_consider_task(child, options) {
+                return Ok(Some(info));
+            }
+        }
+
+        if (options & WNOHANG) != 0 {
+            return Ok(None);
+        }
+        if task.signal_pending() {
+            return Err(LinuxError::ERESTARTSYS);
+        }
+        // Sleep until a child changes its state or a signal comes.
+        // All children may be reaped automatically, and then we get ECHILD.
+        task.wait_chldexit.wait_until(|| {
+            let children = eligible_children(&pid_type, id, options);
+            task.signal_pending() || children.is_empty() ||
+                children.iter().any(|child| child_waitable(child, options))
+        });
+    }
 }
 
-fn wait_children(status: &mut u32) -> Option<Tid> {
-    let ctx = taskctx::current_ctx();
-    for (index, child) in ctx.children.lock().iter().enumerate() {
-        info!("Current[{}]: has child[{}]", ctx.tid(), child);
-        if let Some(tid) = wait_task_zombie(*child, status) {
-            ctx.children.lock().remove(index);
-            return Some(tid);
+/// Children of all threads in the current thread group (or only of the
+/// current thread for __WNOTHREAD), which are selected by pid_type and id.
+fn eligible_children(pid_type: &PidType, id: usize, options: usize) -> Vec<TaskRef> {
+    let curr = task::current();
+    let mut children = Vec::new();
+    for thread in task::all_tasks() {
+        if thread.tgid() != curr.tgid() {
+            continue;
+        }
+        if (options & __WNOTHREAD) != 0 && thread.tid() != curr.tid() {
+            continue;
         }
+        let tids = thread.sched_info.children.lock().clone();
+        children.extend(tids.into_iter().filter_map(task::get_task));
     }
-    None
+
+    children.retain(|child| {
+        let selected = match pid_type {
+            PidType::PID => child.tgid() == id,
+            PidType::PGID => child.pgid() == id,
+            _ => true,
+        };
+        // __WCLONE selects the children which don't send SIGCHLD on exit,
+        // and __WALL selects all.
+        let clone_child = child.sched_info.exit_signal != SIGCHLD as i32;
+        selected && ((options & __WALL) != 0 || clone_child == ((options & __WCLONE) != 0))
+    });
+    children
 }
 
-fn wait_task_zombie(tid: Tid, status: &mut u32) -> Option<Tid> {
-    info!("wait_task_zombie tid {}", tid);
-    let target = task::get_task(tid).unwrap();
-    let exit_state = target.exit_state.compare_exchange(
+/// Whether the child has a state change to be reported by wait.
+fn child_waitable(child: &TaskStruct, options: usize) -> bool {
+    match child.exit_state.load(Ordering::Acquire) {
+        0 => {
+            let jobctl = child.jobctl.lock();
+            ((options & WSTOPPED) != 0 && jobctl.stop_signo != 0) ||
+                ((options & WCONTINUED) != 0 && jobctl.continued)
+        },
+        // The leader can't be reaped until all threads in its group exit.
+        EXIT_ZOMBIE => (options & WEXITED) != 0 && thread_group_empty(child.tgid()),
+        _ => false,
+    }
+}
+
+fn wait_consider_task(child: &TaskStruct, options: usize) -> Option<WaitInfo> {
+    if !child_waitable(child, options) {
+        return None;
+    }
+    if child.exit_state.load(Ordering::Acquire) == EXIT_ZOMBIE {
+        return wait_task_zombie(child, options);
+    }
+    if let Some(info) = wait_task_stopped(child, options) {
+        return Some(info);
+    }
+    wait_task_continued(child, options)
+}
+
+fn wait_task_zombie(child: &TaskStruct, options: usize) -> Option<WaitInfo> {
+    info!("wait_task_zombie tid {}", child.tid());
+    let (cause, status) = decode_exit_code(task_exit_code(child));
+    let info = WaitInfo { pid: child.tid(), cause, status };
+    if (options & WNOWAIT) != 0 {
+        return Some(info);
+    }
+
+    // Only one waiter can reap the zombie.
+    let exit_state = child.exit_state.compare_exchange(
         EXIT_ZOMBIE, EXIT_DEAD,
-        Ordering::Relaxed, Ordering::Relaxed
+        Ordering::AcqRel, Ordering::Relaxed
     );
     if exit_state != Ok(EXIT_ZOMBIE) {
         return None;
     }
+    release_task(child);
+    Some(info)
+}
+
+fn wait_task_stopped(child: &TaskStruct, options: usize) -> Option<WaitInfo> {
+    if (options & WSTOPPED) == 0 {
+        return None;
+    }
+    let mut jobctl = child.jobctl.lock();
+    if jobctl.stop_signo == 0 {
+        return None;
+    }
+    let info = WaitInfo {
+        pid: child.tid(),
+        cause: CLD_STOPPED,
+        status: jobctl.stop_signo as u32,
+    };
+    if (options & WNOWAIT) == 0 {
+        jobctl.stop_signo = 0;
+    }
+    Some(info)
+}
+
+fn wait_task_continued(child: &TaskStruct, options: usize) -> Option<WaitInfo> {
+    if (options & WCONTINUED) == 0 {
+        return None;
+    }
+    let mut jobctl = child.jobctl.lock();
+    if !jobctl.continued {
+        return None;
+    }
+    if (options & WNOWAIT) == 0 {
+        jobctl.continued = false;
+    }
+    Some(WaitInfo {
+        pid: child.tid(),
+        cause: CLD_CONTINUED,
+        status: SIGCONT as u32,
+    })
+}
 
+/// Removes the reaped task from its parent (or group leader)
+/// and the tid map.
+fn release_task(tsk: &TaskStruct) {
+    let tid = tsk.tid();
+    if let Some(leader) = tsk.sched_info.group_leader.as_ref() {
+        leader.siblings.lock().retain(|&t| t != tid);
+    } else if let Some(parent) = tsk.sched_info.real_parent.lock().as_ref() {
+        parent.children.lock().retain(|&t| t != tid);
+    }
     task::unregister_task(tid);
-    *status = target.exit_code.load(Ordering::Relaxed);
-    Some(tid)
 }
 
 /// Exits the current task.
 pub fn exit(exit_code: u32) -> ! {
     info!("task {} exit ...", taskctx::current_ctx().tid());
-    do_exit(exit_code)
+    do_exit((exit_code & 0xff) << 8)
 }
 
 /// Exits the current task group.
 pub fn exit_group(exit_code: u32) -> ! {
     info!("exit_group ... [{}]", exit_code);
-    do_exit(exit_code)
+    do_group_exit((exit_code & 0xff) << 8)
+}
+
+/// Exits the current task group with the wait status `code`,
+/// e.g., the signal number for a task killed by signal.
+/// The other threads in the group are killed. If the group is already
+/// exiting, its status is used instead of `code`.
+pub fn do_group_exit(code: u32) -> ! {
+    info!("do_group_exit ... [{:#X}]", code);
+    let code = {
+        let task = task::current();
+        let mut group = task.group_exit.lock();
+        if group.exiting {
+            group.code
+        } else {
+            group.exiting = true;
+            group.code = code;
+            zap_other_threads(&task);
+            code
+        }
+    };
+    do_exit(code)
+}
+
+/// Sends SIGKILL to all other threads in the group.
+fn zap_other_threads(task: &TaskStruct) {
+    for t in task::all_tasks() {
+        if t.tgid() != task.tgid() || t.tid() == task.tid() ||
+            t.exit_state.load(Ordering::Acquire) != 0 {
+            continue;
+        }
+        let info = SigInfo {
+            signo: SIGKILL as i32,
+            errno: 0,
+            code: SI_KERNEL,
+            tid: 0,
+            addr: 0,
+            status: 0,
+        };
+        task::send_signal(&t, SIGKILL, info);
+    }
+}
+
+/// Kills all other threads in the group for execve, and waits until
+/// they have exited.
+/// Note: Linux lets a non-leader take over the pid of the leader, but
+/// tid can't be changed here. So it keeps its own tid, and the leader
+/// is left as a zombie until the group exits.
+pub fn de_thread() -> LinuxResult {
+    let task = task::current();
+    let others_dead = || find_alive_thread(task.tgid(), task.tid()).is_none();
+    if others_dead() {
+        return Ok(());
+    }
+
+    {
+        let mut group = task.group_exit.lock();
+        if group.exiting {
+            // The group is being killed, and so are we.
+            return Err(LinuxError::EAGAIN);
+        }
+        group.exiting = true;
+        group.code = 0;
+        zap_other_threads(&task);
+    }
+    // The killed threads wake us up in exit_notify.
+    task.wait_chldexit.wait_until(others_dead);
+    *task.group_exit.lock() = GroupExit::default();
+    Ok(())
 }
 
 fn do_exit(exit_code: u32) -> ! {
     exit_mm();
+    exit_files();
+    exit_signals();
     exit_notify(exit_code);
     do_task_dead()
 }
 
-// Todo: implement it in mm.drop
 fn exit_mm() {
+    mm_release();
     let task = task::current();
-    if task.sched_info.group_leader.is_some() {
-        // Todo: dont release mm for threads.
-        // It's just a temp solution. Implement page refcount.
-        return;
+    if let Some(mm) = task.try_mm() {
+        // The last thread using the mm unmaps all pages.
+        mm.lock().mmput();
     }
-    let mm = task.mm();
-    let mut locked_mm = mm.lock();
-    loop {
-        if let Some((va, dva)) = &locked_mm.mapped.pop_first() {
-            let _ = locked_mm.unmap_region((*va).into(), PAGE_SIZE);
-            axalloc::global_allocator().dealloc_pages(*dva, 1);
-        } else {
-            break;
+}
+
+/// Drops the file table. Files are closed by the last task using it.
+fn exit_files() {
+    let task = task::current();
+    let files = {
+        let mut table = task.filetable.lock();
+        table.users -= 1;
+        if table.users > 0 {
+            return;
         }
+        core::mem::replace(&mut *table, FileTable::new())
+    };
+    // Close the files out of the lock.
+    drop(files);
+}
+
+/// Discards the pending signals of the exiting thread.
+fn exit_signals() {
+    let task = task::current();
+    *task.sigpending.lock() = SigPending::new();
+}
+
+/// Releases the robust futexes held by the exiting thread, then clears
+/// the tid at 'clear_child_tid' and wakes up the joiner, e.g., pthread_join.
+fn mm_release() {
+    futex::exit_robust_list();
+
+    let mut ctx = taskctx::current_ctx();
+    ctx.as_ctx_mut().robust_list = 0;
+    let tidptr = ctx.clear_child_tid;
+    if tidptr == 0 {
+        return;
+    }
+    ctx.as_ctx_mut().clear_child_tid = 0;
+    // We don't check the error because we can't do anything about it.
+    if put_user(0u32, tidptr).is_ok() {
+        let _ = futex::wake(tidptr);
     }
 }
 
+/// Called by execve before the old image goes away, since the robust
+/// list and 'clear_child_tid' point into it.
+pub fn exec_mm_release() {
+    mm_release();
+}
+
 fn exit_notify(exit_code: u32) {
     let task = task::current();
+    forget_original_parent(&task);
+
     task.exit_code.store(exit_code, Ordering::Relaxed);
-    task.exit_state.store(EXIT_ZOMBIE, Ordering::Relaxed);
-    // Todo: wakeup parent
+
+    // The last thread in the group to become zombie reports the group,
+    // whose leader is waited by the parent.
+    let group_dead = {
+        let _guard = task.group_exit.lock();
+        task.exit_state.store(EXIT_ZOMBIE, Ordering::Release);
+        thread_group_empty(task.tgid())
+    };
+    // Wake up de_thread, which waits for the other threads to exit.
+    task.wait_chldexit.notify_all(false);
+
+    if task.sched_info.group_leader.is_none() {
+        if group_dead && do_notify_parent(&task) {
+            reap_zombie(&task);
+        }
+        return;
+    }
+
+    // Threads are reaped at once, and nobody waits for them.
+    reap_zombie(&task);
+    if group_dead {
+        if let Some(leader) = task::get_task(task.tgid()) {
+            if do_notify_parent(&leader) {
+                reap_zombie(&leader);
+            }
+        }
+    }
+}
+
+/// Whether all threads in the group have exited.
+fn thread_group_empty(tgid: Tid) -> bool {
+    task::all_tasks().iter().all(|t| {
+        t.tgid() != tgid || t.exit_state.load(Ordering::Acquire) != 0
+    })
+}
+
+/// Wait status of the zombie. It's the status of the group if the group
+/// exits by exit_group or a fatal signal.
+fn task_exit_code(tsk: &TaskStruct) -> u32 {
+    let group = tsk.group_exit.lock();
+    if group.exiting {
+        group.code
+    } else {
+        tsk.exit_code.load(Ordering::Relaxed)
+    }
+}
+
+/// Reparents the children (and their threads) of the exiting task to
+/// the new reaper. A zombie child is reported to the new parent at once.
+fn forget_original_parent(father: &TaskStruct) {
+    let children = core::mem::take(&mut *father.sched_info.children.lock());
+    if children.is_empty() {
+        return;
+    }
+    let reaper = match find_new_reaper(father) {
+        Some(reaper) => reaper,
+        None => {
+            warn!("no reaper for children of {}", father.tid());
+            return;
+        },
+    };
+    info!("reparent children of {} to {}", father.tid(), reaper.tid());
+
+    for t in task::all_tasks() {
+        if t.sched_info.ppid() == father.tid() {
+            *t.sched_info.real_parent.lock() = Some(reaper.sched_info.clone());
+        }
+    }
+    reaper.sched_info.children.lock().extend(children.iter());
+
+    for tid in children {
+        if let Some(child) = task::get_task(tid) {
+            if child.exit_state.load(Ordering::Acquire) == EXIT_ZOMBIE &&
+                thread_group_empty(child.tgid()) && do_notify_parent(&child) {
+                reap_zombie(&child);
+            }
+        }
+    }
+}
+
+/// Orphans go to another live thread in the group of the father at first.
+/// If there's none, they go to the closest ancestor which is a subreaper,
+/// or to init.
+fn find_new_reaper(father: &TaskStruct) -> Option<TaskRef> {
+    if let Some(thread) = find_alive_thread(father.tgid(), father.tid()) {
+        return Some(thread);
+    }
+    if father.tgid() == 1 {
+        // Init is exiting, and nobody can reap the orphans.
+        return None;
+    }
+
+    let mut parent = father.sched_info.real_parent.lock().clone();
+    while let Some(ctx) = parent {
+        if ctx.tgid() == 1 {
+            break;
+        }
+        if let Some(ancestor) = task::get_task(ctx.tgid()) {
+            if ancestor.child_subreaper.load(Ordering::Acquire) {
+                if let Some(thread) = find_alive_thread(ancestor.tgid(), 0) {
+                    return Some(thread);
+                }
+            }
+        }
+        parent = ctx.real_parent.lock().clone();
+    }
+    task::get_task(1)
+}
+
+/// Finds a thread in the group which hasn't exited, except `except`.
+fn find_alive_thread(tgid: Tid, except: Tid) -> Option<TaskRef> {
+    task::all_tasks().into_iter().find(|t| {
+        t.tgid() == tgid && t.tid() != except &&
+            t.exit_state.load(Ordering::Acquire) == 0
+    })
+}
+
+/// Reaps the zombie unless a waiter has done it.
+fn reap_zombie(tsk: &TaskStruct) {
+    let exit_state = tsk.exit_state.compare_exchange(
+        EXIT_ZOMBIE, EXIT_DEAD,
+        Ordering::AcqRel, Ordering::Relaxed
+    );
+    if exit_state == Ok(EXIT_ZOMBIE) {
+        release_task(tsk);
+    }
+}
+
+/// Sends exit_signal of the exiting task to its parent, and wakes up
+/// the parent if it's in do_wait.
+/// Returns true if the parent doesn't wait for the task, i.e., it ignores
+/// SIGCHLD or sets SA_NOCLDWAIT, and the task should be reaped at once.
+fn do_notify_parent(tsk: &TaskStruct) -> bool {
+    let mut sig = tsk.sched_info.exit_signal;
+    let parent = match task::get_task(tsk.sched_info.ppid()) {
+        Some(parent) => parent,
+        None => return false,
+    };
+
+    let mut autoreap = false;
+    if sig == SIGCHLD as i32 {
+        let action = parent.sighand.lock().action[SIGCHLD - 1];
+        if action.handler == SIG_IGN || (action.flags & SA_NOCLDWAIT) != 0 {
+            autoreap = true;
+            if action.handler == SIG_IGN {
+                sig = 0;
+            }
+        }
+    }
+
+    if sig > 0 {
+        let (code, status) = decode_exit_code(task_exit_code(tsk));
+        let info = SigInfo {
+            signo: sig,
+            errno: 0,
+            code,
+            tid: tsk.tgid(),
+            addr: 0,
+            status: status as i32,
+        };
+        task::send_signal(&parent, sig as usize, info);
+    }
+    parent.wait_chldexit.notify_all(false);
+    autoreap
 }
 
 fn do_task_dead() -> ! {
-    let task = task::current();
-    info!("do_task_dead ... tid {}", task.tid());
+    // The task may have been reaped, so don't look it up by task::current.
+    let ctx = taskctx::current_ctx();
+    info!("do_task_dead ... tid {}", ctx.tid());
 
-    // Causes final put_task_struct in finish_task_switch():
-    task.set_state(TaskState::Dead);
+    // Kernel stack is freed after switching away, just like
+    // put_task_stack in finish_task_switch().
+    ctx.set_state(TaskState::Dead);
 
-    if task.tid() == 1 {
+    if ctx.tid() == 1 {
         info!("InitTask[1] exits normally ...");
         axhal::misc::terminate()
     } else {
-        let rq = run_queue::task_rq(&task.sched_info);
+        let rq = run_queue::task_rq(ctx.as_ctx_ref());
         rq.lock().resched(false);
         unreachable!()
     }
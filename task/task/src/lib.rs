@@ -5,7 +5,7 @@
 
 use core::ops::Deref;
 use core::mem::ManuallyDrop;
-use core::sync::atomic::{Ordering, AtomicUsize, AtomicU32};
+use core::sync::atomic::{Ordering, AtomicUsize, AtomicU32, AtomicBool};
 
 #[macro_use]
 extern crate log;
@@ -24,8 +24,9 @@ use fstree::FsStruct;
 use filetable::FileTable;
 use wait_queue::WaitQueue;
 use preempt_guard::NoPreempt;
+use spin::Once;
 
-pub use crate::tid_map::{register_task, unregister_task, get_task};
+pub use crate::tid_map::{register_task, unregister_task, get_task, all_tasks};
 pub use taskctx::Tid;
 pub use taskctx::current_ctx;
 pub use taskctx::{TaskStack, THREAD_SIZE};
@@ -34,13 +35,41 @@ pub use tid::alloc_tid;
 mod tid;
 mod tid_map;
 
-const NSIG: usize = 64;
-
-pub const SIGINT : usize = 2;
-pub const SIGKILL: usize = 9;
-pub const SIGSEGV: usize = 11;
-pub const SIGCHLD: usize = 17;
-pub const SIGSTOP: usize = 19;
+pub const NSIG: usize = 64;
+/// Signals below it are standard ones, which are not queued repeatedly.
+pub const SIGRTMIN: usize = 32;
+
+pub const SIGHUP   : usize = 1;
+pub const SIGINT   : usize = 2;
+pub const SIGQUIT  : usize = 3;
+pub const SIGILL   : usize = 4;
+pub const SIGTRAP  : usize = 5;
+pub const SIGABRT  : usize = 6;
+pub const SIGBUS   : usize = 7;
+pub const SIGFPE   : usize = 8;
+pub const SIGKILL  : usize = 9;
+pub const SIGUSR1  : usize = 10;
+pub const SIGSEGV  : usize = 11;
+pub const SIGUSR2  : usize = 12;
+pub const SIGPIPE  : usize = 13;
+pub const SIGALRM  : usize = 14;
+pub const SIGTERM  : usize = 15;
+pub const SIGSTKFLT: usize = 16;
+pub const SIGCHLD  : usize = 17;
+pub const SIGCONT  : usize = 18;
+pub const SIGSTOP  : usize = 19;
+pub const SIGTSTP  : usize = 20;
+pub const SIGTTIN  : usize = 21;
+pub const SIGTTOU  : usize = 22;
+pub const SIGURG   : usize = 23;
+pub const SIGXCPU  : usize = 24;
+pub const SIGXFSZ  : usize = 25;
+pub const SIGVTALRM: usize = 26;
+pub const SIGPROF  : usize = 27;
+pub const SIGWINCH : usize = 28;
+pub const SIGIO    : usize = 29;
+pub const SIGPWR   : usize = 30;
+pub const SIGSYS   : usize = 31;
 
 #[derive(Clone)]
 pub struct SigInfo {
@@ -48,20 +77,82 @@ pub struct SigInfo {
     pub errno: i32,
     pub code: i32,
     pub tid: Tid,
+    /// Faulting address for SIGSEGV and SIGBUS
+    pub addr: usize,
+    /// Exit code or signal of the child for SIGCHLD
+    pub status: i32,
+}
+
+pub const RLIMIT_STACK: usize = 3; /* max stack size */
+pub const RLIMIT_NOFILE: usize = 7; /* max number of open files */
+pub const RLIM_NLIMITS: usize = 16;
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Default limit of stack size: 8M
+const _STK_LIM: u64 = 8 * 1024 * 1024;
+/// Default limits of open files
+const INR_OPEN_CUR: u64 = 1024;
+const INR_OPEN_MAX: u64 = 4096;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct RLimit64 {
+    pub rlim_cur: u64,
+    pub rlim_max: u64,
+}
+
+impl RLimit64 {
+    pub const fn new(rlim_cur: u64, rlim_max: u64) -> Self {
+        Self { rlim_cur, rlim_max }
+    }
+}
+
+/// Resource limits which are shared by all threads in a thread group.
+pub struct RLimits {
+    pub rlim: [RLimit64; RLIM_NLIMITS],
+}
+
+impl RLimits {
+    pub fn new() -> Self {
+        let mut rlim = [RLimit64::new(RLIM_INFINITY, RLIM_INFINITY); RLIM_NLIMITS];
+        rlim[RLIMIT_STACK] = RLimit64::new(_STK_LIM, RLIM_INFINITY);
+        rlim[RLIMIT_NOFILE] = RLimit64::new(INR_OPEN_CUR, INR_OPEN_MAX);
+        Self { rlim }
+    }
 }
 
 /// signal action flags
-pub const SA_RESTORER:  usize = 0x4000000;
+pub const SA_NOCLDSTOP: usize = 0x00000001;
+pub const SA_NOCLDWAIT: usize = 0x00000002;
+pub const SA_SIGINFO:   usize = 0x00000004;
+pub const SA_RESTORER:  usize = 0x04000000;
+pub const SA_ONSTACK:   usize = 0x08000000;
 pub const SA_RESTART:   usize = 0x10000000;
+pub const SA_NODEFER:   usize = 0x40000000;
+pub const SA_RESETHAND: usize = 0x80000000;
 
+/// struct sigaction of rt_sigaction.
 // Note: No restorer in sigaction for riscv64.
+#[repr(C)]
 #[derive(Copy, Clone, Default)]
 pub struct SigAction {
     pub handler: usize,
     pub flags: usize,
+    #[cfg(target_arch = "x86_64")]
+    pub restorer: usize,
     pub mask: usize,
 }
 
+/// Alternate signal stack which is set by sigaltstack.
+/// It's disabled if size is zero.
+#[derive(Copy, Clone, Default)]
+pub struct SigAltStack {
+    pub sp: usize,
+    pub size: usize,
+    /// Only SS_AUTODISARM is kept here.
+    pub flags: usize,
+}
+
 pub struct SigPending {
     pub list: Vec<SigInfo>,
     pub signal: usize,
@@ -76,6 +167,31 @@ impl SigPending {
     }
 }
 
+/// Stop and continue of a thread group, which are reported to the parent
+/// by wait with WUNTRACED and WCONTINUED.
+#[derive(Default)]
+pub struct JobCtl {
+    /// The signal which stops the group, or 0 after it's reported.
+    pub stop_signo: usize,
+    /// The group is continued and it isn't reported yet.
+    pub continued: bool,
+    /// The signal of the group stop in progress or in effect, or 0.
+    pub group_stop: usize,
+    /// Threads which haven't stopped yet for the group stop.
+    pub group_stop_count: usize,
+    /// CLD_CONTINUED should be sent to the parent.
+    pub notify_continued: bool,
+}
+
+/// Exit of a thread group by exit_group or a fatal signal.
+#[derive(Default)]
+pub struct GroupExit {
+    /// All threads in the group are exiting.
+    pub exiting: bool,
+    /// Wait status of the group, which is valid if it's exiting.
+    pub code: u32,
+}
+
 pub struct SigHand {
     pub action: [SigAction; NSIG],
 }
@@ -94,10 +210,35 @@ pub struct TaskStruct {
     pub filetable: Arc<SpinLock<FileTable>>,
     pub sigpending: SpinLock<SigPending>,
     pub sighand: Arc<SpinLock<SigHand>>,
+    /// Signals blocked by this thread.
+    pub blocked: AtomicUsize,
+    /// Mask to be restored after a signal is handled (for rt_sigsuspend).
+    pub saved_sigmask: SpinLock<Option<usize>>,
+    /// Notified when a signal is sent to this thread.
+    pub signal_wait: WaitQueue,
+    /// The signal of the group stop which this thread takes part in,
+    /// or 0 if it isn't stopped or going to stop.
+    pub stopped: AtomicUsize,
+    /// Alternate signal stack of this thread.
+    pub sas_stack: SpinLock<SigAltStack>,
+    /// Process group id, which is shared by all threads in a thread group.
+    pub pgid: Arc<AtomicUsize>,
+    /// Job control state, which is shared by all threads in a thread group.
+    pub jobctl: Arc<SpinLock<JobCtl>>,
+    /// Orphans of descendants are reparented to this thread group
+    /// (PR_SET_CHILD_SUBREAPER), which is shared by all threads in it.
+    pub child_subreaper: Arc<AtomicBool>,
+    /// Shared by all threads in a thread group. Its lock also serializes
+    /// the exits of them.
+    pub group_exit: Arc<SpinLock<GroupExit>>,
+    pub rlimits: Arc<SpinLock<RLimits>>,
     pub sched_info: Arc<SchedInfo>,
 
     pub exit_state: AtomicUsize,
     pub exit_code: AtomicU32,
+    /// Notified when a child exits, or when a thread exits for de_thread.
+    /// It's shared by all threads in a thread group.
+    pub wait_chldexit: Arc<WaitQueue>,
     pub vfork_done: Option<WaitQueue>,
 }
 
@@ -112,10 +253,21 @@ impl TaskStruct {
             filetable: filetable::init_files(),
             sigpending: SpinLock::new(SigPending::new()),
             sighand: Arc::new(SpinLock::new(SigHand::new())),
+            blocked: AtomicUsize::new(0),
+            saved_sigmask: SpinLock::new(None),
+            signal_wait: WaitQueue::new(),
+            stopped: AtomicUsize::new(0),
+            sas_stack: SpinLock::new(SigAltStack::default()),
+            pgid: Arc::new(AtomicUsize::new(0)),
+            jobctl: Arc::new(SpinLock::new(JobCtl::default())),
+            child_subreaper: Arc::new(AtomicBool::new(false)),
+            group_exit: Arc::new(SpinLock::new(GroupExit::default())),
+            rlimits: Arc::new(SpinLock::new(RLimits::new())),
             sched_info: taskctx::init_thread(),
 
             exit_state: AtomicUsize::new(0),
             exit_code: AtomicU32::new(0),
+            wait_chldexit: Arc::new(WaitQueue::new()),
             vfork_done: None,
         }
     }
@@ -124,10 +276,28 @@ impl TaskStruct {
         self.sched_info.tid()
     }
 
+    /// Whether there're unblocked signals to be handled,
+    /// or the thread should stop for a group stop.
+    pub fn signal_pending(&self) -> bool {
+        if self.stopped.load(Ordering::Acquire) != 0 {
+            return true;
+        }
+        let blocked = self.blocked.load(Ordering::Acquire);
+        (self.sigpending.lock().signal & !blocked) != 0
+    }
+
+    pub fn rlimit(&self, resource: usize) -> u64 {
+        self.rlimits.lock().rlim[resource].rlim_cur
+    }
+
     pub fn tgid(&self) -> usize {
         self.sched_info.tgid()
     }
 
+    pub fn pgid(&self) -> usize {
+        self.pgid.load(Ordering::Acquire)
+    }
+
     pub fn pt_regs_addr(&self) -> usize {
         self.sched_info.pt_regs_addr()
     }
@@ -146,7 +316,10 @@ impl TaskStruct {
         //assert!(self.mm.is_none());
         let mm = MmStruct::new();
         let mm_id = mm.id();
-        self.mm.replace(Arc::new(SpinNoIrq::new(mm)));
+        if let Some(old) = self.mm.replace(Arc::new(SpinNoIrq::new(mm))) {
+            // The old mm may be still used by others, e.g., the vfork parent.
+            old.lock().mmput();
+        }
         info!("================== mmid {}", mm_id);
         let mut ctx = taskctx::current_ctx();
         ctx.mm_id.store(mm_id, Ordering::Relaxed);
@@ -155,10 +328,26 @@ impl TaskStruct {
         switch_mm(0, mm_id, self.mm().lock().pgd());
     }
 
+    /// Gives the task its own copy of the fd table if it's shared with
+    /// others, e.g., by CLONE_FILES.
+    pub fn unshare_files(&mut self) {
+        let files = {
+            let mut table = self.filetable.lock();
+            if table.users <= 1 {
+                return;
+            }
+            table.users -= 1;
+            table.dup()
+        };
+        self.filetable = Arc::new(SpinLock::new(files));
+    }
+
     pub fn dup_task_struct(&self) -> Self {
         info!("dup_task_struct ...");
         let mut task = Self::new();
         task.fs = self.fs.clone();
+        task.blocked = AtomicUsize::new(self.blocked.load(Ordering::Acquire));
+        task.sas_stack = SpinLock::new(*self.sas_stack.lock());
         task
     }
 
@@ -273,6 +462,22 @@ pub fn yield_now() {
     rq.lock().resched(false);
 }
 
+/// Sends a signal to a task. It's registered by the signal crate, so that
+/// the crates signal depends on, e.g., sys, can send signals as well.
+static SEND_SIGNAL: Once<fn(&TaskStruct, usize, SigInfo)> = Once::new();
+
+pub fn register_send_signal(f: fn(&TaskStruct, usize, SigInfo)) {
+    SEND_SIGNAL.call_once(|| f);
+}
+
+/// Sends the signal `sig` to the task by the registered handler.
+pub fn send_signal(task: &TaskStruct, sig: usize, info: SigInfo) {
+    match SEND_SIGNAL.get() {
+        Some(send) => send(task, sig, info),
+        None => warn!("no handler to send signal {} to task {}", sig, task.tid()),
+    }
+}
+
 pub fn activate(task: TaskRef) {
     let rq = run_queue::task_rq(&task.sched_info);
     rq.lock().activate_task(task.sched_info.clone());
@@ -284,6 +489,12 @@ pub fn alloc_mm() {
     task.as_task_mut().alloc_mm();
 }
 
+pub fn unshare_files() {
+    let _ = NoPreempt::new();
+    let mut task = current();
+    task.as_task_mut().unshare_files();
+}
+
 pub fn init(cpu_id: usize, dtb_pa: usize) {
     axconfig::init_once!();
     info!("Initialize schedule system ...");
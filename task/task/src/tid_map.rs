@@ -1,4 +1,5 @@
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use spinpreempt::SpinLock;
 use crate::TaskRef;
 use crate::Tid;
@@ -14,6 +15,11 @@ pub fn register_task(task: TaskRef) {
     TID_MAP.lock().insert(tid, task);
 }
 
+/// Returns all tasks, which are ordered by tid.
+pub fn all_tasks() -> Vec<TaskRef> {
+    TID_MAP.lock().values().cloned().collect()
+}
+
 pub fn unregister_task(tid: Tid) {
     TID_MAP.lock().remove(&tid);
 }
@@ -77,14 +77,19 @@ pub struct SchedInfo {
     tid:    Tid,
     tgid:   Tid,
 
-    pub real_parent:   Option<Arc<SchedInfo>>,
+    /// The parent, which is changed when the task is reparented.
+    pub real_parent:   SpinNoIrq<Option<Arc<SchedInfo>>>,
     pub group_leader:  Option<Arc<SchedInfo>>,
 
     pub children: SpinNoIrq<Vec<Tid>>,
     pub siblings: SpinNoIrq<Vec<Tid>>,
 
+    /// Signal sent to the parent when this task exits, -1 for none.
+    pub exit_signal: i32,
+
     pub set_child_tid: usize,
     pub clear_child_tid: usize,
+    pub robust_list: usize,
 
     pub pgd: Option<Arc<SpinNoIrq<PageTable>>>,
     pub mm_id: AtomicUsize,
@@ -111,14 +116,17 @@ impl SchedInfo {
             tid: 0,
             tgid: 0,
 
-            real_parent: None,
+            real_parent: SpinNoIrq::new(None),
             group_leader: None,
 
             children: SpinNoIrq::new(Vec::new()),
             siblings: SpinNoIrq::new(Vec::new()),
 
+            exit_signal: 0,
+
             set_child_tid: 0,
             clear_child_tid: 0,
+            robust_list: 0,
 
             pgd: None,
             mm_id: AtomicUsize::new(0),
@@ -176,6 +184,16 @@ impl SchedInfo {
         matches!(self.state(), TaskState::Blocked)
     }
 
+    #[inline]
+    pub fn is_dead(&self) -> bool {
+        matches!(self.state(), TaskState::Dead)
+    }
+
+    #[inline]
+    pub fn in_wait_queue(&self) -> bool {
+        self.in_wait_queue.load(Ordering::Acquire)
+    }
+
     #[inline]
     pub fn set_in_wait_queue(&self, in_wait_queue: bool) {
         self.in_wait_queue.store(in_wait_queue, Ordering::Release);
@@ -212,6 +230,11 @@ impl SchedInfo {
         self.pgd = Some(pgd.clone());
     }
 
+    /// Tid of the parent, or 0 if there's no parent.
+    pub fn ppid(&self) -> Tid {
+        self.real_parent.lock().as_ref().map_or(0, |parent| parent.tid())
+    }
+
     pub fn pt_regs_addr(&self) -> usize {
         self.kstack.as_ref().unwrap().top() - align_down(TRAPFRAME_SIZE, STACK_ALIGN)
     }
@@ -309,6 +332,15 @@ impl Deref for CurrentCtx {
     }
 }
 
+/// Frees the kernel stack of a dead task.
+///
+/// # Safety
+///
+/// The task must never run again, i.e., it has switched away after exit.
+pub unsafe fn release_kstack(ctx: &mut CtxRef) {
+    Arc::get_mut_unchecked(ctx).kstack = None;
+}
+
 pub fn current_ctx() -> CurrentCtx {
     CurrentCtx::get()
 }
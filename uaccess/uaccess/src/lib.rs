@@ -0,0 +1,285 @@
+//! Access to user memory.
+//!
+//! Kernel must access user memory only by these routines. They check the
+//! range against TASK_SIZE and the vmas of current mm, fault in the pages,
+//! and turn a bad address into EFAULT instead of a kernel crash.
+//! The pages are accessed by their linear mapping under the mm lock, never
+//! by the user addresses, so kernel never takes a fault on user memory.
+
+#![no_std]
+
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use core::sync::atomic::{AtomicU32, Ordering};
+use axerrno::{LinuxError, LinuxResult};
+use axhal::arch::{TASK_SIZE, EXC_LOAD_PAGE_FAULT, EXC_STORE_PAGE_FAULT};
+use axhal::mem::phys_to_virt;
+use page_table::paging::MappingFlags;
+use axtype::{align_down_4k, PAGE_SIZE};
+
+/// Max length of a path, including the trailing nul.
+pub const PATH_MAX: usize = 4096;
+/// Max length of an argument or environment string for execve.
+pub const MAX_ARG_STRLEN: usize = PAGE_SIZE * 32;
+
+/// Plain data types which can be read from user memory by [`get_user`]
+/// and written to it by [`put_user`].
+///
+/// # Safety
+///
+/// Any bit pattern of `size_of::<Self>()` bytes must be a valid value of
+/// the type. So it mustn't contain bool, char, enums, references or
+/// pointers which the kernel dereferences.
+/// And it mustn't have implicit padding, which would leak kernel stack
+/// to user; name the padding as a field instead, e.g., `_pad`.
+pub unsafe trait Pod: Copy {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => {
+        $(unsafe impl Pod for $t {})*
+    };
+}
+
+impl_pod!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+unsafe impl Pod for task::SigAction {}
+unsafe impl Pod for task::RLimit64 {}
+#[cfg(target_arch = "x86_64")]
+unsafe impl Pod for axhal::arch::FxsaveArea {}
+
+/// Checks whether the range [addr, addr + size) is in user space.
+#[inline]
+pub fn access_ok(addr: usize, size: usize) -> bool {
+    addr <= TASK_SIZE && size <= TASK_SIZE - addr
+}
+
+/// Faults in the pages of the range, which also checks them against vmas.
+fn fault_in(addr: usize, size: usize, write: bool) -> LinuxResult {
+    if !access_ok(addr, size) {
+        return Err(LinuxError::EFAULT);
+    }
+    let cause = if write {
+        EXC_STORE_PAGE_FAULT
+    } else {
+        EXC_LOAD_PAGE_FAULT
+    };
+    let mut va = align_down_4k(addr);
+    while va < addr + size {
+        mmap::faultin_page(va, cause)?;
+        va += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Faults in the range for reading. See [`get_user_nofault`].
+pub fn fault_in_readable(addr: usize, size: usize) -> LinuxResult {
+    fault_in(addr, size, false)
+}
+
+/// Calls `f(kva, done, len)` for each piece of the user range
+/// [addr, addr + size) within a page, where `kva` is the address of the
+/// piece in the linear mapping and `done` is the offset of it in the range.
+///
+/// The page is looked up and accessed under the mm lock, so a sibling
+/// thread can't unmap it in between; a page which isn't present (or
+/// not writable for `write`) is faulted in first.
+/// `f` returns false to stop early.
+fn access_user_pages(
+    addr: usize,
+    size: usize,
+    write: bool,
+    mut f: impl FnMut(usize, usize, usize) -> bool,
+) -> LinuxResult {
+    if size == 0 {
+        return Ok(());
+    }
+    if !access_ok(addr, size) {
+        return Err(LinuxError::EFAULT);
+    }
+    let required = if write {
+        MappingFlags::USER | MappingFlags::WRITE
+    } else {
+        MappingFlags::USER | MappingFlags::READ
+    };
+    let mm = task::current().mm();
+    let mut done = 0;
+    while done < size {
+        let va = addr + done;
+        let len = core::cmp::min(align_down_4k(va) + PAGE_SIZE - va, size - done);
+        let mut faulted = false;
+        let more = loop {
+            {
+                let locked_mm = mm.lock();
+                let pgd = locked_mm.pgd();
+                let query = pgd.lock().query(va.into());
+                if let Ok((pa, flags, _)) = query {
+                    if flags.contains(required) {
+                        break f(phys_to_virt(pa).as_usize(), done, len);
+                    }
+                }
+            }
+            if faulted {
+                return Err(LinuxError::EFAULT);
+            }
+            fault_in(va, len, write)?;
+            faulted = true;
+        };
+        if !more {
+            break;
+        }
+        done += len;
+    }
+    Ok(())
+}
+
+/// Copies `dst.len()` bytes from user address `src`.
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> LinuxResult {
+    access_user_pages(src, dst.len(), false, |kva, done, len| {
+        unsafe {
+            core::ptr::copy_nonoverlapping(kva as *const u8, dst[done..].as_mut_ptr(), len);
+        }
+        true
+    })
+}
+
+/// Copies `src` to user address `dst`.
+pub fn copy_to_user(dst: usize, src: &[u8]) -> LinuxResult {
+    access_user_pages(dst, src.len(), true, |kva, done, len| {
+        unsafe {
+            core::ptr::copy_nonoverlapping(src[done..].as_ptr(), kva as *mut u8, len);
+        }
+        true
+    })
+}
+
+/// Zeroes `size` bytes at user address `dst`.
+pub fn clear_user(dst: usize, size: usize) -> LinuxResult {
+    access_user_pages(dst, size, true, |kva, _, len| {
+        unsafe {
+            core::ptr::write_bytes(kva as *mut u8, 0, len);
+        }
+        true
+    })
+}
+
+/// Reads a value from user address `src`.
+pub fn get_user<T: Pod>(src: usize) -> LinuxResult<T> {
+    let mut val = MaybeUninit::<T>::uninit();
+    let buf = unsafe {
+        core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>())
+    };
+    copy_from_user(buf, src)?;
+    Ok(unsafe { val.assume_init() })
+}
+
+/// Reads a value from user address `src` without faulting in the page,
+/// so it can be called under spin locks. The value mustn't cross a page.
+/// Returns EFAULT if the page isn't present, then the caller should drop
+/// its locks, fault in the page by [`fault_in_readable`] and try again.
+pub fn get_user_nofault<T: Pod>(src: usize) -> LinuxResult<T> {
+    let size = size_of::<T>();
+    if !access_ok(src, size) || align_down_4k(src) != align_down_4k(src + size - 1) {
+        return Err(LinuxError::EFAULT);
+    }
+    let pgd = taskctx::current_ctx().try_pgd().ok_or(LinuxError::EFAULT)?;
+    let (pa, flags, _) = pgd.lock().query(src.into()).map_err(|_| LinuxError::EFAULT)?;
+    if !flags.contains(MappingFlags::USER | MappingFlags::READ) {
+        return Err(LinuxError::EFAULT);
+    }
+    // Read it by the linear mapping of the physical page.
+    let va = phys_to_virt(pa).as_usize();
+    Ok(unsafe { core::ptr::read_volatile(va as *const T) })
+}
+
+/// Writes a value to user address `dst`.
+pub fn put_user<T: Pod>(val: T, dst: usize) -> LinuxResult {
+    let buf = unsafe {
+        core::slice::from_raw_parts(&val as *const T as *const u8, size_of::<T>())
+    };
+    copy_to_user(dst, buf)
+}
+
+/// Atomically compares the u32 at user address `uaddr` with `oldval`, and
+/// replaces it with `newval` if they are equal. Returns the value before.
+pub fn futex_atomic_cmpxchg(uaddr: usize, oldval: u32, newval: u32) -> LinuxResult<u32> {
+    if uaddr % size_of::<u32>() != 0 {
+        return Err(LinuxError::EINVAL);
+    }
+    let mut ret = 0;
+    access_user_pages(uaddr, size_of::<u32>(), true, |kva, _, _| {
+        let ptr = unsafe { &*(kva as *const AtomicU32) };
+        ret = ptr
+            .compare_exchange(oldval, newval, Ordering::SeqCst, Ordering::SeqCst)
+            .unwrap_or_else(|cur| cur);
+        true
+    })?;
+    Ok(ret)
+}
+
+/// Copies a nul-terminated string from user address `src`.
+/// Returns ENAMETOOLONG if it's longer than `max` (including the nul).
+pub fn strncpy_from_user(src: usize, max: usize) -> LinuxResult<String> {
+    let mut bytes = Vec::new();
+    let mut addr = src;
+    loop {
+        // Check one page each time, the string may end before the next one.
+        let end = align_down_4k(addr) + PAGE_SIZE;
+        // Reserve in advance, so no allocation happens under the mm lock.
+        bytes.reserve(end - addr);
+        let mut found = false;
+        access_user_pages(addr, end - addr, false, |kva, _, len| {
+            let chunk = unsafe { core::slice::from_raw_parts(kva as *const u8, len) };
+            match chunk.iter().position(|&c| c == 0) {
+                Some(pos) => {
+                    bytes.extend_from_slice(&chunk[..pos]);
+                    found = true;
+                }
+                None => bytes.extend_from_slice(chunk),
+            }
+            !found
+        })?;
+        if bytes.len() >= max {
+            return Err(LinuxError::ENAMETOOLONG);
+        }
+        if found {
+            break;
+        }
+        addr = end;
+    }
+    String::from_utf8(bytes).map_err(|_| LinuxError::EINVAL)
+}
+
+/// Copies a path from user address `ptr`.
+pub fn get_user_str(ptr: usize) -> LinuxResult<String> {
+    strncpy_from_user(ptr, PATH_MAX)
+}
+
+/// Copies a null-terminated array of strings (e.g., argv and envp
+/// of execve) from user address `addr`. Null `addr` is an empty array.
+pub fn get_user_str_vec(addr: usize) -> LinuxResult<Vec<String>> {
+    let mut vec = Vec::new();
+    if addr == 0 {
+        return Ok(vec);
+    }
+    let mut index = 0;
+    loop {
+        let ptr_str: usize = get_user(addr + index * size_of::<usize>())?;
+        if ptr_str == 0 {
+            break;
+        }
+        let s = strncpy_from_user(ptr_str, MAX_ARG_STRLEN).map_err(|e| {
+            if e == LinuxError::ENAMETOOLONG {
+                LinuxError::E2BIG
+            } else {
+                e
+            }
+        })?;
+        vec.push(s);
+        index += 1;
+    }
+    Ok(vec)
+}
@@ -1,13 +1,14 @@
 //! Startup process for monolithic kernel.
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 #[macro_use]
 extern crate axlog2;
 extern crate alloc;
 
+use core::sync::atomic::Ordering;
 use axerrno::{LinuxError, LinuxResult};
-#[cfg(target_arch = "riscv64")]
+#[cfg(any(target_arch = "riscv64", target_arch = "x86_64"))]
 use axhal::mem::phys_to_virt;
 use axtype::DtbInfo;
 use fork::{user_mode_thread, CloneFlags};
@@ -62,19 +63,61 @@ fn parse_dtb(_dtb_pa: usize) -> DtbInfo {
         axdtb::parse(dtb_va.into(), &mut cb);
         dtb_info
     }
-    #[cfg(not(target_arch = "riscv64"))]
+    #[cfg(target_arch = "x86_64")]
+    {
+        // For x86_64, it's the multiboot info rather than a dtb.
+        let mut dtb_info = DtbInfo::new();
+        if let Some(cmd) = multiboot_cmdline(_dtb_pa) {
+            parse_cmdline(cmd, &mut dtb_info);
+        }
+        dtb_info
+    }
+    #[cfg(not(any(target_arch = "riscv64", target_arch = "x86_64")))]
     {
         DtbInfo::new()
     }
 }
 
-#[allow(dead_code)]
+/// The 'cmdline' field of multiboot info is valid.
+#[cfg(target_arch = "x86_64")]
+const MULTIBOOT_INFO_CMDLINE: u32 = 1 << 2;
+
+/// Gets the kernel command line from multiboot info at `mbi_pa`.
+/// The boot loader puts the kernel path before the params, so skip it.
+#[cfg(target_arch = "x86_64")]
+fn multiboot_cmdline(mbi_pa: usize) -> Option<&'static str> {
+    if mbi_pa == 0 {
+        return None;
+    }
+    let mbi = phys_to_virt(mbi_pa.into()).as_usize();
+    let flags = unsafe { *(mbi as *const u32) };
+    if (flags & MULTIBOOT_INFO_CMDLINE) == 0 {
+        return None;
+    }
+    let cmdline_pa = unsafe { *((mbi + 16) as *const u32) } as usize;
+    let cmdline = phys_to_virt(cmdline_pa.into()).as_usize();
+    let cmdline = unsafe { core::ffi::CStr::from_ptr(cmdline as *const core::ffi::c_char) };
+    let cmdline = cmdline.to_str().ok()?.trim();
+    Some(cmdline.split_once(' ').map_or("", |(_, params)| params))
+}
+
 fn parse_cmdline(cmd: &str, dtb_info: &mut DtbInfo) {
-    let cmd = cmd.trim_end_matches(char::from(0));
-    if cmd.len() > 0 {
-        assert!(cmd.starts_with("init="));
-        let cmd = cmd.strip_prefix("init=").unwrap();
-        dtb_info.set_init_cmd(cmd);
+    let mut cmd = cmd.trim_end_matches(char::from(0)).trim();
+    while cmd.len() > 0 {
+        // 'init=' takes the rest of cmdline, including args of init.
+        if let Some(init_cmd) = cmd.strip_prefix("init=") {
+            dtb_info.set_init_cmd(init_cmd);
+            return;
+        }
+        let (param, rest) = cmd.split_once(' ').unwrap_or((cmd, ""));
+        if let Some(spec) = param.strip_prefix("strace=") {
+            if let Err(e) = axsyscall::set_syscall_trace(spec) {
+                warn!("bad boot param '{}': {:?}", param, e);
+            }
+        } else {
+            warn!("unknown boot param '{}'", param);
+        }
+        cmd = rest.trim_start();
     }
 }
 
@@ -107,6 +150,15 @@ fn cpu_startup_entry() {
 
 /// Prepare for entering first user app.
 fn kernel_init(dtb_info: DtbInfo) {
+    // Init is the leader of its own process group.
+    {
+        let task = task::current();
+        task.pgid.store(task.tgid(), Ordering::Release);
+    }
+
+    // Open the console as stdin, stdout and stderr.
+    fileops::console_on_rootfs();
+
     /*
      * We try each of these until one succeeds.
      *
@@ -146,3 +198,39 @@ fn run_init_process(init_filename: &str) -> LinuxResult {
     info!("run_init_process...");
     exec::kernel_execve(init_filename)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(cmd: &str) -> DtbInfo {
+        let mut dtb_info = DtbInfo::new();
+        parse_cmdline(cmd, &mut dtb_info);
+        dtb_info
+    }
+
+    #[test]
+    fn test_parse_init() {
+        assert_eq!(parse("").get_init_cmd(), None);
+        assert_eq!(parse("init=/sbin/init").get_init_cmd(), Some("/sbin/init"));
+        // It takes the rest of cmdline as args of init.
+        assert_eq!(
+            parse("quiet init=/bin/busybox sh -c 'ls /' strace=all").get_init_cmd(),
+            Some("/bin/busybox sh -c 'ls /' strace=all")
+        );
+        // Trailing nuls of the dtb property are dropped.
+        assert_eq!(parse("init=/bin/sh\0\0").get_init_cmd(), Some("/bin/sh"));
+    }
+
+    #[test]
+    fn test_parse_strace() {
+        let dtb_info = parse("  strace=1,3   init=/bin/sh ");
+        assert_eq!(dtb_info.get_init_cmd(), Some("/bin/sh"));
+        assert_eq!(axsyscall::syscall_trace(), "1,3");
+        // A bad spec is ignored.
+        assert_eq!(parse("strace=x").get_init_cmd(), None);
+        assert_eq!(axsyscall::syscall_trace(), "1,3");
+        parse("strace=off");
+        assert_eq!(axsyscall::syscall_trace(), "off");
+    }
+}
@@ -2,6 +2,7 @@
 
 extern crate alloc;
 use alloc::collections::VecDeque;
+use alloc::sync::Arc;
 use spinbase::SpinRaw;
 
 use taskctx::CtxRef;
@@ -48,24 +49,15 @@ impl WaitQueue {
         }
     }
 
-    /*
-    fn cancel_events(&self, curr: CurrentTask) {
-        // A task can be wake up only one events (timer or `notify()`), remove
-        // the event from another queue.
+    /// Called with the run queue locked after `curr` wakes up.
+    fn cancel_events(&self, curr: &CtxRef) {
+        // A task can be woken up by other events than `notify()`, e.g., a
+        // signal, and then it's still in the queue. Remove it from there.
         if curr.in_wait_queue() {
-            // wake up by timer (timeout).
-            // `RUN_QUEUE` is not locked here, so disable IRQs.
-            let _guard = kernel_guard::IrqSave::new();
-            self.queue.lock().retain(|t| !curr.ptr_eq(t));
+            self.queue.lock().retain(|t| !Arc::ptr_eq(t, curr));
             curr.set_in_wait_queue(false);
         }
-        #[cfg(feature = "irq")]
-        if curr.in_timer_list() {
-            // timeout was set but not triggered (wake up by `WaitQueue::notify()`)
-            crate::timers::cancel_alarm(curr.as_task_ref());
-        }
     }
-    */
 
     /// Blocks the current task and put it into the wait queue, until other task
     /// notifies it.
@@ -76,7 +68,7 @@ impl WaitQueue {
             task.set_in_wait_queue(true);
             self.queue.lock().push_back(task)
         });
-        //self.cancel_events(crate::current());
+        self.cancel_events(&curr);
     }
 
     /// Blocks the current task and put it into the wait queue, until the given
@@ -88,8 +80,8 @@ impl WaitQueue {
     where
         F: Fn() -> bool,
     {
+        let curr = taskctx::current_ctx();
         loop {
-            let curr = taskctx::current_ctx();
             let mut rq = run_queue::task_rq(&curr).lock();
             if condition() {
                 break;
@@ -98,8 +90,8 @@ impl WaitQueue {
                 task.set_in_wait_queue(true);
                 self.queue.lock().push_back(task);
             });
+            self.cancel_events(&curr);
         }
-        //self.cancel_events(crate::current());
     }
 
     /*
@@ -175,24 +167,17 @@ impl WaitQueue {
         }
     }
 
-    /*
     /// Wakes all tasks in the wait queue.
     ///
     /// If `resched` is true, the current task will be preempted when the
     /// preemption is enabled.
     pub fn notify_all(&self, resched: bool) {
-        loop {
-            let mut rq = RUN_QUEUE.lock();
-            if let Some(task) = self.queue.lock().pop_front() {
-                task.set_in_wait_queue(false);
-                rq.unblock_task(task, resched);
-            } else {
-                break;
-            }
-            drop(rq); // we must unlock `RUN_QUEUE` after unlocking `self.queue`.
-        }
+        let curr = taskctx::current_ctx();
+        let mut rq = run_queue::task_rq(&curr).lock();
+        self.notify_all_locked(resched, &mut rq);
     }
 
+    /*
     /// Wake up the given task in the wait queue.
     ///
     /// If `resched` is true, the current task will be preempted when the
@@ -220,12 +205,10 @@ impl WaitQueue {
         }
     }
 
-    /*
     pub(crate) fn notify_all_locked(&self, resched: bool, rq: &mut AxRunQueue) {
         while let Some(task) = self.queue.lock().pop_front() {
             task.set_in_wait_queue(false);
             rq.unblock_task(task, resched);
         }
     }
-    */
 }